@@ -0,0 +1,246 @@
+use super::wire::{write_headers, write_str_bytes};
+use crate::error::HfnError;
+
+// Every buffer returned by read() starts with one of these discriminators,
+// followed by the msgpack encoded event body.
+
+// pkg_id, headers map, payload bin, socket_id str
+pub const EVENT_MESSAGE: u8 = 1;
+// socket_id str, client_id str, session_id str, client_version str, client_ts uint,
+// resumed bool (true when the socket id of an earlier connection was resumed)
+pub const EVENT_CONNECT: u8 = 2;
+// socket_id str, reason uint
+pub const EVENT_DISCONNECT: u8 = 3;
+// socket_id str (empty when not socket related), code uint, message str
+pub const EVENT_ERROR: u8 = 4;
+// socket_id str, message id int, delivered bool
+pub const EVENT_DELIVERY: u8 = 5;
+// socket_id str, call id uint, code uint (0 when the call succeeded), message str,
+// payload bin (empty when it failed)
+pub const EVENT_RPC_RESULT: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    ClientClose = 1,
+    HeartbeatTimeout = 2,
+    ServerShutdown = 3,
+    // connection dropped without a CLOSE packet
+    ConnectionLost = 4,
+    // closed by the host through close_socket / close_sockets_by_client
+    ServerClose = 5,
+    // the client could not keep up with outgoing messages
+    SlowConsumer = 6,
+    // the client sent a frame that could not be decoded
+    ProtocolViolation = 7,
+    // told to reconnect later with a RETRY packet, by the host or the connection cap
+    Retry = 8,
+    // told to drop its session with a RESET packet
+    Reset = 9,
+    // sent to another node with a REDIRECT packet
+    Redirect = 10,
+}
+
+impl DisconnectReason {
+    // whether the client may come back to its session within the grace period
+    pub fn keeps_session(self) -> bool {
+        !matches!(
+            self,
+            DisconnectReason::ServerShutdown
+                | DisconnectReason::ServerClose
+                | DisconnectReason::Reset
+                | DisconnectReason::Redirect
+        )
+    }
+}
+
+pub struct ConnectEvent<'a> {
+    pub socket_id: &'a str,
+    pub client_id: &'a str,
+    pub session_id: &'a str,
+    pub client_version: &'a str,
+    pub client_ts: u64,
+    pub resumed: bool,
+}
+
+impl ConnectEvent<'_> {
+    pub fn to_buf(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(
+            16 + self.socket_id.len()
+                + self.client_id.len()
+                + self.session_id.len()
+                + self.client_version.len(),
+        );
+
+        rmp::encode::write_pfix(&mut data, EVENT_CONNECT).unwrap();
+        rmp::encode::write_str(&mut data, self.socket_id).unwrap();
+        rmp::encode::write_str(&mut data, self.client_id).unwrap();
+        rmp::encode::write_str(&mut data, self.session_id).unwrap();
+        rmp::encode::write_str(&mut data, self.client_version).unwrap();
+        rmp::encode::write_uint(&mut data, self.client_ts).unwrap();
+        rmp::encode::write_bool(&mut data, self.resumed).unwrap();
+
+        data
+    }
+}
+
+// a client message, from a socket of ours or relayed by the gateway
+pub fn encode_message(
+    socket_id: &[u8],
+    pkg_id: i32,
+    headers: &[Vec<u8>],
+    payload: &[u8],
+) -> Vec<u8> {
+    let headers_len: usize = headers.iter().map(|header| 5 + header.len()).sum();
+    let mut data = Vec::with_capacity(16 + headers_len + payload.len() + socket_id.len());
+
+    rmp::encode::write_pfix(&mut data, EVENT_MESSAGE).unwrap();
+    rmp::encode::write_sint(&mut data, pkg_id as i64).unwrap();
+    write_headers(&mut data, headers);
+    rmp::encode::write_bin(&mut data, payload).unwrap();
+    write_str_bytes(&mut data, socket_id);
+
+    data
+}
+
+pub fn encode_disconnect(socket_id: &str, reason: DisconnectReason) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + socket_id.len());
+
+    rmp::encode::write_pfix(&mut data, EVENT_DISCONNECT).unwrap();
+    rmp::encode::write_str(&mut data, socket_id).unwrap();
+    rmp::encode::write_pfix(&mut data, reason as u8).unwrap();
+
+    data
+}
+
+pub fn encode_error(socket_id: &str, err: &HfnError) -> Vec<u8> {
+    let message = err.to_string();
+    let mut data = Vec::with_capacity(8 + socket_id.len() + message.len());
+
+    rmp::encode::write_pfix(&mut data, EVENT_ERROR).unwrap();
+    rmp::encode::write_str(&mut data, socket_id).unwrap();
+    rmp::encode::write_uint(&mut data, err.code() as u64).unwrap();
+    rmp::encode::write_str(&mut data, &message).unwrap();
+
+    data
+}
+
+pub fn encode_delivery(socket_id: &str, id: i32, delivered: bool) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + socket_id.len());
+
+    rmp::encode::write_pfix(&mut data, EVENT_DELIVERY).unwrap();
+    rmp::encode::write_str(&mut data, socket_id).unwrap();
+    rmp::encode::write_sint(&mut data, id as i64).unwrap();
+    rmp::encode::write_bool(&mut data, delivered).unwrap();
+
+    data
+}
+
+// how a call_rpc ended, the response payload or why there is none
+pub fn encode_rpc_result(
+    socket_id: &str,
+    call_id: u32,
+    result: Result<&[u8], &HfnError>,
+) -> Vec<u8> {
+    let (code, message, payload) = match result {
+        Ok(payload) => (0, String::new(), payload),
+        Err(e) => (e.code(), e.to_string(), &[][..]),
+    };
+    let mut data = Vec::with_capacity(16 + socket_id.len() + message.len() + payload.len());
+
+    rmp::encode::write_pfix(&mut data, EVENT_RPC_RESULT).unwrap();
+    rmp::encode::write_str(&mut data, socket_id).unwrap();
+    rmp::encode::write_uint(&mut data, call_id as u64).unwrap();
+    rmp::encode::write_uint(&mut data, code as u64).unwrap();
+    rmp::encode::write_str(&mut data, &message).unwrap();
+    rmp::encode::write_bin(&mut data, payload).unwrap();
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{codec::event::*, error::HfnError};
+
+    #[test]
+    fn encode_connect_event() {
+        let data = ConnectEvent {
+            socket_id: "s1",
+            client_id: "c1",
+            session_id: "ss1",
+            client_version: "1.0",
+            client_ts: 1645000000000,
+            resumed: true,
+        }
+        .to_buf();
+
+        assert_eq!(data[0], EVENT_CONNECT);
+        let (socket_id, rest) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+        let (client_id, rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        let (session_id, rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        let (client_version, mut rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        let client_ts: u64 = rmp::decode::read_int(&mut rest).unwrap();
+
+        assert_eq!(socket_id, "s1");
+        assert_eq!(client_id, "c1");
+        assert_eq!(session_id, "ss1");
+        assert_eq!(client_version, "1.0");
+        assert_eq!(client_ts, 1645000000000);
+        assert!(rmp::decode::read_bool(&mut rest).unwrap());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn encode_message_event() {
+        let headers = vec![b"hfn".to_vec(), b"3".to_vec()];
+        let data = encode_message(b"s1", -2, &headers, &[0x90]);
+
+        assert_eq!(data[0], EVENT_MESSAGE);
+        let mut rest = &data[1..];
+        assert_eq!(rmp::decode::read_int::<i32, _>(&mut rest).unwrap(), -2);
+        assert_eq!(rmp::decode::read_map_len(&mut rest).unwrap(), 1);
+        let (key, rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        let (value, mut rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        assert_eq!((key, value), ("hfn", "3"));
+        assert_eq!(rmp::decode::read_bin_len(&mut rest).unwrap(), 1);
+        let (socket_id, rest) = rmp::decode::read_str_from_slice(&rest[1..]).unwrap();
+        assert_eq!(socket_id, "s1");
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn encode_disconnect_event() {
+        let data = encode_disconnect("s1", DisconnectReason::HeartbeatTimeout);
+
+        assert_eq!(data[0], EVENT_DISCONNECT);
+        let (socket_id, mut rest) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+        assert_eq!(socket_id, "s1");
+        assert_eq!(rmp::decode::read_pfix(&mut rest).unwrap(), 2);
+    }
+
+    #[test]
+    fn encode_error_event() {
+        let data = encode_error("", &HfnError::ChannelClosed);
+
+        assert_eq!(data[0], EVENT_ERROR);
+        let (socket_id, mut rest) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+        assert_eq!(socket_id, "");
+        let code: u16 = rmp::decode::read_int(&mut rest).unwrap();
+        assert_eq!(code, 10);
+        let (message, _) = rmp::decode::read_str_from_slice(rest).unwrap();
+        assert_eq!(message, "channel closed");
+    }
+
+    #[test]
+    fn encode_rpc_result_event() {
+        let data = encode_rpc_result("s1", 7, Err(&HfnError::RpcTimeout(7)));
+
+        assert_eq!(data[0], EVENT_RPC_RESULT);
+        let (socket_id, mut rest) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+        assert_eq!(socket_id, "s1");
+        assert_eq!(rmp::decode::read_int::<u32, _>(&mut rest).unwrap(), 7);
+        assert_eq!(rmp::decode::read_int::<u16, _>(&mut rest).unwrap(), 19);
+        let (message, mut rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        assert_eq!(message, "rpc call 7 timed out");
+        assert_eq!(rmp::decode::read_bin_len(&mut rest).unwrap(), 0);
+    }
+}
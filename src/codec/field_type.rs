@@ -0,0 +1,239 @@
+use std::{fmt, str::FromStr};
+
+use serde::Serialize;
+
+// The `type` of a schema field in hfn.json:
+//
+//   int8 int16 int32 int64, uint8 uint16 uint32 uint64, float32 float64
+//   bool string bytes timestamp
+//   map<K,V>       K is string, an int or a uint
+//   enum<a,b,c>    the variant index on the wire, its name in HfnValue
+//   ref<id>        another schema of the same package
+//   T?             may be absent
+//
+// int, uint and float are int64, uint64 and float64. A timestamp is milliseconds since
+// the epoch as an int64. Sdks get the parsed type in HfnField.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FieldType {
+    Int {
+        bits: u8,
+    },
+    Uint {
+        bits: u8,
+    },
+    Float {
+        bits: u8,
+    },
+    Bool,
+    String,
+    Bytes,
+    Timestamp,
+    Map {
+        key: Box<FieldType>,
+        value: Box<FieldType>,
+    },
+    Enum {
+        variants: Vec<String>,
+    },
+    Ref {
+        schema_id: u32,
+    },
+    Optional {
+        inner: Box<FieldType>,
+    },
+}
+
+impl FieldType {
+    pub fn is_optional(&self) -> bool {
+        matches!(self, FieldType::Optional { .. })
+    }
+
+    // schema ids this type points at, nested ones included
+    pub fn refs(&self) -> Vec<u32> {
+        match self {
+            FieldType::Ref { schema_id } => vec![*schema_id],
+            FieldType::Map { key, value } => [key.refs(), value.refs()].concat(),
+            FieldType::Optional { inner } => inner.refs(),
+            _ => vec![],
+        }
+    }
+
+    fn is_map_key(&self) -> bool {
+        matches!(
+            self,
+            FieldType::String | FieldType::Int { .. } | FieldType::Uint { .. }
+        )
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = String;
+
+    fn from_str(t: &str) -> Result<Self, Self::Err> {
+        let t = t.trim();
+        if let Some(inner) = t.strip_suffix('?') {
+            let inner = inner.parse::<FieldType>()?;
+            if inner.is_optional() {
+                return Err(format!("`{}` is optional twice", t));
+            }
+            return Ok(FieldType::Optional {
+                inner: Box::new(inner),
+            });
+        }
+
+        let field_type = match t {
+            "int8" => FieldType::Int { bits: 8 },
+            "int16" => FieldType::Int { bits: 16 },
+            "int32" => FieldType::Int { bits: 32 },
+            "int64" | "int" => FieldType::Int { bits: 64 },
+            "uint8" => FieldType::Uint { bits: 8 },
+            "uint16" => FieldType::Uint { bits: 16 },
+            "uint32" => FieldType::Uint { bits: 32 },
+            "uint64" | "uint" => FieldType::Uint { bits: 64 },
+            "float32" => FieldType::Float { bits: 32 },
+            "float64" | "float" => FieldType::Float { bits: 64 },
+            "bool" => FieldType::Bool,
+            "string" => FieldType::String,
+            "bytes" => FieldType::Bytes,
+            "timestamp" => FieldType::Timestamp,
+            _ => return parse_generic(t),
+        };
+        Ok(field_type)
+    }
+}
+
+// map<K,V>, enum<a,b> and ref<id>
+fn parse_generic(t: &str) -> Result<FieldType, String> {
+    let unknown = || format!("unknown type `{}`", t);
+    let (name, args) = t
+        .strip_suffix('>')
+        .and_then(|t| t.split_once('<'))
+        .ok_or_else(unknown)?;
+    let args = split_args(args);
+
+    match (name.trim(), args.as_slice()) {
+        ("map", [key, value]) => {
+            let key = key.parse::<FieldType>()?;
+            if !key.is_map_key() {
+                return Err(format!("`{}` can't key a map", key));
+            }
+            Ok(FieldType::Map {
+                key: Box::new(key),
+                value: Box::new(value.parse()?),
+            })
+        }
+        ("enum", variants) => {
+            let variants: Vec<String> = variants.iter().map(|v| v.to_string()).collect();
+            for (i, variant) in variants.iter().enumerate() {
+                if variant.is_empty() || variants[..i].contains(variant) {
+                    return Err(format!("invalid enum variant `{}` in `{}`", variant, t));
+                }
+            }
+            Ok(FieldType::Enum { variants })
+        }
+        ("ref", [schema_id]) => schema_id
+            .parse()
+            .map(|schema_id| FieldType::Ref { schema_id })
+            .map_err(|_| unknown()),
+        _ => Err(unknown()),
+    }
+}
+
+// comma separated, commas inside nested <> belong to the nested type
+fn split_args(args: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, c) in args.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(args[start..].trim());
+    parts
+}
+
+// the hfn.json form, int64 rather than int
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldType::Int { bits } => write!(f, "int{}", bits),
+            FieldType::Uint { bits } => write!(f, "uint{}", bits),
+            FieldType::Float { bits } => write!(f, "float{}", bits),
+            FieldType::Bool => write!(f, "bool"),
+            FieldType::String => write!(f, "string"),
+            FieldType::Bytes => write!(f, "bytes"),
+            FieldType::Timestamp => write!(f, "timestamp"),
+            FieldType::Map { key, value } => write!(f, "map<{},{}>", key, value),
+            FieldType::Enum { variants } => write!(f, "enum<{}>", variants.join(",")),
+            FieldType::Ref { schema_id } => write!(f, "ref<{}>", schema_id),
+            FieldType::Optional { inner } => write!(f, "{}?", inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::field_type::FieldType;
+
+    #[test]
+    fn parse_field_types() {
+        assert_eq!("int32".parse(), Ok(FieldType::Int { bits: 32 }));
+        assert_eq!(
+            "float".parse(),
+            Ok(FieldType::Float { bits: 64 }),
+            "float is float64"
+        );
+        assert_eq!(
+            "map<string, map<uint8,ref<2>?>>?".parse(),
+            Ok(FieldType::Optional {
+                inner: Box::new(FieldType::Map {
+                    key: Box::new(FieldType::String),
+                    value: Box::new(FieldType::Map {
+                        key: Box::new(FieldType::Uint { bits: 8 }),
+                        value: Box::new(FieldType::Optional {
+                            inner: Box::new(FieldType::Ref { schema_id: 2 }),
+                        }),
+                    }),
+                }),
+            })
+        );
+        assert_eq!(
+            "enum<red,green>".parse(),
+            Ok(FieldType::Enum {
+                variants: vec!["red".to_string(), "green".to_string()],
+            })
+        );
+
+        for t in [
+            "int",
+            "map<string,ref<2>?>",
+            "enum<red,green>",
+            "timestamp?",
+        ] {
+            let parsed: FieldType = t.parse().unwrap();
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+
+        for t in [
+            "json",
+            "int33",
+            "ref<x>",
+            "ref<1,2>",
+            "map<bytes,int32>",
+            "map<string>",
+            "enum<a,a>",
+            "enum<>",
+            "string??",
+            "map<string,int32",
+        ] {
+            assert!(t.parse::<FieldType>().is_err(), "{} should not parse", t);
+        }
+    }
+}
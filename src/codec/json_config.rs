@@ -1,6 +1,23 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
 use serde::{Deserialize, Serialize};
 
-use super::u8_args::*;
+use super::{field_type::FieldType, u8_args::*};
+use crate::error::HfnError;
+
+pub type HfnStruct = (
+    Vec<HfnPackage>,
+    Vec<HfnModule>,
+    Vec<HfnModel>,
+    Vec<HfnHfn>,
+    Vec<HfnRpc>,
+    Vec<HfnSchema>,
+    Vec<HfnField>,
+);
 
 // Deserialize hfn.json
 #[derive(Deserialize, Debug)]
@@ -9,28 +26,61 @@ pub struct JsonConfig {
     pub description: Option<String>,
     pub appid: String,
     pub dev: JsonConfigDev,
+    #[serde(default)]
+    pub heartbeat: Option<JsonConfigHeartbeat>,
+    // production upstream mode, used when no addr is given
+    #[serde(default)]
+    pub gateway: Option<JsonConfigGateway>,
+    // TLS on the listener, InitArgs take precedence
+    #[serde(default)]
+    pub tls: Option<JsonConfigTls>,
     #[serde(rename = "createdAt")]
     pub created_at: String,
     pub packages: Vec<JsonConfigPackage>,
 }
 
+impl FromStr for JsonConfig {
+    type Err = HfnError;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let config: JsonConfig =
+            serde_json::from_str(data).map_err(|e| HfnError::InvalidConfig(e.to_string()))?;
+        let report = config.validate();
+        if !report.errors.is_empty() {
+            let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+            return Err(HfnError::InvalidConfig(errors.join("; ")));
+        }
+        Ok(config)
+    }
+}
+
 impl JsonConfig {
-    pub fn from_str(data: String) -> Self {
-        let config: JsonConfig = serde_json::from_str(&data).expect("failed to parse hfn.json");
-        config
+    // Every problem of a parsed hfn.json. Ids have to be unique, field types have to
+    // parse and references have to name a schema of the same package. The devtools url
+    // has to be a ws or wss url and createdAt an rfc 3339 timestamp. A schema nothing
+    // refers to is only a warning.
+    pub fn validate(&self) -> ConfigReport {
+        let mut report = ConfigReport::default();
+
+        match url::Url::parse(&self.dev.devtools) {
+            Ok(url) if url.scheme() == "ws" || url.scheme() == "wss" => {}
+            Ok(_) => report.error("$.dev.devtools", "expected a ws or wss url".to_string()),
+            Err(e) => report.error("$.dev.devtools", format!("invalid url: {}", e)),
+        }
+        if let Err(e) = chrono::DateTime::parse_from_rfc3339(&self.created_at) {
+            report.error("$.createdAt", format!("invalid rfc 3339 timestamp: {}", e));
+        }
+
+        let mut package_ids = UniqueIds::default();
+        for (p, package) in self.packages.iter().enumerate() {
+            let path = format!("$.packages[{}]", p);
+            package_ids.insert(&mut report, format!("{}.id", path), package.id);
+            package.validate(&path, &mut report);
+        }
+        report
     }
 
-    pub fn to_hfn_struct(
-        &self,
-    ) -> (
-        Vec<HfnPackage>,
-        Vec<HfnModule>,
-        Vec<HfnModel>,
-        Vec<HfnHfn>,
-        Vec<HfnRpc>,
-        Vec<HfnSchema>,
-        Vec<HfnField>,
-    ) {
+    pub fn to_hfn_struct(&self) -> Result<HfnStruct, HfnError> {
         let mut hfn_packages = vec![];
         let mut hfn_modules = vec![];
         let mut hfn_models = vec![];
@@ -38,7 +88,7 @@ impl JsonConfig {
         let mut hfn_rpcs = vec![];
         let mut hfn_schemas = vec![];
         let mut hfn_fields = vec![];
-        let _ = &self.packages.iter().for_each(|p| {
+        for p in &self.packages {
             hfn_packages.push(HfnPackage {
                 id: p.id,
                 name: p.name.clone(),
@@ -83,26 +133,28 @@ impl JsonConfig {
                 });
             });
 
-            p.schemas.iter().for_each(|schema| {
+            for schema in &p.schemas {
                 hfn_schemas.push(HfnSchema {
                     id: schema.id,
                     package_id: p.id,
                 });
 
-                schema.fields.iter().for_each(|field| {
+                for field in &schema.fields {
+                    let field_type = field.field_type()?;
                     hfn_fields.push(HfnField {
                         id: field.id,
                         name: field.name.clone(),
                         schema_id: schema.id,
                         package_id: p.id,
                         t: field.t.clone(),
+                        field_type,
                         is_array: field.is_array,
                     });
-                });
-            });
-        });
+                }
+            }
+        }
 
-        (
+        Ok((
             hfn_packages,
             hfn_modules,
             hfn_models,
@@ -110,7 +162,129 @@ impl JsonConfig {
             hfn_rpcs,
             hfn_schemas,
             hfn_fields,
-        )
+        ))
+    }
+}
+
+// A problem found in hfn.json, path is a JSON path into the file such as
+// `$.packages[0].rpcs[1].reqSchemaId`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+// Errors make hfn.json invalid, warnings go to the sdk in InitResult.
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+}
+
+impl ConfigReport {
+    fn error(&mut self, path: &str, message: String) {
+        let path = path.to_string();
+        self.errors.push(ConfigIssue { path, message });
+    }
+
+    fn warning(&mut self, path: &str, message: String) {
+        let path = path.to_string();
+        self.warnings.push(ConfigIssue { path, message });
+    }
+}
+
+// id -> path of its first use
+#[derive(Default)]
+struct UniqueIds(HashMap<u32, String>);
+
+impl UniqueIds {
+    fn insert(&mut self, report: &mut ConfigReport, path: String, id: u32) {
+        match self.0.get(&id) {
+            Some(first) => report.error(
+                &path,
+                format!("duplicate id {}, first used at {}", id, first),
+            ),
+            None => {
+                self.0.insert(id, path);
+            }
+        }
+    }
+}
+
+impl JsonConfigPackage {
+    fn validate(&self, path: &str, report: &mut ConfigReport) {
+        let mut schema_ids = UniqueIds::default();
+        for (s, schema) in self.schemas.iter().enumerate() {
+            schema_ids.insert(report, format!("{}.schemas[{}].id", path, s), schema.id);
+        }
+        let schemas: HashSet<u32> = self.schemas.iter().map(|s| s.id).collect();
+        let mut referenced = HashSet::new();
+        let mut check_ref = |report: &mut ConfigReport, path: String, id: u32| {
+            referenced.insert(id);
+            if !schemas.contains(&id) {
+                report.error(&path, format!("schema {} not found", id));
+            }
+        };
+
+        let mut module_ids = UniqueIds::default();
+        let mut model_ids = UniqueIds::default();
+        let mut hfn_ids = UniqueIds::default();
+        for (m, module) in self.modules.iter().enumerate() {
+            let path = format!("{}.modules[{}]", path, m);
+            module_ids.insert(report, format!("{}.id", path), module.id);
+
+            for (i, model) in module.models.iter().enumerate() {
+                let path = format!("{}.models[{}]", path, i);
+                model_ids.insert(report, format!("{}.id", path), model.id);
+                check_ref(report, format!("{}.schemaId", path), model.schema_id);
+            }
+            // hfn ids are unique across the modules of a package, messages only name the id
+            for (i, hfn) in module.hfns.iter().enumerate() {
+                let path = format!("{}.hfns[{}]", path, i);
+                hfn_ids.insert(report, format!("{}.id", path), hfn.id);
+                check_ref(report, format!("{}.schemaId", path), hfn.schema_id);
+            }
+        }
+
+        let mut rpc_ids = UniqueIds::default();
+        for (i, rpc) in self.rpcs.iter().enumerate() {
+            let path = format!("{}.rpcs[{}]", path, i);
+            rpc_ids.insert(report, format!("{}.id", path), rpc.id);
+            check_ref(report, format!("{}.reqSchemaId", path), rpc.req_schema_id);
+            check_ref(report, format!("{}.resSchemaId", path), rpc.res_schema_id);
+        }
+
+        for (s, schema) in self.schemas.iter().enumerate() {
+            let mut field_ids = UniqueIds::default();
+            for (f, field) in schema.fields.iter().enumerate() {
+                let path = format!("{}.schemas[{}].fields[{}]", path, s, f);
+                field_ids.insert(report, format!("{}.id", path), field.id);
+                let field_type = match field.t.parse::<FieldType>() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        report.error(&format!("{}.type", path), e);
+                        continue;
+                    }
+                };
+                // a schema referring to itself alone is still unused
+                for id in field_type.refs().into_iter().filter(|id| *id != schema.id) {
+                    check_ref(report, format!("{}.type", path), id);
+                }
+            }
+        }
+
+        for (s, schema) in self.schemas.iter().enumerate() {
+            if !referenced.contains(&schema.id) {
+                let path = format!("{}.schemas[{}]", path, s);
+                report.warning(&path, format!("schema {} is not referenced", schema.id));
+            }
+        }
     }
 }
 
@@ -119,6 +293,33 @@ pub struct JsonConfigDev {
     pub devtools: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonConfigGateway {
+    // ws:// or wss:// endpoint of the gateway cluster
+    pub url: String,
+    // signs the upstream registration, HFN_UPSTREAM_SECRET takes precedence
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+// PEM file paths, relative ones are resolved from the directory of hfn.json
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonConfigTls {
+    pub cert: String,
+    pub key: String,
+    #[serde(rename = "clientCa", default)]
+    pub client_ca: Option<String>,
+}
+
+// seconds, InitArgs take precedence
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonConfigHeartbeat {
+    #[serde(rename = "pingInterval")]
+    pub ping_interval: Option<u64>,
+    #[serde(rename = "pingTimeout")]
+    pub ping_timeout: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JsonConfigPackage {
     pub id: u32,
@@ -174,8 +375,109 @@ pub struct JsonConfigPackageSchema {
 pub struct JsonConfigPackageSchemaField {
     pub id: u32,
     pub name: String,
+    // see FieldType, parsed by validate
     #[serde(rename = "type")]
     pub t: String,
     #[serde(rename = "isArray")]
     pub is_array: bool,
 }
+
+impl JsonConfigPackageSchemaField {
+    pub fn field_type(&self) -> Result<FieldType, HfnError> {
+        self.t
+            .parse()
+            .map_err(|e| HfnError::InvalidConfig(format!("field {}: {}", self.name, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{codec::JsonConfig, error::HfnError};
+
+    const CONFIG: &str = r#"{
+        "name": "test",
+        "appid": "app",
+        "dev": { "devtools": "http://127.0.0.1:1" },
+        "createdAt": "2022-01-01",
+        "packages": [
+            {
+                "id": 1,
+                "name": "pkg",
+                "modules": [
+                    { "id": 1, "name": "a", "models": [], "hfns": [
+                        { "id": 1, "name": "show", "schemaId": 1 }
+                    ] },
+                    { "id": 1, "name": "b", "models": [
+                        { "id": 1, "name": "user", "schemaId": 9 }
+                    ], "hfns": [
+                        { "id": 1, "name": "hide", "schemaId": 1 }
+                    ] }
+                ],
+                "rpcs": [{ "id": 1, "name": "get", "reqSchemaId": 1, "resSchemaId": 8 }],
+                "schemas": [
+                    { "id": 1, "fields": [
+                        { "id": 1, "name": "a", "type": "string", "isArray": false },
+                        { "id": 1, "name": "b", "type": "ref<7>", "isArray": false },
+                        { "id": 2, "name": "c", "type": "json", "isArray": false }
+                    ] },
+                    { "id": 2, "fields": [
+                        { "id": 1, "name": "self", "type": "ref<2>?", "isArray": false }
+                    ] },
+                    { "id": 2, "fields": [] }
+                ]
+            },
+            { "id": 1, "name": "again", "modules": [], "rpcs": [], "schemas": [] }
+        ]
+    }"#;
+
+    #[test]
+    fn validate_config() {
+        let config: JsonConfig = serde_json::from_str(CONFIG).unwrap();
+        let report = config.validate();
+        let paths: Vec<&str> = report.errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "$.dev.devtools",
+                "$.createdAt",
+                "$.packages[0].schemas[2].id",
+                "$.packages[0].modules[1].id",
+                "$.packages[0].modules[1].models[0].schemaId",
+                "$.packages[0].modules[1].hfns[0].id",
+                "$.packages[0].rpcs[0].resSchemaId",
+                "$.packages[0].schemas[0].fields[1].id",
+                "$.packages[0].schemas[0].fields[1].type",
+                "$.packages[0].schemas[0].fields[2].type",
+                "$.packages[1].id",
+            ]
+        );
+        assert_eq!(report.errors[9].message, "unknown type `json`");
+        assert_eq!(
+            report.errors[3].to_string(),
+            "$.packages[0].modules[1].id: duplicate id 1, first used at $.packages[0].modules[0].id"
+        );
+        assert_eq!(report.errors[6].message, "schema 8 not found");
+
+        // a schema only referring to itself is unused
+        let warnings: Vec<&str> = report.warnings.iter().map(|w| w.path.as_str()).collect();
+        assert_eq!(
+            warnings,
+            vec!["$.packages[0].schemas[1]", "$.packages[0].schemas[2]"]
+        );
+        assert_eq!(
+            report.warnings[0].to_string(),
+            "$.packages[0].schemas[1]: schema 2 is not referenced"
+        );
+
+        // every error is reported
+        match JsonConfig::from_str(CONFIG) {
+            Err(HfnError::InvalidConfig(e)) => {
+                assert_eq!(e.matches("; ").count(), 10);
+                assert!(e.contains("$.packages[0].schemas[0].fields[2].type: unknown type `json`"));
+            }
+            _ => panic!("should be an invalid config"),
+        }
+    }
+}
@@ -1,5 +1,10 @@
+pub mod event;
+pub mod field_type;
 pub mod json_config;
+pub mod payload;
+pub mod sign;
 pub mod u8_args;
+pub mod wire;
 
 pub use json_config::JsonConfig;
-pub use u8_args::{InitArgs, InitResult};
+pub use u8_args::{ClientAuth, ErrorResult, InitArgs, InitResult, SlowConsumerPolicy};
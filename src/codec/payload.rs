@@ -0,0 +1,955 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    io::Cursor,
+};
+
+use rmp::Marker;
+
+use super::{
+    field_type::FieldType,
+    json_config::JsonConfig,
+    wire::{read_bytes, remaining, write_str_bytes, ParseError, Reader},
+};
+use crate::error::HfnError;
+
+// nested arrays, maps and structs a payload may have
+const MAX_DEPTH: usize = 32;
+
+// A decoded payload. Schema structs are keyed by field id, absent fields are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HfnValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Array(Vec<HfnValue>),
+    Map(Vec<(HfnValue, HfnValue)>),
+    Struct(BTreeMap<u32, HfnValue>),
+}
+
+impl HfnValue {
+    pub fn field(&self, id: u32) -> Option<&HfnValue> {
+        match self {
+            HfnValue::Struct(fields) => fields.get(&id),
+            _ => None,
+        }
+    }
+}
+
+// Where in the payload a value failed, "$" is the payload itself, "$.2[0]" the first
+// element of field 2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadError {
+    pub path: String,
+    pub error: SchemaError,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+// Why a payload doesn't match its schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    // not msgpack or cut short
+    Malformed(ParseError),
+    // bytes left after the payload's value
+    TrailingBytes,
+    // arrays and maps nested past the limit
+    TooDeep,
+    // a value of the wrong type, or a field the schema doesn't have
+    TypeMismatch,
+    // a number that doesn't fit its field
+    OutOfRange,
+    // a field its schema requires is absent
+    MissingField,
+    // an enum field holding a name or index its type doesn't list
+    UnknownVariant,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Malformed(e) => write!(f, "{}", e),
+            SchemaError::TrailingBytes => write!(f, "trailing bytes after the payload"),
+            SchemaError::TooDeep => write!(f, "nesting too deep"),
+            SchemaError::TypeMismatch => write!(f, "unexpected field type"),
+            SchemaError::OutOfRange => write!(f, "number out of range"),
+            SchemaError::MissingField => write!(f, "missing required field"),
+            SchemaError::UnknownVariant => write!(f, "unknown enum variant"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl From<ParseError> for SchemaError {
+    fn from(e: ParseError) -> Self {
+        SchemaError::Malformed(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SchemaField {
+    // without the Optional of an optional field, that's what an array field's items are
+    t: FieldType,
+    is_array: bool,
+    // may be absent, required otherwise
+    optional: bool,
+}
+
+impl SchemaField {
+    fn new(t: FieldType, is_array: bool) -> Self {
+        match t {
+            FieldType::Optional { inner } => SchemaField {
+                t: *inner,
+                is_array,
+                optional: true,
+            },
+            t => SchemaField {
+                t,
+                is_array,
+                optional: false,
+            },
+        }
+    }
+}
+
+// What a MESSAGE calls, named by its `hfn` or `rpc` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageTarget {
+    Hfn(u32),
+    // the request of the rpc
+    Rpc(u32),
+    // the response to a call of the rpc
+    RpcResult(u32),
+}
+
+// Encodes payloads by the schemas of hfn.json. A struct goes on the wire as a msgpack
+// array where element n is field n, missing fields are nil and trailing nils are cut,
+// so payloads carry no field names. Fields are required unless their type ends in `?`.
+// Enums go by variant index and come back by name.
+#[derive(Debug, Default)]
+pub struct PayloadCodec {
+    packages: HashSet<u32>,
+    // (package id, schema id) -> field id -> field
+    schemas: HashMap<(u32, u32), BTreeMap<u32, SchemaField>>,
+    // (package id, target) -> schema id of its payload
+    targets: HashMap<(u32, MessageTarget), u32>,
+}
+
+impl PayloadCodec {
+    pub fn new(json_config: &JsonConfig) -> Result<Self, HfnError> {
+        let mut codec = PayloadCodec::default();
+        for package in &json_config.packages {
+            codec.packages.insert(package.id);
+
+            for schema in &package.schemas {
+                let fields = schema
+                    .fields
+                    .iter()
+                    .map(|field| {
+                        Ok((
+                            field.id,
+                            SchemaField::new(field.field_type()?, field.is_array),
+                        ))
+                    })
+                    .collect::<Result<_, HfnError>>()?;
+                codec.schemas.insert((package.id, schema.id), fields);
+            }
+
+            for module in &package.modules {
+                for hfn in &module.hfns {
+                    let target = (package.id, MessageTarget::Hfn(hfn.id));
+                    codec.targets.insert(target, hfn.schema_id);
+                }
+            }
+            for rpc in &package.rpcs {
+                let target = (package.id, MessageTarget::Rpc(rpc.id));
+                codec.targets.insert(target, rpc.req_schema_id);
+                let target = (package.id, MessageTarget::RpcResult(rpc.id));
+                codec.targets.insert(target, rpc.res_schema_id);
+            }
+        }
+        Ok(codec)
+    }
+
+    pub fn has_package(&self, package_id: u32) -> bool {
+        self.packages.contains(&package_id)
+    }
+
+    pub fn has_schema(&self, package_id: u32, schema_id: u32) -> bool {
+        self.schemas.contains_key(&(package_id, schema_id))
+    }
+
+    pub fn target_schema(&self, package_id: u32, target: MessageTarget) -> Option<u32> {
+        self.targets.get(&(package_id, target)).copied()
+    }
+
+    // value must be a Struct, an unknown schema is an error of the caller
+    pub fn encode(
+        &self,
+        package_id: u32,
+        schema_id: u32,
+        value: &HfnValue,
+    ) -> Result<Vec<u8>, PayloadError> {
+        let mut path = String::from("$");
+        let t = FieldType::Ref { schema_id };
+        let value = self
+            .encode_value(package_id, &t, value, &mut path)
+            .map_err(|error| PayloadError { path, error })?;
+
+        let mut buf = Vec::new();
+        write_value(&mut buf, &value);
+        Ok(buf)
+    }
+
+    pub fn decode(
+        &self,
+        package_id: u32,
+        schema_id: u32,
+        payload: &[u8],
+    ) -> Result<HfnValue, PayloadError> {
+        let mut cur = Cursor::new(payload);
+        let value = read_value(&mut cur, 0)
+            .and_then(|value| match remaining(&cur) {
+                0 => Ok(value),
+                _ => Err(SchemaError::TrailingBytes),
+            })
+            .map_err(|error| PayloadError {
+                path: "$".to_string(),
+                error,
+            })?;
+
+        let mut path = String::from("$");
+        let t = FieldType::Ref { schema_id };
+        self.decode_value(package_id, &t, value, &mut path)
+            .map_err(|error| PayloadError { path, error })
+    }
+
+    // On error path is left pointing at the offending value.
+    fn encode_value(
+        &self,
+        package_id: u32,
+        t: &FieldType,
+        value: &HfnValue,
+        path: &mut String,
+    ) -> Result<HfnValue, SchemaError> {
+        let (schema_id, fields) = match (t, value) {
+            (FieldType::Ref { schema_id }, HfnValue::Struct(fields)) => (*schema_id, fields),
+            (FieldType::Optional { .. }, HfnValue::Nil) => return Ok(HfnValue::Nil),
+            (FieldType::Optional { inner }, value) => {
+                return self.encode_value(package_id, inner, value, path)
+            }
+            (FieldType::Map { key, value: t }, HfnValue::Map(entries)) => {
+                let mut wire = Vec::with_capacity(entries.len());
+                for (k, v) in entries {
+                    let len = path.len();
+                    push_key(path, k);
+                    let k = check_scalar(key, k.clone())?;
+                    let v = self.encode_value(package_id, t, v, path)?;
+                    path.truncate(len);
+                    wire.push((k, v));
+                }
+                return Ok(HfnValue::Map(wire));
+            }
+            (FieldType::Enum { variants }, HfnValue::String(name)) => {
+                return match variants.iter().position(|v| v == name) {
+                    Some(i) => Ok(HfnValue::Uint(i as u64)),
+                    None => Err(SchemaError::UnknownVariant),
+                };
+            }
+            (t, value) => return check_scalar(t, value.clone()),
+        };
+        let schema = self
+            .schemas
+            .get(&(package_id, schema_id))
+            .ok_or(SchemaError::TypeMismatch)?;
+
+        let mut wire = Vec::new();
+        for (&id, value) in fields {
+            if *value == HfnValue::Nil {
+                continue;
+            }
+
+            let len = path.len();
+            path.push_str(&format!(".{}", id));
+            // the schema has no such field
+            let field = schema.get(&id).ok_or(SchemaError::TypeMismatch)?;
+            let value = self.encode_field(package_id, field, value, path)?;
+            path.truncate(len);
+
+            if wire.len() <= id as usize {
+                wire.resize(id as usize + 1, HfnValue::Nil);
+            }
+            wire[id as usize] = value;
+        }
+
+        let present = |id: u32| wire.get(id as usize).is_some_and(|v| *v != HfnValue::Nil);
+        check_required(schema, present, path)?;
+        Ok(HfnValue::Array(wire))
+    }
+
+    fn encode_field(
+        &self,
+        package_id: u32,
+        field: &SchemaField,
+        value: &HfnValue,
+        path: &mut String,
+    ) -> Result<HfnValue, SchemaError> {
+        if !field.is_array {
+            return self.encode_value(package_id, &field.t, value, path);
+        }
+
+        let items = match value {
+            HfnValue::Array(v) => v,
+            _ => return Err(SchemaError::TypeMismatch),
+        };
+        let mut wire = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            let len = path.len();
+            path.push_str(&format!("[{}]", i));
+            wire.push(self.encode_value(package_id, &field.t, item, path)?);
+            path.truncate(len);
+        }
+        Ok(HfnValue::Array(wire))
+    }
+
+    fn decode_value(
+        &self,
+        package_id: u32,
+        t: &FieldType,
+        value: HfnValue,
+        path: &mut String,
+    ) -> Result<HfnValue, SchemaError> {
+        let (schema_id, items) = match (t, value) {
+            (FieldType::Ref { schema_id }, HfnValue::Array(items)) => (*schema_id, items),
+            (FieldType::Optional { .. }, HfnValue::Nil) => return Ok(HfnValue::Nil),
+            (FieldType::Optional { inner }, value) => {
+                return self.decode_value(package_id, inner, value, path)
+            }
+            (FieldType::Map { key, value: t }, HfnValue::Map(entries)) => {
+                let mut values = Vec::with_capacity(entries.len());
+                for (k, v) in entries {
+                    let len = path.len();
+                    push_key(path, &k);
+                    let k = check_scalar(key, k)?;
+                    let v = self.decode_value(package_id, t, v, path)?;
+                    path.truncate(len);
+                    values.push((k, v));
+                }
+                return Ok(HfnValue::Map(values));
+            }
+            (FieldType::Enum { variants }, HfnValue::Uint(i)) => {
+                return usize::try_from(i)
+                    .ok()
+                    .and_then(|i| variants.get(i))
+                    .map(|name| HfnValue::String(name.clone()))
+                    .ok_or(SchemaError::UnknownVariant);
+            }
+            (t, value) => return check_scalar(t, value),
+        };
+        let schema = self
+            .schemas
+            .get(&(package_id, schema_id))
+            .ok_or(SchemaError::TypeMismatch)?;
+
+        let mut fields = BTreeMap::new();
+        for (id, value) in items.into_iter().enumerate() {
+            let id = u32::try_from(id).map_err(|_| SchemaError::OutOfRange)?;
+            // a field this side doesn't know yet is skipped
+            let field = match schema.get(&id) {
+                Some(v) if value != HfnValue::Nil => v,
+                _ => continue,
+            };
+
+            let len = path.len();
+            path.push_str(&format!(".{}", id));
+            let value = self.decode_field(package_id, field, value, path)?;
+            path.truncate(len);
+
+            fields.insert(id, value);
+        }
+
+        check_required(schema, |id| fields.contains_key(&id), path)?;
+        Ok(HfnValue::Struct(fields))
+    }
+
+    fn decode_field(
+        &self,
+        package_id: u32,
+        field: &SchemaField,
+        value: HfnValue,
+        path: &mut String,
+    ) -> Result<HfnValue, SchemaError> {
+        if !field.is_array {
+            return self.decode_value(package_id, &field.t, value, path);
+        }
+
+        let items = match value {
+            HfnValue::Array(v) => v,
+            _ => return Err(SchemaError::TypeMismatch),
+        };
+        let mut values = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            let len = path.len();
+            path.push_str(&format!("[{}]", i));
+            values.push(self.decode_value(package_id, &field.t, item, path)?);
+            path.truncate(len);
+        }
+        Ok(HfnValue::Array(values))
+    }
+}
+
+fn check_required(
+    schema: &BTreeMap<u32, SchemaField>,
+    present: impl Fn(u32) -> bool,
+    path: &mut String,
+) -> Result<(), SchemaError> {
+    match schema
+        .iter()
+        .find(|(id, field)| !field.optional && !present(**id))
+    {
+        Some((id, _)) => {
+            path.push_str(&format!(".{}", id));
+            Err(SchemaError::MissingField)
+        }
+        None => Ok(()),
+    }
+}
+
+// a map entry is `["key"]` or `[1]` in a path
+fn push_key(path: &mut String, key: &HfnValue) {
+    match key {
+        HfnValue::String(k) => path.push_str(&format!("[{:?}]", k)),
+        HfnValue::Int(k) => path.push_str(&format!("[{}]", k)),
+        HfnValue::Uint(k) => path.push_str(&format!("[{}]", k)),
+        _ => path.push_str("[?]"),
+    }
+}
+
+// Scalars are coerced where nothing is lost, msgpack writers pick the smallest integer
+// encoding and some write whole floats as integers. Integers are checked against the
+// width of their type, a float32 is rounded to it.
+fn check_scalar(t: &FieldType, value: HfnValue) -> Result<HfnValue, SchemaError> {
+    let int = |v: i128, bits: u8| {
+        let max = (1i128 << (bits - 1)) - 1;
+        match (-max - 1..=max).contains(&v) {
+            true => Ok(HfnValue::Int(v as i64)),
+            false => Err(SchemaError::OutOfRange),
+        }
+    };
+    let uint = |v: i128, bits: u8| match (0..1i128 << bits).contains(&v) {
+        true => Ok(HfnValue::Uint(v as u64)),
+        false => Err(SchemaError::OutOfRange),
+    };
+    let float = |v: f64, bits: u8| match bits {
+        32 if v.is_finite() && v.abs() > f32::MAX as f64 => Err(SchemaError::OutOfRange),
+        32 => Ok(HfnValue::Float(v as f32 as f64)),
+        _ => Ok(HfnValue::Float(v)),
+    };
+
+    match (t, value) {
+        (FieldType::Int { bits }, HfnValue::Int(v)) => int(v as i128, *bits),
+        (FieldType::Int { bits }, HfnValue::Uint(v)) => int(v as i128, *bits),
+        (FieldType::Timestamp, HfnValue::Int(v)) => int(v as i128, 64),
+        (FieldType::Timestamp, HfnValue::Uint(v)) => int(v as i128, 64),
+        (FieldType::Uint { bits }, HfnValue::Int(v)) => uint(v as i128, *bits),
+        (FieldType::Uint { bits }, HfnValue::Uint(v)) => uint(v as i128, *bits),
+        (FieldType::Float { bits }, HfnValue::Float(v)) => float(v, *bits),
+        (FieldType::Float { bits }, HfnValue::Int(v)) => float(v as f64, *bits),
+        (FieldType::Float { bits }, HfnValue::Uint(v)) => float(v as f64, *bits),
+        (FieldType::Bool, v @ HfnValue::Bool(_))
+        | (FieldType::String, v @ HfnValue::String(_))
+        | (FieldType::Bytes, v @ HfnValue::Bytes(_)) => Ok(v),
+        _ => Err(SchemaError::TypeMismatch),
+    }
+}
+
+// Any msgpack value. Extension types are rejected, nothing in hfn.json declares one.
+pub fn read_value(cur: &mut Reader, depth: usize) -> Result<HfnValue, SchemaError> {
+    if depth > MAX_DEPTH {
+        return Err(SchemaError::TooDeep);
+    }
+
+    let marker = rmp::decode::read_marker(cur).map_err(|_| ParseError::UnexpectedEof)?;
+    let value = match marker {
+        Marker::Null => HfnValue::Nil,
+        Marker::True => HfnValue::Bool(true),
+        Marker::False => HfnValue::Bool(false),
+        Marker::FixPos(v) => HfnValue::Uint(v as u64),
+        Marker::FixNeg(v) => HfnValue::Int(v as i64),
+        Marker::U8 => HfnValue::Uint(read_be::<1>(cur)?),
+        Marker::U16 => HfnValue::Uint(read_be::<2>(cur)?),
+        Marker::U32 => HfnValue::Uint(read_be::<4>(cur)?),
+        Marker::U64 => HfnValue::Uint(read_be::<8>(cur)?),
+        Marker::I8 => HfnValue::Int(read_be::<1>(cur)? as u8 as i8 as i64),
+        Marker::I16 => HfnValue::Int(read_be::<2>(cur)? as u16 as i16 as i64),
+        Marker::I32 => HfnValue::Int(read_be::<4>(cur)? as u32 as i32 as i64),
+        Marker::I64 => HfnValue::Int(read_be::<8>(cur)? as i64),
+        Marker::F32 => HfnValue::Float(f32::from_bits(read_be::<4>(cur)? as u32) as f64),
+        Marker::F64 => HfnValue::Float(f64::from_bits(read_be::<8>(cur)?)),
+        Marker::FixStr(len) => read_str(cur, len as u32)?,
+        Marker::Str8 => {
+            let len = read_be::<1>(cur)? as u32;
+            read_str(cur, len)?
+        }
+        Marker::Str16 => {
+            let len = read_be::<2>(cur)? as u32;
+            read_str(cur, len)?
+        }
+        Marker::Str32 => {
+            let len = read_be::<4>(cur)? as u32;
+            read_str(cur, len)?
+        }
+        Marker::Bin8 => {
+            let len = read_be::<1>(cur)? as u32;
+            HfnValue::Bytes(read_bytes(cur, len)?)
+        }
+        Marker::Bin16 => {
+            let len = read_be::<2>(cur)? as u32;
+            HfnValue::Bytes(read_bytes(cur, len)?)
+        }
+        Marker::Bin32 => {
+            let len = read_be::<4>(cur)? as u32;
+            HfnValue::Bytes(read_bytes(cur, len)?)
+        }
+        Marker::FixArray(len) => read_array(cur, len as usize, depth)?,
+        Marker::Array16 => {
+            let len = read_be::<2>(cur)? as usize;
+            read_array(cur, len, depth)?
+        }
+        Marker::Array32 => {
+            let len = read_be::<4>(cur)? as usize;
+            read_array(cur, len, depth)?
+        }
+        Marker::FixMap(len) => read_map(cur, len as usize, depth)?,
+        Marker::Map16 => {
+            let len = read_be::<2>(cur)? as usize;
+            read_map(cur, len, depth)?
+        }
+        Marker::Map32 => {
+            let len = read_be::<4>(cur)? as usize;
+            read_map(cur, len, depth)?
+        }
+        _ => return Err(SchemaError::TypeMismatch),
+    };
+    Ok(value)
+}
+
+fn read_be<const N: usize>(cur: &mut Reader) -> Result<u64, ParseError> {
+    let bytes = read_bytes(cur, N as u32)?;
+    Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn read_str(cur: &mut Reader, len: u32) -> Result<HfnValue, ParseError> {
+    let bytes = read_bytes(cur, len)?;
+    String::from_utf8(bytes)
+        .map(HfnValue::String)
+        .map_err(|_| ParseError::InvalidUtf8)
+}
+
+fn read_array(cur: &mut Reader, len: usize, depth: usize) -> Result<HfnValue, SchemaError> {
+    // every element takes at least a byte, don't trust the count for the allocation
+    let mut items = Vec::with_capacity(len.min(remaining(cur)));
+    for _ in 0..len {
+        items.push(read_value(cur, depth + 1)?);
+    }
+    Ok(HfnValue::Array(items))
+}
+
+fn read_map(cur: &mut Reader, len: usize, depth: usize) -> Result<HfnValue, SchemaError> {
+    let mut entries = Vec::with_capacity(len.min(remaining(cur) / 2));
+    for _ in 0..len {
+        let key = read_value(cur, depth + 1)?;
+        let value = read_value(cur, depth + 1)?;
+        entries.push((key, value));
+    }
+    Ok(HfnValue::Map(entries))
+}
+
+// A Struct outside a schema is written as a map keyed by field id.
+pub fn write_value(buf: &mut Vec<u8>, value: &HfnValue) {
+    match value {
+        HfnValue::Nil => rmp::encode::write_nil(buf).unwrap(),
+        HfnValue::Bool(v) => rmp::encode::write_bool(buf, *v).unwrap(),
+        HfnValue::Int(v) => {
+            rmp::encode::write_sint(buf, *v).unwrap();
+        }
+        HfnValue::Uint(v) => {
+            rmp::encode::write_uint(buf, *v).unwrap();
+        }
+        // a float32 field, or any float that fits one exactly
+        HfnValue::Float(v) if *v as f32 as f64 == *v => {
+            rmp::encode::write_f32(buf, *v as f32).unwrap()
+        }
+        HfnValue::Float(v) => rmp::encode::write_f64(buf, *v).unwrap(),
+        HfnValue::String(v) => write_str_bytes(buf, v.as_bytes()),
+        HfnValue::Bytes(v) => rmp::encode::write_bin(buf, v).unwrap(),
+        HfnValue::Array(items) => {
+            rmp::encode::write_array_len(buf, items.len() as u32).unwrap();
+            for item in items {
+                write_value(buf, item);
+            }
+        }
+        HfnValue::Map(entries) => {
+            rmp::encode::write_map_len(buf, entries.len() as u32).unwrap();
+            for (key, value) in entries {
+                write_value(buf, key);
+                write_value(buf, value);
+            }
+        }
+        HfnValue::Struct(fields) => {
+            rmp::encode::write_map_len(buf, fields.len() as u32).unwrap();
+            for (id, value) in fields {
+                rmp::encode::write_uint(buf, *id as u64).unwrap();
+                write_value(buf, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, str::FromStr};
+
+    use proptest::prelude::*;
+
+    use crate::codec::{
+        payload::*,
+        wire::{ParseError, Reader},
+        JsonConfig,
+    };
+
+    fn codec() -> PayloadCodec {
+        let config = JsonConfig::from_str(
+            r#"{
+                "name": "test",
+                "appid": "app",
+                "dev": { "devtools": "ws://127.0.0.1:1" },
+                "createdAt": "2022-01-01T00:00:00Z",
+                "packages": [{
+                    "id": 1,
+                    "name": "pkg",
+                    "modules": [],
+                    "rpcs": [],
+                    "schemas": [
+                        { "id": 1, "fields": [
+                            { "id": 1, "name": "name", "type": "string?", "isArray": false },
+                            { "id": 2, "name": "age", "type": "uint?", "isArray": false },
+                            { "id": 3, "name": "tags", "type": "string?", "isArray": true },
+                            { "id": 4, "name": "owner", "type": "ref<2>?", "isArray": false },
+                            { "id": 5, "name": "score", "type": "float?", "isArray": false },
+                            { "id": 7, "name": "extra", "type": "map<uint8,string?>?", "isArray": false },
+                            { "id": 8, "name": "color", "type": "enum<red,green>?", "isArray": true },
+                            { "id": 9, "name": "level", "type": "int8?", "isArray": false },
+                            { "id": 10, "name": "seen", "type": "timestamp?", "isArray": false },
+                            { "id": 11, "name": "ratio", "type": "float32?", "isArray": false },
+                            { "id": 12, "name": "owners", "type": "map<string,ref<2>>?", "isArray": false }
+                        ] },
+                        { "id": 2, "fields": [
+                            { "id": 1, "name": "id", "type": "int", "isArray": false }
+                        ] }
+                    ]
+                }]
+            }"#,
+        )
+        .unwrap();
+        PayloadCodec::new(&config).unwrap()
+    }
+
+    fn fields(fields: Vec<(u32, HfnValue)>) -> HfnValue {
+        HfnValue::Struct(fields.into_iter().collect())
+    }
+
+    #[test]
+    fn encode_payload_by_schema() {
+        let codec = codec();
+        let value = fields(vec![
+            (1, HfnValue::String("a".to_string())),
+            (3, HfnValue::Array(vec![HfnValue::String("x".to_string())])),
+            (4, fields(vec![(1, HfnValue::Int(-1))])),
+            (5, HfnValue::Float(0.5)),
+            (7, HfnValue::Map(vec![(HfnValue::Uint(1), HfnValue::Nil)])),
+        ]);
+
+        let data = codec.encode(1, 1, &value).unwrap();
+        // element n is field n, field 2 and 6 are absent
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            read_value(&mut cur, 0),
+            Ok(HfnValue::Array(vec![
+                HfnValue::Nil,
+                HfnValue::String("a".to_string()),
+                HfnValue::Nil,
+                HfnValue::Array(vec![HfnValue::String("x".to_string())]),
+                HfnValue::Array(vec![HfnValue::Nil, HfnValue::Int(-1)]),
+                HfnValue::Float(0.5),
+                HfnValue::Nil,
+                HfnValue::Map(vec![(HfnValue::Uint(1), HfnValue::Nil)]),
+            ]))
+        );
+        assert_eq!(codec.decode(1, 1, &data), Ok(value));
+
+        // integers written by another encoder are coerced to the field type
+        let mut data = Vec::new();
+        rmp::encode::write_array_len(&mut data, 6).unwrap();
+        rmp::encode::write_nil(&mut data).unwrap();
+        rmp::encode::write_nil(&mut data).unwrap();
+        rmp::encode::write_sint(&mut data, 30).unwrap();
+        rmp::encode::write_nil(&mut data).unwrap();
+        rmp::encode::write_array_len(&mut data, 2).unwrap();
+        rmp::encode::write_nil(&mut data).unwrap();
+        rmp::encode::write_uint(&mut data, 7).unwrap();
+        rmp::encode::write_uint(&mut data, 2).unwrap();
+        let value = codec.decode(1, 1, &data).unwrap();
+        assert_eq!(value.field(2), Some(&HfnValue::Uint(30)));
+        assert_eq!(value.field(4), Some(&fields(vec![(1, HfnValue::Int(7))])));
+        assert_eq!(value.field(5), Some(&HfnValue::Float(2.0)));
+    }
+
+    #[test]
+    fn reject_payload_not_matching_schema() {
+        let codec = codec();
+        fn error<T>(path: &str, error: SchemaError) -> Result<T, PayloadError> {
+            Err(PayloadError {
+                path: path.to_string(),
+                error,
+            })
+        }
+
+        let value = fields(vec![(
+            3,
+            HfnValue::Array(vec![HfnValue::String("x".to_string()), HfnValue::Int(1)]),
+        )]);
+        assert_eq!(
+            codec.encode(1, 1, &value),
+            error("$.3[1]", SchemaError::TypeMismatch)
+        );
+
+        let value = fields(vec![(2, HfnValue::Int(-1))]);
+        assert_eq!(
+            codec.encode(1, 1, &value),
+            error("$.2", SchemaError::OutOfRange)
+        );
+
+        // id of the owner is required
+        let value = fields(vec![(4, fields(vec![]))]);
+        assert_eq!(
+            codec.encode(1, 1, &value),
+            error("$.4.1", SchemaError::MissingField)
+        );
+        let mut data = Vec::new();
+        let wire = HfnValue::Array(vec![
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Array(vec![]),
+        ]);
+        write_value(&mut data, &wire);
+        assert_eq!(
+            codec.decode(1, 1, &data),
+            error("$.4.1", SchemaError::MissingField)
+        );
+
+        // the schema has no field 6
+        let value = fields(vec![(6, HfnValue::Bool(true))]);
+        assert_eq!(
+            codec.encode(1, 1, &value),
+            error("$.6", SchemaError::TypeMismatch)
+        );
+        assert_eq!(
+            codec.encode(1, 1, &HfnValue::Uint(1)),
+            error("$", SchemaError::TypeMismatch)
+        );
+
+        // a nested struct with a string id
+        let mut data = Vec::new();
+        let wire = HfnValue::Array(vec![
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Array(vec![HfnValue::Nil, HfnValue::String("1".to_string())]),
+        ]);
+        write_value(&mut data, &wire);
+        assert_eq!(
+            codec.decode(1, 1, &data),
+            error("$.4.1", SchemaError::TypeMismatch)
+        );
+
+        // fields unknown to this side are skipped
+        let mut data = Vec::new();
+        let wire = HfnValue::Array(vec![
+            HfnValue::Nil,
+            HfnValue::String("a".to_string()),
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Bool(true),
+        ]);
+        write_value(&mut data, &wire);
+        assert_eq!(
+            codec.decode(1, 1, &data),
+            Ok(fields(vec![(1, HfnValue::String("a".to_string()))]))
+        );
+
+        let data = vec![0x91; MAX_DEPTH + 2];
+        assert_eq!(codec.decode(1, 1, &data), error("$", SchemaError::TooDeep));
+        assert_eq!(
+            codec.decode(1, 1, &[0xdc, 0xff, 0xff]),
+            error("$", SchemaError::Malformed(ParseError::UnexpectedEof))
+        );
+        let mut data = codec
+            .encode(1, 1, &fields(vec![(1, HfnValue::Nil)]))
+            .unwrap();
+        data.push(0xc0);
+        assert_eq!(
+            codec.decode(1, 1, &data),
+            error("$", SchemaError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn encode_typed_fields() {
+        let codec = codec();
+        let color = |c: &str| HfnValue::String(c.to_string());
+        let value = fields(vec![
+            (8, HfnValue::Array(vec![color("green"), color("red")])),
+            (9, HfnValue::Int(-128)),
+            (10, HfnValue::Int(1_640_995_200_000)),
+            (11, HfnValue::Float(0.25)),
+            (
+                12,
+                HfnValue::Map(vec![(color("a"), fields(vec![(1, HfnValue::Int(1))]))]),
+            ),
+        ]);
+
+        let data = codec.encode(1, 1, &value).unwrap();
+        let mut cur = Cursor::new(&data[..]);
+        let wire = read_value(&mut cur, 0).unwrap();
+        let items = match &wire {
+            HfnValue::Array(items) => items,
+            _ => panic!("{:?}", wire),
+        };
+        // enums go by index
+        assert_eq!(
+            items[8],
+            HfnValue::Array(vec![HfnValue::Uint(1), HfnValue::Uint(0)])
+        );
+        assert_eq!(codec.decode(1, 1, &data), Ok(value));
+
+        // a float32 is rounded to it
+        let value = fields(vec![(11, HfnValue::Float(0.1))]);
+        let data = codec.encode(1, 1, &value).unwrap();
+        assert_eq!(
+            codec.decode(1, 1, &data).unwrap().field(11),
+            Some(&HfnValue::Float(0.1f32 as f64))
+        );
+
+        fn error(path: &str, error: SchemaError) -> Result<Vec<u8>, PayloadError> {
+            Err(PayloadError {
+                path: path.to_string(),
+                error,
+            })
+        }
+        for (value, path, e) in [
+            (
+                fields(vec![(9, HfnValue::Int(128))]),
+                "$.9",
+                SchemaError::OutOfRange,
+            ),
+            (
+                fields(vec![(11, HfnValue::Float(1e39))]),
+                "$.11",
+                SchemaError::OutOfRange,
+            ),
+            (
+                fields(vec![(8, HfnValue::Array(vec![color("blue")]))]),
+                "$.8[0]",
+                SchemaError::UnknownVariant,
+            ),
+            (
+                fields(vec![(
+                    7,
+                    HfnValue::Map(vec![(HfnValue::Uint(256), color("x"))]),
+                )]),
+                "$.7[256]",
+                SchemaError::OutOfRange,
+            ),
+            (
+                fields(vec![(
+                    12,
+                    HfnValue::Map(vec![(color("a"), fields(vec![]))]),
+                )]),
+                "$.12[\"a\"].1",
+                SchemaError::MissingField,
+            ),
+        ] {
+            assert_eq!(codec.encode(1, 1, &value), error(path, e));
+        }
+
+        // an enum index past its variants
+        let mut data = Vec::new();
+        let wire = HfnValue::Array(vec![
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Nil,
+            HfnValue::Array(vec![HfnValue::Uint(2)]),
+        ]);
+        write_value(&mut data, &wire);
+        assert_eq!(
+            codec.decode(1, 1, &data),
+            Err(PayloadError {
+                path: "$.8[0]".to_string(),
+                error: SchemaError::UnknownVariant,
+            })
+        );
+    }
+
+    fn value() -> impl Strategy<Value = HfnValue> {
+        let leaf = prop_oneof![
+            Just(HfnValue::Nil),
+            any::<bool>().prop_map(HfnValue::Bool),
+            (i64::MIN..0).prop_map(HfnValue::Int),
+            any::<u64>().prop_map(HfnValue::Uint),
+            any::<f64>()
+                .prop_filter("nan", |v| !v.is_nan())
+                .prop_map(HfnValue::Float),
+            any::<String>().prop_map(HfnValue::String),
+            prop::collection::vec(any::<u8>(), 0..16).prop_map(HfnValue::Bytes),
+        ];
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(HfnValue::Array),
+                prop::collection::vec((inner.clone(), inner), 0..4).prop_map(HfnValue::Map),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn dynamic_value_round_trip(value in value()) {
+            let mut data = Vec::new();
+            write_value(&mut data, &value);
+            let mut cur: Reader = Cursor::new(&data[..]);
+            prop_assert_eq!(read_value(&mut cur, 0), Ok(value));
+            prop_assert_eq!(cur.position() as usize, data.len());
+        }
+    }
+}
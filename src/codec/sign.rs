@@ -0,0 +1,60 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// HMAC-SHA256, lowercase hex
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    // hmac takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// constant time check of a hex signature made by hmac_sha256_hex
+pub fn verify_hmac_sha256_hex(key: &[u8], data: &[u8], sig: &str) -> bool {
+    let sig = match decode_hex(sig) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.verify_slice(&sig).is_ok()
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::sign::*;
+
+    #[test]
+    fn hmac_sha256_test_vector() {
+        // RFC 4231 test case 2
+        let sig = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            sig
+        );
+        assert!(verify_hmac_sha256_hex(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            sig
+        ));
+        assert!(!verify_hmac_sha256_hex(b"Jefe", b"what do ya want?", sig));
+        assert!(!verify_hmac_sha256_hex(b"Jefe", b"", "zz"));
+        assert!(!verify_hmac_sha256_hex(b"Jefe", b"", "é0"));
+    }
+}
@@ -1,8 +1,10 @@
-use std::io::Cursor;
+use std::{collections::HashMap, io::Cursor};
 
-use rmp_serde;
 use serde::{Deserialize, Serialize};
 
+use super::field_type::FieldType;
+use crate::error::HfnError;
+
 // Deserialize init options
 #[derive(Debug, Deserialize)]
 pub struct InitArgs {
@@ -13,12 +15,89 @@ pub struct InitArgs {
     pub pkg_names: Vec<String>,
     pub hfn_config_path: Option<String>,
     pub tokio_work_threads: Option<usize>,
+    // seconds a disconnected session can be resumed, 0 disables resumption
+    #[serde(default)]
+    pub session_grace_period: Option<u64>,
+    // buffered events waiting for read()
+    #[serde(default)]
+    pub read_chan_capacity: Option<usize>,
+    // buffered outgoing messages per socket
+    #[serde(default)]
+    pub socket_chan_capacity: Option<usize>,
+    // buffered outgoing messages to the gateway
+    #[serde(default)]
+    pub gateway_chan_capacity: Option<usize>,
+    #[serde(default)]
+    pub slow_consumer_policy: Option<SlowConsumerPolicy>,
+    // seconds between server PINGs, overrides hfn.json
+    #[serde(default)]
+    pub ping_interval: Option<u64>,
+    // seconds to wait for the PONG, overrides hfn.json
+    #[serde(default)]
+    pub ping_timeout: Option<u64>,
+    // live client connections, clients over it get a RETRY
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    // seconds a client over max_connections is told to wait
+    #[serde(default)]
+    pub connection_retry_delay: Option<u64>,
+    // kb, client payloads over it are compressed, unset disables compression
+    #[serde(default)]
+    pub compress_size: Option<u64>,
+    // gateway cluster to serve clients through instead of listening on addr,
+    // overrides hfn.json
+    #[serde(default)]
+    pub gateway_url: Option<String>,
+    // how /hfn clients prove who they are, a host authenticator takes precedence
+    #[serde(default)]
+    pub client_auth: Option<ClientAuth>,
+    // PEM files, serve the listener over TLS, override tls in hfn.json
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    // PEM roots, clients must present a certificate chaining to one of them
+    #[serde(default)]
+    pub tls_client_ca_path: Option<String>,
+    // reject client messages that don't call a known hfn or rpc with a payload
+    // matching its schema
+    #[serde(default)]
+    pub strict_payloads: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientAuth {
+    // token -> subject, the client sends `token` or an Authorization: Bearer header
+    Token {
+        tokens: HashMap<String, String>,
+    },
+    // the client signs aid, cid, sid and ts, see Authenticator::Hmac
+    Hmac {
+        secret: String,
+        // seconds the client ts may be off, 300 by default
+        #[serde(default)]
+        max_skew: Option<u64>,
+    },
+}
+
+// What send_message does when a socket's buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    // drop the oldest buffered message and report it undelivered
+    DropOldest,
+    // reject the new message with HfnError::Full
+    #[default]
+    DropNewest,
+    // close the socket
+    Disconnect,
 }
 
 impl InitArgs {
-    pub fn from_buf(data: Vec<u8>) -> Self {
+    pub fn from_buf(data: Vec<u8>) -> Result<Self, HfnError> {
         let mut de = rmp_serde::Deserializer::new(Cursor::new(&data));
-        Deserialize::deserialize(&mut de).expect("failed to parse init args")
+        Deserialize::deserialize(&mut de).map_err(|e| HfnError::InvalidInitArgs(e.to_string()))
     }
 }
 
@@ -32,6 +111,8 @@ pub struct InitResult {
     pub rpcs: Vec<HfnRpc>,
     pub schemas: Vec<HfnSchema>,
     pub fields: Vec<HfnField>,
+    // hfn.json problems that don't stop the instance, `path: message`
+    pub warnings: Vec<String>,
 }
 
 impl InitResult {
@@ -44,6 +125,23 @@ impl InitResult {
     }
 }
 
+// Serialize HfnError for sdk
+#[derive(Debug, Serialize)]
+pub struct ErrorResult {
+    pub code: u16,
+    pub message: String,
+}
+
+impl ErrorResult {
+    pub fn to_buf(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut ser = rmp_serde::Serializer::new(&mut buf).with_struct_map();
+        self.serialize(&mut ser)
+            .expect("failed to serialize error result");
+        buf
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct HfnPackage {
     pub id: u32,
@@ -96,7 +194,10 @@ pub struct HfnSchema {
 pub struct HfnField {
     pub id: u32,
     pub name: String,
+    // the type as written in hfn.json
     pub t: String,
+    // `t` parsed, aliases resolved
+    pub field_type: FieldType,
     pub is_array: bool,
     pub package_id: u32,
     pub schema_id: u32,
@@ -0,0 +1,142 @@
+use std::{fmt, io::Cursor};
+
+use rmp::decode::{NumValueReadError, ValueReadError};
+
+// Why a frame could not be decoded. Lengths come from the peer, so every read is
+// checked against what is left in the frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    // the frame ends in the middle of a packet
+    UnexpectedEof,
+    // a field has the wrong msgpack type
+    TypeMismatch,
+    // an integer does not fit its field
+    OutOfRange,
+    InvalidUtf8,
+    UnknownPacket(u8),
+    // a compressed payload that doesn't inflate, or inflates past the limit
+    Decompress,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof => write!(f, "unexpected end of frame"),
+            ParseError::TypeMismatch => write!(f, "unexpected field type"),
+            ParseError::OutOfRange => write!(f, "integer out of range"),
+            ParseError::InvalidUtf8 => write!(f, "invalid utf-8 string"),
+            ParseError::UnknownPacket(t) => write!(f, "unknown packet type: {}", t),
+            ParseError::Decompress => write!(f, "invalid compressed payload"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ValueReadError> for ParseError {
+    fn from(e: ValueReadError) -> Self {
+        match e {
+            ValueReadError::TypeMismatch(_) => ParseError::TypeMismatch,
+            _ => ParseError::UnexpectedEof,
+        }
+    }
+}
+
+impl From<NumValueReadError> for ParseError {
+    fn from(e: NumValueReadError) -> Self {
+        match e {
+            NumValueReadError::TypeMismatch(_) => ParseError::TypeMismatch,
+            NumValueReadError::OutOfRange => ParseError::OutOfRange,
+            _ => ParseError::UnexpectedEof,
+        }
+    }
+}
+
+pub type Reader<'a> = Cursor<&'a [u8]>;
+
+pub fn remaining(cur: &Reader) -> usize {
+    cur.get_ref().len().saturating_sub(cur.position() as usize)
+}
+
+pub fn read_bytes(cur: &mut Reader, len: u32) -> Result<Vec<u8>, ParseError> {
+    let start = cur.position() as usize;
+    let end = start
+        .checked_add(len as usize)
+        .ok_or(ParseError::UnexpectedEof)?;
+    let bytes = cur
+        .get_ref()
+        .get(start..end)
+        .ok_or(ParseError::UnexpectedEof)?
+        .to_vec();
+    cur.set_position(end as u64);
+    Ok(bytes)
+}
+
+// raw bytes of a msgpack str
+pub fn read_str_bytes(cur: &mut Reader) -> Result<Vec<u8>, ParseError> {
+    let len = rmp::decode::read_str_len(cur)?;
+    read_bytes(cur, len)
+}
+
+pub fn read_string(cur: &mut Reader) -> Result<String, ParseError> {
+    String::from_utf8(read_str_bytes(cur)?).map_err(|_| ParseError::InvalidUtf8)
+}
+
+pub fn read_bin(cur: &mut Reader) -> Result<Vec<u8>, ParseError> {
+    let len = rmp::decode::read_bin_len(cur)?;
+    read_bytes(cur, len)
+}
+
+// str -> str map flattened to [key, value, ...]
+pub fn read_headers(cur: &mut Reader) -> Result<Vec<Vec<u8>>, ParseError> {
+    let count = rmp::decode::read_map_len(cur)? as usize;
+    // every entry takes at least two bytes, don't trust the count for the allocation
+    let mut headers = Vec::with_capacity(count.min(remaining(cur) / 2) * 2);
+    for _ in 0..count {
+        headers.push(read_str_bytes(cur)?);
+        headers.push(read_str_bytes(cur)?);
+    }
+    Ok(headers)
+}
+
+// Writing to a Vec can't fail, so the encoders below unwrap.
+
+pub fn write_str_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    rmp::encode::write_str_len(buf, data.len() as u32).unwrap();
+    buf.extend_from_slice(data);
+}
+
+pub fn write_headers(buf: &mut Vec<u8>, headers: &[Vec<u8>]) {
+    rmp::encode::write_map_len(buf, (headers.len() / 2) as u32).unwrap();
+    for chunk in headers.chunks_exact(2) {
+        write_str_bytes(buf, &chunk[0]);
+        write_str_bytes(buf, &chunk[1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::codec::wire::*;
+
+    #[test]
+    fn length_past_end_of_frame() {
+        let mut data = Vec::new();
+        rmp::encode::write_bin_len(&mut data, 100).unwrap();
+        data.extend_from_slice(&[1, 2, 3]);
+
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(read_bin(&mut cur), Err(ParseError::UnexpectedEof));
+    }
+
+    #[test]
+    fn header_count_past_end_of_frame() {
+        let mut data = Vec::new();
+        rmp::encode::write_map_len(&mut data, u32::MAX).unwrap();
+        rmp::encode::write_str(&mut data, "k").unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(read_headers(&mut cur), Err(ParseError::UnexpectedEof));
+    }
+}
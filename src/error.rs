@@ -0,0 +1,138 @@
+use std::fmt;
+
+use crate::codec::ErrorResult;
+
+#[derive(Debug)]
+pub enum HfnError {
+    // hfn.json could not be located
+    ConfigNotFound(String),
+    // hfn.json exists but could not be read or parsed
+    InvalidConfig(String),
+    // msgpack encoded init args could not be decoded
+    InvalidInitArgs(String),
+    AlreadyInitialized,
+    NotInitialized,
+    // failed to build the tokio runtime
+    Runtime(String),
+    // listen address is missing or malformed, or binding failed
+    InvalidAddr(String),
+    // devtools / gateway url is malformed
+    InvalidUrl(String),
+    SocketNotFound(String),
+    ChannelClosed,
+    // instance has been shut down
+    Shutdown,
+    // websocket connection failed or dropped
+    Transport(String),
+    // the gateway write channel is at capacity, retry later
+    WouldBlock,
+    // the socket's outgoing buffer is at capacity
+    Full(String),
+    // a peer sent a frame that could not be decoded
+    ProtocolViolation(String),
+    // an api argument is out of range
+    InvalidArgument(String),
+    // the listener certificate, key or client ca could not be loaded
+    Tls(String),
+    // a payload doesn't match its schema
+    InvalidPayload(String),
+    // no response to the rpc call within its timeout
+    RpcTimeout(u32),
+    // the client answered the rpc call with an error
+    RpcFailed(String),
+}
+
+impl HfnError {
+    // stable numeric code shared with every sdk
+    pub fn code(&self) -> u16 {
+        match self {
+            HfnError::ConfigNotFound(_) => 1,
+            HfnError::InvalidConfig(_) => 2,
+            HfnError::InvalidInitArgs(_) => 3,
+            HfnError::AlreadyInitialized => 4,
+            HfnError::NotInitialized => 5,
+            HfnError::Runtime(_) => 6,
+            HfnError::InvalidAddr(_) => 7,
+            HfnError::InvalidUrl(_) => 8,
+            HfnError::SocketNotFound(_) => 9,
+            HfnError::ChannelClosed => 10,
+            HfnError::Shutdown => 11,
+            HfnError::Transport(_) => 12,
+            HfnError::WouldBlock => 13,
+            HfnError::Full(_) => 14,
+            HfnError::ProtocolViolation(_) => 15,
+            HfnError::InvalidArgument(_) => 16,
+            HfnError::Tls(_) => 17,
+            HfnError::InvalidPayload(_) => 18,
+            HfnError::RpcTimeout(_) => 19,
+            HfnError::RpcFailed(_) => 20,
+        }
+    }
+
+    pub fn to_buf(&self) -> Vec<u8> {
+        ErrorResult {
+            code: self.code(),
+            message: self.to_string(),
+        }
+        .to_buf()
+    }
+}
+
+impl fmt::Display for HfnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HfnError::ConfigNotFound(path) => write!(f, "hfn.json file not found: {}", path),
+            HfnError::InvalidConfig(e) => write!(f, "invalid hfn.json: {}", e),
+            HfnError::InvalidInitArgs(e) => write!(f, "invalid init args: {}", e),
+            HfnError::AlreadyInitialized => write!(f, "instance already initialized"),
+            HfnError::NotInitialized => write!(f, "instance not initialized"),
+            HfnError::Runtime(e) => write!(f, "unable to build tokio runtime: {}", e),
+            HfnError::InvalidAddr(e) => write!(f, "invalid listen addr: {}", e),
+            HfnError::InvalidUrl(e) => write!(f, "invalid url: {}", e),
+            HfnError::SocketNotFound(id) => write!(f, "socket not found: {}", id),
+            HfnError::ChannelClosed => write!(f, "channel closed"),
+            HfnError::Shutdown => write!(f, "instance has been shut down"),
+            HfnError::Transport(e) => write!(f, "transport error: {}", e),
+            HfnError::WouldBlock => write!(f, "channel is full, try again later"),
+            HfnError::Full(id) => write!(f, "socket buffer is full: {}", id),
+            HfnError::ProtocolViolation(e) => write!(f, "protocol violation: {}", e),
+            HfnError::InvalidArgument(e) => write!(f, "invalid argument: {}", e),
+            HfnError::Tls(e) => write!(f, "tls error: {}", e),
+            HfnError::InvalidPayload(e) => write!(f, "invalid payload: {}", e),
+            HfnError::RpcTimeout(id) => write!(f, "rpc call {} timed out", id),
+            HfnError::RpcFailed(e) => write!(f, "rpc call failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HfnError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::error::HfnError;
+
+    #[test]
+    fn encode_error_buf() {
+        let buf = HfnError::ConfigNotFound("/tmp/hfn.json".to_string()).to_buf();
+
+        let mut cur = Cursor::new(&buf);
+        assert_eq!(rmp::decode::read_map_len(&mut cur).unwrap(), 2);
+
+        let mut key = [0u8; 16];
+        assert_eq!(rmp::decode::read_str(&mut cur, &mut key).unwrap(), "code");
+        assert_eq!(rmp::decode::read_int::<u16, _>(&mut cur).unwrap(), 1);
+
+        let mut key = [0u8; 16];
+        assert_eq!(
+            rmp::decode::read_str(&mut cur, &mut key).unwrap(),
+            "message"
+        );
+        let mut message = [0u8; 64];
+        assert_eq!(
+            rmp::decode::read_str(&mut cur, &mut message).unwrap(),
+            "hfn.json file not found: /tmp/hfn.json"
+        );
+    }
+}
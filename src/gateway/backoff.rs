@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+pub const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Exponential backoff with full jitter: the nth delay is picked at random up to
+// base * 2^n, capped at max, so gateways dropped together don't reconnect together.
+#[derive(Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    // after a connection made it to OPEN
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::gateway::backoff::Backoff;
+
+    #[test]
+    fn delays_grow_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            assert!(backoff.next_delay() <= Duration::from_millis(ceiling));
+        }
+
+        // many attempts don't overflow
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(1));
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn delays_are_jittered() {
+        let mut delays: Vec<Duration> = (0..16)
+            .map(|_| {
+                let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(1));
+                backoff.next_delay()
+            })
+            .collect();
+        delays.dedup();
+        assert!(delays.len() > 1);
+    }
+}
@@ -1,99 +1,294 @@
+use std::{sync::Arc, time::Duration};
+
 use futures_util::StreamExt;
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{mpsc, watch},
+    time::sleep,
+};
+
+use crate::{
+    codec::event::{encode_error, encode_message},
+    error::HfnError,
+    protocol::{
+        compress::{CompressMethod, Compression},
+        Framing, Packet, PacketPong, PROTOCOL_VERSION,
+    },
+    server::rpc::{report_failed_calls, RpcCalls, RPC_GATEWAY_LOST},
+};
+
+use super::{
+    backoff::{Backoff, RECONNECT_BASE_DELAY},
+    signature::UpstreamSigner,
+    transport::Transport,
+};
 
-use crate::gateway::transport::Packet;
+const DEV_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
 
-use super::transport::{PacketMessage, Transport};
+// (socket id, message id, host encoded message)
+pub type GatewayWrite = (String, i32, Vec<u8>);
 
+// The core's connection to a gateway. In dev that is the local devtools, otherwise a
+// gateway cluster that relays clients to cores without a public listener.
 pub struct Gateway {
     pub dev: bool,
     pub runway: url::Url,
-    pub read_tx: mpsc::UnboundedSender<Vec<u8>>,
+    // signs the registration when a secret is configured
+    pub signer: Option<UpstreamSigner>,
+    pub read_tx: mpsc::Sender<Vec<u8>>,
+    // the host's rpc calls to clients behind the gateway
+    pub rpc_calls: Arc<RpcCalls>,
+    pub shutdown_rx: watch::Receiver<bool>,
+}
+
+// How a connection to the gateway ended
+enum Disconnect {
+    Shutdown,
+    // dropped, reconnect with backoff
+    Lost,
+    // RETRY: reconnect after the delay
+    Retry(Duration),
+    // REDIRECT: connect to the target after the delay
+    Redirect(String, Duration),
 }
 
 impl Gateway {
-    pub async fn connect(&self, mut write_rx: mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
-        let stream = Transport::connect(self.runway.clone())
-            .await
-            .expect("failed to connect to devtools");
+    // Stay connected to the gateway until shutdown. Writes wait in the write chan while
+    // disconnected and only go out once a connection got its OPEN.
+    pub async fn run(&self, mut write_rx: mpsc::Receiver<GatewayWrite>) {
+        let mut url = self.runway.clone();
+        // devtools restarting on the same machine should be picked up quickly
+        let mut backoff = match self.dev {
+            true => Backoff::new(RECONNECT_BASE_DELAY, DEV_RECONNECT_MAX_DELAY),
+            false => Backoff::default(),
+        };
+        // taken from the write chan but not sent
+        let mut pending: Option<GatewayWrite> = None;
+        let mut shutdown_rx = self.shutdown_rx.clone();
 
-        let (mut sink, mut stream) = stream.split();
+        loop {
+            let disconnect = self
+                .connect(url.clone(), &mut write_rx, &mut pending, &mut backoff)
+                .await;
 
-        let sink_task = tokio::spawn(async move {
-            while let Some(data) = write_rx.recv().await {
-                Transport::send_message(&mut sink, data).await.unwrap();
-            }
-        });
-
-        let read_tx = self.read_tx.clone();
-        while let Some(packets) = Transport::next(&mut stream).await {
-            for packet in packets {
-                match packet {
-                    Packet::OPEN(open) => {
-                        println!("connected to gateway");
-                    }
-                    Packet::CLOSE(close) => {
-                        // println!("close: {:?}", close);
-                    }
-                    Packet::PING(ping) => {
-                        // println!("ping: {:?}", ping);
-                    }
-                    Packet::PONG(pong) => {
-                        // println!("pong: {:?}", pong);
-                    }
-                    Packet::RETRY(retry) => {
-                        // println!("retry: {:?}", retry);
-                    }
-                    Packet::REDIRECT(redirect) => {
-                        // println!("redirect: {:?}", redirect);
+            let delay = match disconnect {
+                Disconnect::Shutdown => return,
+                Disconnect::Lost => backoff.next_delay(),
+                Disconnect::Retry(delay) => delay,
+                Disconnect::Redirect(target, delay) => {
+                    match Gateway::redirect_url(&self.runway, &target) {
+                        Ok(v) => url = v,
+                        Err(e) => self.report(e).await,
                     }
-                    Packet::MESSAGE(msg) => {
-                        let data = Gateway::encode_message(msg);
-                        read_tx
-                            .send(data)
-                            .expect("failed to send message to read_tx");
+                    delay
+                }
+            };
+            // responses to requests of the lost connection never arrive
+            let failed = self.rpc_calls.fail_all(RPC_GATEWAY_LOST);
+            report_failed_calls(&self.read_tx, failed).await;
+
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = shutdown_rx.changed() => return,
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        url: url::Url,
+        write_rx: &mut mpsc::Receiver<GatewayWrite>,
+        pending: &mut Option<GatewayWrite>,
+        backoff: &mut Backoff,
+    ) -> Disconnect {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        if *shutdown_rx.borrow() {
+            return Disconnect::Shutdown;
+        }
+
+        // we ask for our version, OPEN tells which one the gateway speaks
+        let mut framing = Framing::upstream(PROTOCOL_VERSION);
+        let stream = tokio::select! {
+            stream = Transport::connect(url, framing, self.signer.as_ref()) => match stream {
+                Ok(v) => v,
+                Err(e) => {
+                    let err = HfnError::Transport(format!("failed to connect to {}: {}", self.peer(), e));
+                    self.report(err).await;
+                    return Disconnect::Lost;
+                }
+            },
+            _ = shutdown_rx.changed() => return Disconnect::Shutdown,
+        };
+
+        let (mut sink, mut stream) = stream.split();
+        let mut opened = false;
+
+        loop {
+            tokio::select! {
+                packets = Transport::next(&mut stream, &mut framing, opened) => {
+                    let packets = match packets {
+                        Some(Ok(v)) => v,
+                        Some(Err(e)) => {
+                            let err = HfnError::Transport(format!("malformed {} frame: {}", self.peer(), e));
+                            self.report(err).await;
+                            return Disconnect::Lost;
+                        }
+                        None => break,
+                    };
+
+                    for packet in packets {
+                        match packet {
+                            Packet::OPEN(open) => {
+                                if open.version > PROTOCOL_VERSION {
+                                    let err = HfnError::Transport(format!(
+                                        "{} speaks unsupported protocol version {}",
+                                        self.peer(),
+                                        open.version
+                                    ));
+                                    self.report(err).await;
+                                    return Disconnect::Lost;
+                                }
+
+                                framing.version = open.version;
+                                // the gateway picks from the methods we announced
+                                framing.compression = match CompressMethod::from_u8(open.compress_method) {
+                                    Some(method) if method.is_supported() => Compression {
+                                        method,
+                                        min_size: open.compress_size,
+                                    },
+                                    _ => Compression::NONE,
+                                };
+                                opened = true;
+                                backoff.reset();
+                                if self.dev {
+                                    println!("connected to devtools");
+                                }
+                            }
+                            Packet::MESSAGE(msg) => {
+                                // anything but the response to a pending call goes to the host
+                                let socket_id = String::from_utf8_lossy(&msg.socket_id).into_owned();
+                                let data = self
+                                    .rpc_calls
+                                    .complete(&socket_id, &msg)
+                                    .unwrap_or_else(|| {
+                                        encode_message(
+                                            &msg.socket_id,
+                                            msg.pkg_id,
+                                            &msg.headers,
+                                            &msg.payload,
+                                        )
+                                    });
+                                if self.read_tx.send(data).await.is_err() {
+                                    // host stopped reading
+                                    return Disconnect::Shutdown;
+                                }
+                            }
+                            Packet::PING(_) => {
+                                let pong = Packet::PONG(PacketPong {});
+                                if Transport::send_packet(&mut sink, framing, &pong).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Packet::RETRY(retry) => {
+                                return Disconnect::Retry(Duration::from_secs(retry.delay as u64));
+                            }
+                            Packet::REDIRECT(redirect) => {
+                                let delay = Duration::from_secs(redirect.delay as u64);
+                                return Disconnect::Redirect(redirect.target, delay);
+                            }
+                            Packet::CLOSE(_) => break,
+                            // nothing todo
+                            _ => {}
+                        }
                     }
-                    Packet::ACK(ack) => {
-                        // println!("ack: {:?}", ack);
+                }
+                data = Gateway::next_write(write_rx, pending), if opened => {
+                    let data = match data {
+                        Some(v) => v,
+                        // every sender is gone, the instance is shutting down
+                        None => return Disconnect::Shutdown,
+                    };
+
+                    if let Err(e) = Transport::send_message(&mut sink, framing, &data).await {
+                        eprintln!("failed to write to {}: {}", self.peer(), e);
+                        // try again on the next connection
+                        *pending = Some(data);
+                        break;
                     }
                 }
+                _ = shutdown_rx.changed() => return Disconnect::Shutdown,
             }
         }
 
-        sink_task.abort();
-        println!("Devtools connection closed");
+        let err = HfnError::Transport(format!("{} connection closed", self.peer()));
+        self.report(err).await;
+        Disconnect::Lost
     }
 
-    fn encode_message(mut msg: PacketMessage) -> Vec<u8> {
-        let mut cap = 4 + 2 + msg.payload.len() + 2 + msg.socket_id.len();
+    async fn next_write(
+        write_rx: &mut mpsc::Receiver<GatewayWrite>,
+        pending: &mut Option<GatewayWrite>,
+    ) -> Option<GatewayWrite> {
+        match pending.take() {
+            Some(data) => Some(data),
+            None => write_rx.recv().await,
+        }
+    }
 
-        cap += 2;
-        if !msg.headers.is_empty() {
-            for header in &msg.headers {
-                cap += 5;
-                cap += header.len();
-            }
+    // Devtools serves upstreams at /us. A gateway cluster url is used as given, a bare
+    // host gets /us too.
+    pub fn runway_url(base: &str, dev: bool) -> Result<url::Url, HfnError> {
+        let mut url = ws_url(base)?;
+        if dev || url.path() == "/" {
+            url.set_path("/us");
         }
-        let mut data: Vec<u8> = Vec::with_capacity(cap);
-
-        rmp::encode::write_sint(&mut data, msg.pkg_id as i64).unwrap();
-
-        if msg.headers.is_empty() {
-            rmp::encode::write_map_len(&mut data, 0).unwrap();
-        } else {
-            rmp::encode::write_map_len(&mut data, (msg.headers.len() / 2) as u32).unwrap();
-            msg.headers.chunks_mut(2).for_each(|chunk| {
-                rmp::encode::write_str_len(&mut data, chunk[0].len() as u32).unwrap();
-                data.append(&mut chunk[0]);
-                rmp::encode::write_str_len(&mut data, chunk[1].len() as u32).unwrap();
-                data.append(&mut chunk[1]);
-            });
+        Ok(url)
+    }
+
+    // REDIRECT moves us to another node, the query identifying this upstream is kept
+    fn redirect_url(runway: &url::Url, target: &str) -> Result<url::Url, HfnError> {
+        let mut url = ws_url(target)?;
+        url.set_query(runway.query());
+        Ok(url)
+    }
+
+    // what the gateway is to the user, for errors and logs
+    fn peer(&self) -> &'static str {
+        match self.dev {
+            true => "devtools",
+            false => "gateway",
         }
+    }
 
-        rmp::encode::write_bin(&mut data, &msg.payload).unwrap();
-        rmp::encode::write_str_len(&mut data, msg.socket_id.len() as u32).unwrap();
-        data.append(&mut msg.socket_id);
+    async fn report(&self, err: HfnError) {
+        let _ = self.read_tx.send(encode_error("", &err)).await;
+    }
+}
 
-        data
+fn ws_url(s: &str) -> Result<url::Url, HfnError> {
+    let url = url::Url::parse(s).map_err(|e| HfnError::InvalidUrl(format!("{}: {}", s, e)))?;
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        return Err(HfnError::InvalidUrl(format!("{}: expected ws or wss", s)));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::HfnError, gateway::gateway::Gateway};
+
+    #[test]
+    fn redirect_keeps_the_upstream_query() {
+        let mut runway = Gateway::runway_url("ws://127.0.0.1:1", false).unwrap();
+        runway.set_query(Some("usid=u1"));
+
+        let url = Gateway::redirect_url(&runway, "wss://node2.example.com/us").unwrap();
+        assert_eq!(url.as_str(), "wss://node2.example.com/us?usid=u1");
+
+        for target in ["https://node2.example.com/us", "node2"] {
+            assert!(matches!(
+                Gateway::redirect_url(&runway, target),
+                Err(HfnError::InvalidUrl(_))
+            ));
+        }
     }
 }
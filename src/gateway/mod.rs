@@ -1,2 +1,5 @@
+mod backoff;
+#[allow(clippy::module_inception)]
 pub mod gateway;
+pub mod signature;
 mod transport;
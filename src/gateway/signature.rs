@@ -0,0 +1,61 @@
+use crate::codec::sign::hmac_sha256_hex;
+
+// takes precedence over gateway.secret in hfn.json
+pub const UPSTREAM_SECRET_ENV: &str = "HFN_UPSTREAM_SECRET";
+
+// Proves to the gateway that an upstream belongs to the app, so it can turn away
+// impostors. Every connect signs "{appid}\n{usid}\n{ts}\n{pkgs}" with HMAC-SHA256 of the
+// shared secret, hex encoded in the `sig` query. pkgs is the `pkgs` query value.
+#[derive(Debug, Clone)]
+pub struct UpstreamSigner {
+    secret: Vec<u8>,
+    appid: String,
+    usid: String,
+    pkgs: String,
+}
+
+impl UpstreamSigner {
+    pub fn new(secret: &str, appid: &str, usid: &str, pkgs: &str) -> Self {
+        UpstreamSigner {
+            secret: secret.as_bytes().to_vec(),
+            appid: appid.to_string(),
+            usid: usid.to_string(),
+            pkgs: pkgs.to_string(),
+        }
+    }
+
+    // ts is the `ts` query, milliseconds
+    pub fn sign(&self, ts: i64) -> String {
+        let data = format!("{}\n{}\n{}\n{}", self.appid, self.usid, ts, self.pkgs);
+        hmac_sha256_hex(&self.secret, data.as_bytes())
+    }
+}
+
+// served packages as "id:name,..." ordered by id
+pub fn packages_param(packages: &[(u32, &str)]) -> String {
+    let mut packages = packages.to_vec();
+    packages.sort();
+    packages
+        .iter()
+        .map(|(id, name)| format!("{}:{}", id, name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{codec::sign::hmac_sha256_hex, gateway::signature::*};
+
+    #[test]
+    fn sign_upstream() {
+        let pkgs = packages_param(&[(2, "b"), (1, "a")]);
+        assert_eq!(pkgs, "1:a,2:b");
+
+        let signer = UpstreamSigner::new("secret", "app", "us1", &pkgs);
+        assert_eq!(
+            signer.sign(1000),
+            hmac_sha256_hex(b"secret", b"app\nus1\n1000\n1:a,2:b")
+        );
+        assert_ne!(signer.sign(1000), signer.sign(1001));
+    }
+}
@@ -0,0 +1,866 @@
+use std::{
+    env,
+    fs::read_to_string,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::server::conn::AddrIncoming;
+use once_cell::sync::OnceCell;
+use rusty_ulid::generate_ulid_string;
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::{
+        mpsc::{
+            self,
+            error::{TryRecvError, TrySendError},
+        },
+        watch, Mutex as AsyncMutex,
+    },
+    time::sleep,
+};
+
+use crate::{
+    codec::{
+        self,
+        event::{encode_rpc_result, DisconnectReason},
+        payload::{HfnValue, MessageTarget, PayloadCodec},
+        InitArgs, JsonConfig,
+    },
+    error::HfnError,
+    gateway::{
+        gateway::{Gateway, GatewayWrite},
+        signature::{packages_param, UpstreamSigner, UPSTREAM_SECRET_ENV},
+    },
+    protocol::{Packet, PacketClose, PacketRedirect, PacketReset, PacketRetry},
+    server::{
+        auth::{AuthRequest, Authenticator, Claims, HostAuthenticator},
+        outbox::OutboxMessage,
+        rpc::{self, report_failed_calls, RpcCalls, RPC_SOCKET_EXPIRED},
+        server::{Server, DEFAULT_SESSION_GRACE_PERIOD},
+        session::{expire_session, report_undelivered, Sessions},
+        socket::{SocketChans, SocketConfig},
+        tls::{TlsPaths, TlsReloader},
+    },
+};
+
+const SHUTDOWN_REASON: &str = "server shutdown";
+
+const DEFAULT_READ_CHAN_CAPACITY: usize = 1024;
+const DEFAULT_SOCKET_CHAN_CAPACITY: usize = 256;
+const DEFAULT_GATEWAY_CHAN_CAPACITY: usize = 1024;
+const DEFAULT_PING_INTERVAL: u8 = 25;
+const DEFAULT_PING_TIMEOUT: u8 = 20;
+const DEFAULT_CONNECTION_RETRY_DELAY: u8 = 5;
+
+pub enum TryReadRes {
+    DATA(Vec<u8>),
+    EMPTY,
+    CLOSED,
+}
+
+// One isolated core: owns its tokio runtime, channels, socket registry and config.
+// Several instances can live in the same process.
+pub struct HfnInstance {
+    pub app_id: String,
+    pub upstream_id: String,
+    init_args: InitArgs,
+    json_config: JsonConfig,
+    payload_codec: Arc<PayloadCodec>,
+    socket_config: SocketConfig,
+    connection_retry_delay: u8,
+    tls_paths: Option<TlsPaths>,
+    // taken on shutdown
+    runtime: Mutex<Option<Runtime>>,
+    socket_chans: Arc<SocketChans>,
+    sessions: Arc<Sessions>,
+    rpc_calls: Arc<RpcCalls>,
+    // dropped on shutdown so read() returns None once drained
+    read_tx: Mutex<Option<mpsc::Sender<Vec<u8>>>>,
+    read_rx: AsyncMutex<mpsc::Receiver<Vec<u8>>>,
+    gateway_write_tx: OnceCell<mpsc::Sender<GatewayWrite>>,
+    // set by the host before run, takes precedence over client_auth
+    host_authenticator: OnceCell<HostAuthenticator>,
+    gateway_message_id: AtomicI32,
+    shutdown_tx: watch::Sender<bool>,
+    local_addr: OnceCell<SocketAddr>,
+}
+
+impl HfnInstance {
+    // returns the instance and the msgpack encoded InitResult for the sdk
+    pub fn init(args: Vec<u8>) -> Result<(Self, Vec<u8>), HfnError> {
+        let args = InitArgs::from_buf(args)?;
+
+        for (name, capacity) in [
+            ("read_chan_capacity", args.read_chan_capacity),
+            ("socket_chan_capacity", args.socket_chan_capacity),
+            ("gateway_chan_capacity", args.gateway_chan_capacity),
+            ("max_connections", args.max_connections),
+        ] {
+            if capacity == Some(0) {
+                return Err(HfnError::InvalidInitArgs(format!(
+                    "{} must be greater than 0",
+                    name
+                )));
+            }
+        }
+
+        let connection_retry_delay = match args.connection_retry_delay {
+            Some(v) => packet_delay(v).map_err(|_| {
+                HfnError::InvalidInitArgs(
+                    "connection_retry_delay must be between 0 and 127".to_string(),
+                )
+            })?,
+            None => DEFAULT_CONNECTION_RETRY_DELAY,
+        };
+
+        let compress_size = match args.compress_size {
+            Some(v) if v > 127 => {
+                return Err(HfnError::InvalidInitArgs(
+                    "compress_size must be between 0 and 127".to_string(),
+                ))
+            }
+            v => v.map(|v| v as u8),
+        };
+
+        let mut config_path;
+        if let Ok(path) = env::var("HFN_CONFIG_PATH") {
+            config_path = Path::new(&path).to_owned();
+        } else if let Some(hfn_config_path) = &args.hfn_config_path {
+            config_path = Path::new(hfn_config_path).to_owned();
+        } else {
+            config_path =
+                env::current_dir().map_err(|e| HfnError::ConfigNotFound(e.to_string()))?;
+            config_path.push("hfn.json");
+        }
+
+        if !config_path.exists() {
+            return Err(HfnError::ConfigNotFound(config_path.display().to_string()));
+        }
+
+        let json_config = read_to_string(&config_path)
+            .map_err(|e| HfnError::InvalidConfig(format!("{}: {}", config_path.display(), e)))?;
+        let json_config = JsonConfig::from_str(&json_config)?;
+        let tls_paths = tls_paths(&args, &json_config, &config_path)?;
+
+        let heartbeat = json_config.heartbeat.as_ref();
+        let socket_config = SocketConfig {
+            chan_capacity: args
+                .socket_chan_capacity
+                .unwrap_or(DEFAULT_SOCKET_CHAN_CAPACITY),
+            slow_consumer_policy: args.slow_consumer_policy.unwrap_or_default(),
+            ping_interval: heartbeat_secs(
+                ("ping_interval", args.ping_interval),
+                (
+                    "heartbeat.pingInterval",
+                    heartbeat.and_then(|h| h.ping_interval),
+                ),
+                DEFAULT_PING_INTERVAL,
+            )?,
+            ping_timeout: heartbeat_secs(
+                ("ping_timeout", args.ping_timeout),
+                (
+                    "heartbeat.pingTimeout",
+                    heartbeat.and_then(|h| h.ping_timeout),
+                ),
+                DEFAULT_PING_TIMEOUT,
+            )?,
+            compress_size,
+        };
+
+        let mut runtime_builder = Builder::new_multi_thread();
+
+        if let Some(tokio_work_threads) = &args.tokio_work_threads {
+            runtime_builder.worker_threads(*tokio_work_threads);
+        }
+
+        runtime_builder.thread_name("hfn-core-runtime-worker");
+        runtime_builder.enable_all();
+        let runtime = runtime_builder
+            .build()
+            .map_err(|e| HfnError::Runtime(e.to_string()))?;
+
+        let (hfn_packages, hfn_modules, hfn_models, hfn_hfns, hfn_rpcs, hfn_schemas, hfn_fields) =
+            json_config.to_hfn_struct()?;
+
+        let upstream_id = match &args.upstream_id {
+            Some(id) => id.to_owned(),
+            None => generate_ulid_string(),
+        };
+
+        let (read_tx, read_rx) = mpsc::channel::<Vec<u8>>(
+            args.read_chan_capacity
+                .unwrap_or(DEFAULT_READ_CHAN_CAPACITY),
+        );
+
+        let result = codec::InitResult {
+            upstream_id: upstream_id.clone(),
+            packages: hfn_packages,
+            modules: hfn_modules,
+            models: hfn_models,
+            hfns: hfn_hfns,
+            rpcs: hfn_rpcs,
+            schemas: hfn_schemas,
+            fields: hfn_fields,
+            warnings: json_config
+                .validate()
+                .warnings
+                .iter()
+                .map(|w| w.to_string())
+                .collect(),
+        };
+
+        let instance = HfnInstance {
+            app_id: json_config.appid.clone(),
+            upstream_id,
+            init_args: args,
+            payload_codec: Arc::new(PayloadCodec::new(&json_config)?),
+            json_config,
+            socket_config,
+            connection_retry_delay,
+            tls_paths,
+            runtime: Mutex::new(Some(runtime)),
+            socket_chans: Arc::new(SocketChans::new()),
+            sessions: Arc::new(Sessions::new()),
+            rpc_calls: Arc::new(RpcCalls::default()),
+            read_tx: Mutex::new(Some(read_tx)),
+            read_rx: AsyncMutex::new(read_rx),
+            gateway_write_tx: OnceCell::new(),
+            host_authenticator: OnceCell::new(),
+            gateway_message_id: AtomicI32::new(1),
+            shutdown_tx: watch::channel(false).0,
+            local_addr: OnceCell::new(),
+        };
+
+        Ok((instance, result.to_buf()))
+    }
+
+    pub fn init_args(&self) -> &InitArgs {
+        &self.init_args
+    }
+
+    pub fn json_config(&self) -> &JsonConfig {
+        &self.json_config
+    }
+
+    // address the server is listening on, useful when binding port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr.get().copied()
+    }
+
+    // Decide on /hfn clients from the host. Has to be set before run, claims it returns
+    // are attached to the socket.
+    pub fn set_client_authenticator<F>(&self, authenticate: F) -> Result<(), HfnError>
+    where
+        F: Fn(&AuthRequest) -> Result<Claims, String> + Send + Sync + 'static,
+    {
+        self.host_authenticator
+            .set(Arc::new(authenticate))
+            .map_err(|_| HfnError::AlreadyInitialized)
+    }
+
+    pub fn run(&self) -> Result<(), HfnError> {
+        let init_args = &self.init_args;
+        let json_config = &self.json_config;
+        let runtime = self.runtime.lock().unwrap();
+        let runtime = runtime.as_ref().ok_or(HfnError::Shutdown)?;
+        let read_tx = self
+            .read_tx
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(HfnError::Shutdown)?;
+
+        // Clients reach the core either on its own listener or through a gateway it
+        // dials out to. An explicit gateway_url wins over addr, which wins over the
+        // gateway in hfn.json, so a deployment can still choose to listen.
+        if init_args.dev {
+            let url = Gateway::runway_url(&json_config.dev.devtools, true)?;
+            return self.run_gateway(runtime, read_tx, url, true);
+        }
+
+        if let Some(gateway_url) = &init_args.gateway_url {
+            let url = Gateway::runway_url(gateway_url, false)?;
+            return self.run_gateway(runtime, read_tx, url, false);
+        }
+
+        match (&init_args.addr, &json_config.gateway) {
+            (Some(addr), _) => self.run_server(runtime, read_tx, addr),
+            (None, Some(gateway)) => {
+                let url = Gateway::runway_url(&gateway.url, false)?;
+                self.run_gateway(runtime, read_tx, url, false)
+            }
+            (None, None) => Err(HfnError::InvalidAddr("addr is required".to_string())),
+        }
+    }
+
+    fn run_server(
+        &self,
+        runtime: &Runtime,
+        read_tx: mpsc::Sender<Vec<u8>>,
+        addr: &str,
+    ) -> Result<(), HfnError> {
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| HfnError::InvalidAddr(addr.to_string()))?;
+
+        // bind inside the runtime so address errors surface here instead of in the server task
+        let incoming = {
+            let _guard = runtime.enter();
+            AddrIncoming::bind(&addr).map_err(|e| HfnError::InvalidAddr(e.to_string()))?
+        };
+
+        // a bad certificate fails run instead of every handshake
+        let tls = match &self.tls_paths {
+            Some(paths) => Some(Arc::new(TlsReloader::new(paths.clone())?)),
+            None => None,
+        };
+
+        let _ = self.local_addr.set(incoming.local_addr());
+
+        let authenticator = match (self.host_authenticator.get(), &self.init_args.client_auth) {
+            (Some(authenticate), _) => Authenticator::Host(authenticate.clone()),
+            (None, Some(client_auth)) => Authenticator::from_args(&self.app_id, client_auth),
+            (None, None) => Authenticator::None,
+        };
+
+        let server = Arc::new(Server {
+            addr: incoming.local_addr(),
+            app_id: self.app_id.clone(),
+            read_tx,
+            socket_chans: self.socket_chans.clone(),
+            sessions: self.sessions.clone(),
+            session_grace_period: self
+                .init_args
+                .session_grace_period
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SESSION_GRACE_PERIOD),
+            socket_config: self.socket_config,
+            max_connections: self.init_args.max_connections,
+            connection_retry_delay: self.connection_retry_delay,
+            connections: AtomicUsize::new(0),
+            authenticator,
+            payload_validator: self
+                .init_args
+                .strict_payloads
+                .then(|| self.payload_codec.clone()),
+            rpc_calls: self.rpc_calls.clone(),
+            tls,
+            shutdown_rx: self.shutdown_tx.subscribe(),
+        });
+
+        runtime.spawn(async move { server.listen(incoming).await });
+
+        Ok(())
+    }
+
+    // url is the gateway endpoint, the query identifying this upstream is added here
+    fn run_gateway(
+        &self,
+        runtime: &Runtime,
+        read_tx: mpsc::Sender<Vec<u8>>,
+        mut url: url::Url,
+        dev: bool,
+    ) -> Result<(), HfnError> {
+        let init_args = &self.init_args;
+
+        // the packages this upstream serves, all of hfn.json unless pkg_names picks some
+        let packages: Vec<(u32, &str)> = self
+            .json_config
+            .packages
+            .iter()
+            .filter(|p| init_args.pkg_names.is_empty() || init_args.pkg_names.contains(&p.name))
+            .map(|p| (p.id, p.name.as_str()))
+            .collect();
+        let pkgs = packages_param(&packages);
+
+        url.query_pairs_mut()
+            .append_pair("usid", &self.upstream_id)
+            .append_pair("appid", &self.app_id)
+            .append_pair("ver", env!("CARGO_PKG_VERSION"))
+            .append_pair("sdk", &init_args.sdk)
+            .append_pair("pkgs", &pkgs);
+
+        let secret = env::var(UPSTREAM_SECRET_ENV)
+            .ok()
+            .or_else(|| self.json_config.gateway.as_ref()?.secret.clone());
+        let signer = secret
+            .map(|secret| UpstreamSigner::new(&secret, &self.app_id, &self.upstream_id, &pkgs));
+
+        let (write_tx, write_rx) = mpsc::channel::<GatewayWrite>(
+            init_args
+                .gateway_chan_capacity
+                .unwrap_or(DEFAULT_GATEWAY_CHAN_CAPACITY),
+        );
+
+        self.gateway_write_tx
+            .set(write_tx)
+            .map_err(|_| HfnError::AlreadyInitialized)?;
+
+        let gateway = Gateway {
+            dev,
+            runway: url,
+            signer,
+            read_tx,
+            rpc_calls: self.rpc_calls.clone(),
+            shutdown_rx: self.shutdown_tx.subscribe(),
+        };
+
+        runtime.spawn(async move {
+            gateway.run(write_rx).await;
+        });
+
+        Ok(())
+    }
+
+    pub fn read(&self) -> Option<Vec<u8>> {
+        self.read_rx.blocking_lock().blocking_recv()
+    }
+
+    pub fn try_read(&self) -> TryReadRes {
+        let mut read_rx = match self.read_rx.try_lock() {
+            Ok(v) => v,
+            // another reader is waiting on the channel
+            Err(_) => return TryReadRes::EMPTY,
+        };
+
+        match read_rx.try_recv() {
+            Ok(data) => TryReadRes::DATA(data),
+            Err(e) => match e {
+                TryRecvError::Empty => TryReadRes::EMPTY,
+                TryRecvError::Disconnected => TryReadRes::CLOSED,
+            },
+        }
+    }
+
+    pub async fn read_async(&self) -> Option<Vec<u8>> {
+        self.read_rx.lock().await.recv().await
+    }
+
+    // Returns the message id. Delivery to a directly connected socket is reported
+    // through a delivery event once the client acks it or gives up reconnecting.
+    pub fn send_message(&self, socket_id: String, payload: Vec<u8>) -> Result<i32, HfnError> {
+        if *self.shutdown_tx.borrow() {
+            return Err(HfnError::Shutdown);
+        }
+
+        if let Some(gateway_write_tx) = self.gateway_write_tx.get() {
+            let id = self
+                .gateway_message_id
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+                    Some(if id == i32::MAX { 1 } else { id + 1 })
+                })
+                .unwrap();
+            return match gateway_write_tx.try_send((socket_id, id, payload)) {
+                Ok(_) => Ok(id),
+                Err(TrySendError::Full(_)) => Err(HfnError::WouldBlock),
+                Err(TrySendError::Closed(_)) => Err(HfnError::ChannelClosed),
+            };
+        }
+
+        let (id, dropped) = match self.socket_chans.get(&socket_id) {
+            Some(socket_chan) => socket_chan.send_message(&socket_id, payload)?,
+            None => return Err(HfnError::SocketNotFound(socket_id)),
+        };
+
+        // made room under the drop oldest policy
+        if let Some(msg) = dropped {
+            self.report_undelivered(socket_id, vec![msg]);
+        }
+        Ok(id)
+    }
+
+    // Call an rpc of the client, returns the call id. The call ends with an rpc result
+    // event carrying the client's response, or the RpcTimeout error when the timeout
+    // passes first. The request itself is a message like any other.
+    pub fn call_rpc(
+        &self,
+        socket_id: String,
+        package_id: u32,
+        rpc_id: u32,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<u32, HfnError> {
+        self.check_rpc(package_id, rpc_id)?;
+        if timeout.is_zero() {
+            return Err(HfnError::InvalidArgument(
+                "rpc timeout must be greater than 0".to_string(),
+            ));
+        }
+        let read_tx = self.read_tx.lock().unwrap().clone();
+        let runtime = self
+            .runtime
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|r| r.handle().clone());
+        let (read_tx, runtime) = read_tx.zip(runtime).ok_or(HfnError::Shutdown)?;
+
+        let call_id = self.rpc_calls.start(&socket_id);
+        let data = rpc::encode_request(package_id, rpc_id, call_id, &payload);
+        if let Err(e) = self.send_message(socket_id.clone(), data) {
+            self.rpc_calls.cancel(call_id);
+            return Err(e);
+        }
+
+        let rpc_calls = self.rpc_calls.clone();
+        let timer = runtime.spawn(async move {
+            sleep(timeout).await;
+            if rpc_calls.expire(call_id) {
+                let err = HfnError::RpcTimeout(call_id);
+                let _ = read_tx
+                    .send(encode_rpc_result(&socket_id, call_id, Err(&err)))
+                    .await;
+            }
+        });
+        self.rpc_calls.set_timer(call_id, timer);
+        Ok(call_id)
+    }
+
+    // ends the call without a result event, false if it already ended
+    pub fn cancel_rpc(&self, call_id: u32) -> bool {
+        self.rpc_calls.cancel(call_id)
+    }
+
+    // Answer the client's call, its id is in the `req` header of the request. Err is the
+    // reason the call failed. Returns the message id like send_message.
+    pub fn respond_rpc(
+        &self,
+        socket_id: String,
+        package_id: u32,
+        rpc_id: u32,
+        call_id: u32,
+        result: Result<Vec<u8>, String>,
+    ) -> Result<i32, HfnError> {
+        self.check_rpc(package_id, rpc_id)?;
+        let result = result.as_deref().map_err(|e| e.as_str());
+        let data = rpc::encode_response(package_id, rpc_id, call_id, result);
+        self.send_message(socket_id, data)
+    }
+
+    fn check_rpc(&self, package_id: u32, rpc_id: u32) -> Result<(), HfnError> {
+        let target = MessageTarget::Rpc(rpc_id);
+        if self
+            .payload_codec
+            .target_schema(package_id, target)
+            .is_none()
+        {
+            return Err(HfnError::InvalidArgument(format!(
+                "rpc {} not found in package {}",
+                rpc_id, package_id
+            )));
+        }
+        Ok(())
+    }
+
+    // msgpack payload of a struct of the package's schema, see PayloadCodec
+    pub fn encode_payload(
+        &self,
+        package_id: u32,
+        schema_id: u32,
+        value: &HfnValue,
+    ) -> Result<Vec<u8>, HfnError> {
+        self.check_schema(package_id, schema_id)?;
+        self.payload_codec
+            .encode(package_id, schema_id, value)
+            .map_err(|e| HfnError::InvalidPayload(e.to_string()))
+    }
+
+    pub fn decode_payload(
+        &self,
+        package_id: u32,
+        schema_id: u32,
+        payload: Vec<u8>,
+    ) -> Result<HfnValue, HfnError> {
+        self.check_schema(package_id, schema_id)?;
+        self.payload_codec
+            .decode(package_id, schema_id, &payload)
+            .map_err(|e| HfnError::InvalidPayload(e.to_string()))
+    }
+
+    fn check_schema(&self, package_id: u32, schema_id: u32) -> Result<(), HfnError> {
+        if !self.payload_codec.has_schema(package_id, schema_id) {
+            return Err(HfnError::InvalidArgument(format!(
+                "schema {} not found in package {}",
+                schema_id, package_id
+            )));
+        }
+        Ok(())
+    }
+
+    // delivery failures found outside the runtime are reported from a task on it
+    fn report_undelivered(&self, socket_id: String, msgs: Vec<OutboxMessage>) {
+        let read_tx = match self.read_tx.lock().unwrap().clone() {
+            Some(v) => v,
+            None => return,
+        };
+
+        if let Some(runtime) = self.runtime.lock().unwrap().as_ref() {
+            runtime.spawn(async move { report_undelivered(&read_tx, &socket_id, msgs).await });
+        }
+    }
+
+    fn report_failed_calls(&self, events: Vec<Vec<u8>>) {
+        let read_tx = match self.read_tx.lock().unwrap().clone() {
+            Some(v) => v,
+            None => return,
+        };
+
+        if let Some(runtime) = self.runtime.lock().unwrap().as_ref() {
+            runtime.spawn(async move { report_failed_calls(&read_tx, events).await });
+        }
+    }
+
+    // round trip time of the socket's last answered PING, None until one is answered
+    pub fn socket_rtt(&self, socket_id: &str) -> Result<Option<Duration>, HfnError> {
+        match self.socket_chans.get(socket_id) {
+            Some(socket_chan) => Ok(*socket_chan.stats.rtt.lock().unwrap()),
+            None => Err(HfnError::SocketNotFound(socket_id.to_string())),
+        }
+    }
+
+    // what the authenticator made of the socket's latest connection
+    pub fn socket_claims(&self, socket_id: &str) -> Result<Claims, HfnError> {
+        match self.socket_chans.get(socket_id) {
+            Some(socket_chan) => Ok(socket_chan.claims.as_ref().clone()),
+            None => Err(HfnError::SocketNotFound(socket_id.to_string())),
+        }
+    }
+
+    // malformed frames received from the socket across all its connections
+    pub fn socket_protocol_violations(&self, socket_id: &str) -> Result<u64, HfnError> {
+        match self.socket_chans.get(socket_id) {
+            Some(socket_chan) => Ok(socket_chan
+                .stats
+                .protocol_violations
+                .load(Ordering::Relaxed)),
+            None => Err(HfnError::SocketNotFound(socket_id.to_string())),
+        }
+    }
+
+    // close one socket, the client receives a CLOSE packet carrying the reason
+    pub fn close_socket(&self, socket_id: &str, reason: &str) -> Result<(), HfnError> {
+        let close = Packet::CLOSE(PacketClose {
+            reason: reason.to_string(),
+        });
+        self.close_socket_with(socket_id, DisconnectReason::ServerClose, close)
+    }
+
+    // Tell the client to reconnect after `delay` seconds, its session stays resumable.
+    pub fn retry_socket(&self, socket_id: &str, delay: u8) -> Result<(), HfnError> {
+        let retry = Packet::RETRY(PacketRetry {
+            delay: packet_delay(delay as u64)?,
+        });
+        self.close_socket_with(socket_id, DisconnectReason::Retry, retry)
+    }
+
+    // Drop the client's session, it starts a new one after `delay` seconds.
+    pub fn reset_socket(&self, socket_id: &str, delay: u8) -> Result<(), HfnError> {
+        let reset = Packet::RESET(PacketReset {
+            delay: packet_delay(delay as u64)?,
+        });
+        self.close_socket_with(socket_id, DisconnectReason::Reset, reset)
+    }
+
+    // Send the client to another node, `target` is the url it connects to after `delay`
+    // seconds.
+    pub fn redirect_socket(
+        &self,
+        socket_id: &str,
+        delay: u8,
+        target: &str,
+    ) -> Result<(), HfnError> {
+        url::Url::parse(target).map_err(|e| HfnError::InvalidUrl(format!("{}: {}", target, e)))?;
+        let redirect = Packet::REDIRECT(PacketRedirect {
+            delay: packet_delay(delay as u64)?,
+            target: target.to_string(),
+        });
+        self.close_socket_with(socket_id, DisconnectReason::Redirect, redirect)
+    }
+
+    fn close_socket_with(
+        &self,
+        socket_id: &str,
+        cause: DisconnectReason,
+        packet: Packet,
+    ) -> Result<(), HfnError> {
+        if *self.shutdown_tx.borrow() {
+            return Err(HfnError::Shutdown);
+        }
+
+        let online = match self.socket_chans.get(socket_id) {
+            Some(socket_chan) => socket_chan.close_with(cause, packet),
+            None => return Err(HfnError::SocketNotFound(socket_id.to_string())),
+        };
+
+        // the client is offline, drop its session so it can't resume
+        if !online && !cause.keeps_session() {
+            self.expire_offline_socket(socket_id);
+        }
+        Ok(())
+    }
+
+    fn expire_offline_socket(&self, socket_id: &str) {
+        if let Some(msgs) = expire_session(&self.socket_chans, &self.sessions, socket_id, None) {
+            self.report_undelivered(socket_id.to_string(), msgs);
+            self.report_failed_calls(self.rpc_calls.fail_socket(socket_id, RPC_SOCKET_EXPIRED));
+        }
+    }
+
+    // close every socket opened by the client, returns how many were closed
+    pub fn close_sockets_by_client(
+        &self,
+        client_id: &str,
+        reason: &str,
+    ) -> Result<usize, HfnError> {
+        if *self.shutdown_tx.borrow() {
+            return Err(HfnError::Shutdown);
+        }
+
+        let socket_ids: Vec<String> = self
+            .socket_chans
+            .iter()
+            .filter(|socket_chan| socket_chan.client_id == client_id)
+            .map(|socket_chan| socket_chan.key().clone())
+            .collect();
+
+        let mut count = 0;
+        for socket_id in socket_ids {
+            if self.close_socket(&socket_id, reason).is_ok() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Stop accepting connections, close every socket with a CLOSE packet, wait for pending
+    // writes to drain and tear down the runtime. Must not be called from inside the runtime.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), HfnError> {
+        let runtime = self
+            .runtime
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(HfnError::Shutdown)?;
+        let deadline = Instant::now() + timeout;
+
+        let _ = self.shutdown_tx.send(true);
+
+        let socket_chans = self.socket_chans.clone();
+        let rpc_calls = self.rpc_calls.clone();
+        let read_tx = self.read_tx.lock().unwrap().take();
+        runtime.block_on(async move {
+            // close is sent after pending writes, so they are flushed first
+            for socket_chan in socket_chans.iter() {
+                socket_chan.close(DisconnectReason::ServerShutdown, SHUTDOWN_REASON);
+            }
+
+            let _ = tokio::time::timeout(timeout, async {
+                while socket_chans
+                    .iter()
+                    .any(|socket_chan| socket_chan.tx.is_some())
+                {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+
+            let read_tx = match read_tx {
+                Some(v) => v,
+                None => return,
+            };
+
+            let undelivered: Vec<(String, Vec<OutboxMessage>)> = socket_chans
+                .iter()
+                .map(|socket_chan| {
+                    let msgs = socket_chan.outbox.lock().unwrap().drain();
+                    (socket_chan.key().clone(), msgs)
+                })
+                .collect();
+            let failed = rpc_calls.fail_all(SHUTDOWN_REASON);
+
+            // a host that stopped reading must not hold up the shutdown
+            let _ = tokio::time::timeout_at(deadline.into(), async {
+                for (socket_id, msgs) in undelivered {
+                    report_undelivered(&read_tx, &socket_id, msgs).await;
+                }
+                report_failed_calls(&read_tx, failed).await;
+            })
+            .await;
+        });
+
+        runtime.shutdown_timeout(deadline.saturating_duration_since(Instant::now()));
+
+        Ok(())
+    }
+}
+
+// TLS files from init args, else hfn.json where relative paths start at its directory
+fn tls_paths(
+    args: &InitArgs,
+    json_config: &JsonConfig,
+    config_path: &Path,
+) -> Result<Option<TlsPaths>, HfnError> {
+    match (&args.tls_cert_path, &args.tls_key_path) {
+        (Some(cert), Some(key)) => {
+            return Ok(Some(TlsPaths {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: args.tls_client_ca_path.as_ref().map(PathBuf::from),
+            }))
+        }
+        (None, None) if args.tls_client_ca_path.is_none() => {}
+        _ => {
+            return Err(HfnError::InvalidInitArgs(
+                "tls_cert_path and tls_key_path are required together".to_string(),
+            ))
+        }
+    }
+
+    let tls = match &json_config.tls {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let dir = config_path.parent().unwrap_or(Path::new(""));
+    Ok(Some(TlsPaths {
+        cert: dir.join(&tls.cert),
+        key: dir.join(&tls.key),
+        client_ca: tls.client_ca.as_ref().map(|v| dir.join(v)),
+    }))
+}
+
+// Heartbeat seconds from init args, else hfn.json, else the default. The OPEN packet
+// carries them as positive fixints.
+fn heartbeat_secs(
+    arg: (&str, Option<u64>),
+    config: (&str, Option<u64>),
+    default: u8,
+) -> Result<u8, HfnError> {
+    let valid = |v: u64| (1..=127).contains(&v).then_some(v as u8);
+
+    match (arg, config) {
+        ((name, Some(v)), _) => valid(v).ok_or_else(|| {
+            HfnError::InvalidInitArgs(format!("{} must be between 1 and 127", name))
+        }),
+        (_, (name, Some(v))) => valid(v)
+            .ok_or_else(|| HfnError::InvalidConfig(format!("{} must be between 1 and 127", name))),
+        _ => Ok(default),
+    }
+}
+
+// RETRY, RESET and REDIRECT carry their delay as a positive fixint
+fn packet_delay(delay: u64) -> Result<u8, HfnError> {
+    if delay > 127 {
+        return Err(HfnError::InvalidArgument(
+            "delay must be between 0 and 127 seconds".to_string(),
+        ));
+    }
+    Ok(delay as u8)
+}
@@ -1,199 +1,149 @@
-use dashmap::DashMap;
-use gateway::gateway::Gateway;
-use rusty_ulid::generate_ulid_string;
-use server::{
-    server::Server,
-    socket::{Action, ActionSendMessage},
-};
-use std::{env, fs::read_to_string, path::Path};
-
-use once_cell::sync::OnceCell;
-use tokio::{
-    runtime::{Builder, Runtime},
-    sync::mpsc::{self, error::TryRecvError},
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
 mod codec;
+mod error;
 mod gateway;
+mod instance;
+mod protocol;
 mod server;
 
-pub static mut APP_ID: String = String::new();
-pub static mut UPSTREAM_ID: String = String::new();
-
-pub static RUNTIME: OnceCell<Runtime> = OnceCell::new();
-
-pub static SOCKET_CHANS: OnceCell<DashMap<String, mpsc::UnboundedSender<Action>>> = OnceCell::new();
-
-pub static mut READ_CHAN_RX: OnceCell<mpsc::UnboundedReceiver<Vec<u8>>> = OnceCell::new();
-pub static READ_CHAN_TX: OnceCell<mpsc::UnboundedSender<Vec<u8>>> = OnceCell::new();
-
-pub static mut GATEWAY_WRITE_CHAN_RX: OnceCell<mpsc::UnboundedReceiver<(String, Vec<u8>)>> =
-    OnceCell::new();
-pub static GATEWAY_WRITE_CHAN_TX: OnceCell<mpsc::UnboundedSender<(String, Vec<u8>)>> =
-    OnceCell::new();
-
-pub static INIT_ARGS: OnceCell<codec::InitArgs> = OnceCell::new();
-pub static JSON_CONFIG: OnceCell<codec::JsonConfig> = OnceCell::new();
-
-pub fn init(args: Vec<u8>) -> Vec<u8> {
-    if RUNTIME.get().is_some() {
-        panic!("Instance already initialized");
-    }
-
-    let args = codec::InitArgs::from_buf(args);
-
-    let mut config_path;
-    if env::var("HFN_CONFIG_PATH").is_ok() {
-        let path = env::var("HFN_CONFIG_PATH").unwrap();
-        config_path = Path::new(&path).to_owned();
-    } else if let Some(hfn_config_path) = &args.hfn_config_path {
-        config_path = Path::new(hfn_config_path).to_owned();
-    } else {
-        config_path = env::current_dir().unwrap();
-        config_path.push("hfn.json");
-    }
-
-    if !config_path.exists() {
-        panic!("hfn.json file not found: {}", config_path.display());
-    }
-
-    let json_config =
-        codec::JsonConfig::from_str(read_to_string(config_path).expect("failed to read hfn.json"));
-
-    let mut runtime_builder = Builder::new_multi_thread();
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[doc(hidden)]
+pub mod testing;
 
-    if let Some(tokio_work_threads) = &args.tokio_work_threads {
-        runtime_builder.worker_threads(*tokio_work_threads);
-    }
-
-    runtime_builder.thread_name("hfn-core-runtime-worker");
-    runtime_builder.enable_all();
-    let runtime = runtime_builder.build().expect("unable build tokio runtime");
-    RUNTIME.set(runtime).unwrap();
-
-    let (hfn_packages, hfn_modules, hfn_models, hfn_hfns, hfn_rpcs, hfn_schemas, hfn_fields) =
-        json_config.to_hfn_struct();
-
-    let upstream_id;
-    if let Some(id) = &args.upstream_id {
-        upstream_id = id.to_owned();
-    } else {
-        upstream_id = generate_ulid_string();
-    }
+pub use codec::{payload::HfnValue, InitArgs, JsonConfig};
+pub use error::HfnError;
+pub use instance::{HfnInstance, TryReadRes};
+pub use server::auth::{AuthRequest, Claims};
 
-    let (read_tx, read_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+// default instance behind the free functions below
+static INSTANCE: RwLock<Option<Arc<HfnInstance>>> = RwLock::new(None);
 
-    unsafe {
-        APP_ID = json_config.appid.clone();
-        UPSTREAM_ID = upstream_id.clone();
+fn instance() -> Result<Arc<HfnInstance>, HfnError> {
+    let instance = INSTANCE.read().unwrap_or_else(|e| e.into_inner());
+    instance.clone().ok_or(HfnError::NotInitialized)
+}
 
-        READ_CHAN_RX.set(read_rx).unwrap();
+pub fn init(args: Vec<u8>) -> Result<Vec<u8>, HfnError> {
+    let mut instance = INSTANCE.write().unwrap_or_else(|e| e.into_inner());
+    if instance.is_some() {
+        return Err(HfnError::AlreadyInitialized);
     }
 
-    READ_CHAN_TX.set(read_tx).unwrap();
-
-    SOCKET_CHANS.set(DashMap::new()).unwrap();
-    INIT_ARGS.set(args).unwrap();
-    JSON_CONFIG.set(json_config).unwrap();
+    let (new_instance, result) = HfnInstance::init(args)?;
+    *instance = Some(Arc::new(new_instance));
+    Ok(result)
+}
 
-    let result = codec::InitResult {
-        upstream_id,
-        packages: hfn_packages,
-        modules: hfn_modules,
-        models: hfn_models,
-        hfns: hfn_hfns,
-        rpcs: hfn_rpcs,
-        schemas: hfn_schemas,
-        fields: hfn_fields,
-    };
-    result.to_buf()
+pub fn set_client_authenticator<F>(authenticate: F) -> Result<(), HfnError>
+where
+    F: Fn(&AuthRequest) -> Result<Claims, String> + Send + Sync + 'static,
+{
+    instance()?.set_client_authenticator(authenticate)
 }
 
-pub fn run() {
-    let init_args = INIT_ARGS.get().unwrap();
-    let json_config = JSON_CONFIG.get().unwrap();
-    let upstream_id = unsafe { UPSTREAM_ID.clone() };
+pub fn run() -> Result<(), HfnError> {
+    instance()?.run()
+}
 
-    let runtime = RUNTIME.get().unwrap();
+pub fn read() -> Result<Option<Vec<u8>>, HfnError> {
+    Ok(instance()?.read())
+}
 
-    if !init_args.dev {
-        let addr = init_args.addr.as_ref().unwrap().clone();
-        runtime.spawn(async move {
-            let server = Server { addr };
-            server.listen().await
-        });
-    } else {
-        let mut url = url::Url::parse(&json_config.dev.devtools).unwrap();
-        url.set_path("/us");
+pub fn try_read() -> Result<TryReadRes, HfnError> {
+    Ok(instance()?.try_read())
+}
 
-        url.query_pairs_mut().append_pair("usid", &upstream_id);
+pub async fn read_async() -> Result<Option<Vec<u8>>, HfnError> {
+    Ok(instance()?.read_async().await)
+}
 
-        url.query_pairs_mut()
-            .append_pair("appid", &json_config.appid);
+pub fn send_message(socket_id: String, payload: Vec<u8>) -> Result<i32, HfnError> {
+    instance()?.send_message(socket_id, payload)
+}
 
-        url.query_pairs_mut()
-            .append_pair("ver", env!("CARGO_PKG_VERSION"));
+pub fn call_rpc(
+    socket_id: String,
+    package_id: u32,
+    rpc_id: u32,
+    payload: Vec<u8>,
+    timeout: Duration,
+) -> Result<u32, HfnError> {
+    instance()?.call_rpc(socket_id, package_id, rpc_id, payload, timeout)
+}
 
-        url.query_pairs_mut().append_pair("sdk", &init_args.sdk);
+pub fn cancel_rpc(call_id: u32) -> Result<bool, HfnError> {
+    Ok(instance()?.cancel_rpc(call_id))
+}
 
-        let (write_tx, write_rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
+pub fn respond_rpc(
+    socket_id: String,
+    package_id: u32,
+    rpc_id: u32,
+    call_id: u32,
+    result: Result<Vec<u8>, String>,
+) -> Result<i32, HfnError> {
+    instance()?.respond_rpc(socket_id, package_id, rpc_id, call_id, result)
+}
 
-        GATEWAY_WRITE_CHAN_TX.set(write_tx).unwrap();
+pub fn encode_payload(
+    package_id: u32,
+    schema_id: u32,
+    value: &HfnValue,
+) -> Result<Vec<u8>, HfnError> {
+    instance()?.encode_payload(package_id, schema_id, value)
+}
 
-        let read_tx = READ_CHAN_TX.get().unwrap().clone();
-        let gateway = Gateway {
-            dev: true,
-            runway: url,
-            read_tx,
-        };
+pub fn decode_payload(
+    package_id: u32,
+    schema_id: u32,
+    payload: Vec<u8>,
+) -> Result<HfnValue, HfnError> {
+    instance()?.decode_payload(package_id, schema_id, payload)
+}
 
-        runtime.spawn(async move {
-            gateway.connect(write_rx).await;
-        });
+pub fn socket_rtt(socket_id: &str) -> Result<Option<Duration>, HfnError> {
+    instance()?.socket_rtt(socket_id)
+}
 
-        // todo add package signature for querystring
-    }
+pub fn socket_claims(socket_id: &str) -> Result<Claims, HfnError> {
+    instance()?.socket_claims(socket_id)
 }
 
-pub fn read() -> Option<Vec<u8>> {
-    let read_rx = unsafe { READ_CHAN_RX.get_mut().unwrap() };
-    let data = read_rx.blocking_recv();
-    data
+pub fn socket_protocol_violations(socket_id: &str) -> Result<u64, HfnError> {
+    instance()?.socket_protocol_violations(socket_id)
 }
 
-pub enum TryReadRes {
-    DATA(Vec<u8>),
-    EMPTY,
-    CLOSED,
+pub fn close_socket(socket_id: &str, reason: &str) -> Result<(), HfnError> {
+    instance()?.close_socket(socket_id, reason)
 }
 
-pub fn try_read() -> TryReadRes {
-    let read_rx = unsafe { READ_CHAN_RX.get_mut().unwrap() };
-    match read_rx.try_recv() {
-        Ok(data) => TryReadRes::DATA(data),
-        Err(e) => match e {
-            TryRecvError::Empty => TryReadRes::EMPTY,
-            TryRecvError::Disconnected => TryReadRes::CLOSED,
-        },
-    }
+pub fn close_sockets_by_client(client_id: &str, reason: &str) -> Result<usize, HfnError> {
+    instance()?.close_sockets_by_client(client_id, reason)
 }
 
-pub async fn read_async() -> Option<Vec<u8>> {
-    let read_rx = unsafe { READ_CHAN_RX.get_mut().unwrap() };
-    let data = read_rx.recv().await;
-    data
+pub fn retry_socket(socket_id: &str, delay: u8) -> Result<(), HfnError> {
+    instance()?.retry_socket(socket_id, delay)
 }
 
-pub fn send_message(socket_id: String, payload: Vec<u8>) {
-    if let Some(gateway_write_tx) = GATEWAY_WRITE_CHAN_TX.get() {
-        gateway_write_tx.send((socket_id, payload)).unwrap();
-        return;
-    }
+pub fn reset_socket(socket_id: &str, delay: u8) -> Result<(), HfnError> {
+    instance()?.reset_socket(socket_id, delay)
+}
 
-    let socket_chans = SOCKET_CHANS.get().unwrap();
-    if let Some(socket_chan) = socket_chans.get(&socket_id) {
-        socket_chan.send(Action::SendMessage(ActionSendMessage { payload }));
+pub fn redirect_socket(socket_id: &str, delay: u8, target: &str) -> Result<(), HfnError> {
+    instance()?.redirect_socket(socket_id, delay, target)
+}
 
-        return;
-    }
+// shut down the default instance, init can be called again afterwards
+pub fn shutdown(timeout: Duration) -> Result<(), HfnError> {
+    let instance = INSTANCE
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .ok_or(HfnError::NotInitialized)?;
+    instance.shutdown(timeout)
 }
@@ -0,0 +1,178 @@
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression as Level};
+
+use crate::codec::wire::ParseError;
+
+// A decompressed payload may not grow past this, tungstenite's default max message size
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+// Payload compression of a MESSAGE, the number is its `compress` flag on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressMethod {
+    #[default]
+    None = 0,
+    // zlib stream, what CompressionStream("deflate") produces in browsers
+    Deflate = 1,
+    // only with the zstd feature
+    Zstd = 2,
+}
+
+impl CompressMethod {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(CompressMethod::None),
+            1 => Some(CompressMethod::Deflate),
+            2 => Some(CompressMethod::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        self == CompressMethod::None || CompressMethod::supported().contains(&self)
+    }
+
+    // methods this build can decode, best first
+    pub fn supported() -> &'static [CompressMethod] {
+        #[cfg(feature = "zstd")]
+        return &[CompressMethod::Zstd, CompressMethod::Deflate];
+        #[cfg(not(feature = "zstd"))]
+        return &[CompressMethod::Deflate];
+    }
+
+    // comma separated, as announced in the connect query
+    pub fn supported_list() -> String {
+        CompressMethod::supported()
+            .iter()
+            .map(|m| (*m as u8).to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    // Our best method among the ones a peer announced as a comma separated list.
+    // Unknown methods are skipped, nothing in common means no compression.
+    pub fn negotiate(announced: Option<&str>) -> CompressMethod {
+        let announced: Vec<u8> = announced
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|m| m.trim().parse().ok())
+            .collect();
+
+        CompressMethod::supported()
+            .iter()
+            .copied()
+            .find(|m| announced.contains(&(*m as u8)))
+            .unwrap_or_default()
+    }
+}
+
+// What a connection compresses on send: payloads larger than min_size with method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Compression {
+    pub method: CompressMethod,
+    // kb, as carried by OPEN
+    pub min_size: u8,
+}
+
+impl Compression {
+    pub const NONE: Compression = Compression {
+        method: CompressMethod::None,
+        min_size: 0,
+    };
+
+    pub fn applies_to(&self, payload: &[u8]) -> bool {
+        self.method != CompressMethod::None && payload.len() > self.min_size as usize * 1024
+    }
+}
+
+pub fn compress(method: CompressMethod, data: &[u8]) -> Vec<u8> {
+    match method {
+        CompressMethod::None => data.to_vec(),
+        CompressMethod::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+            // writing to a Vec can't fail
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        #[cfg(feature = "zstd")]
+        CompressMethod::Zstd => zstd::bulk::compress(data, 0).unwrap(),
+        // never negotiated without the feature
+        #[cfg(not(feature = "zstd"))]
+        CompressMethod::Zstd => data.to_vec(),
+    }
+}
+
+// The peer decides the sizes, output is capped at MAX_DECOMPRESSED_SIZE.
+pub fn decompress(method: CompressMethod, data: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut out = Vec::new();
+    let limit = MAX_DECOMPRESSED_SIZE as u64 + 1;
+    match method {
+        CompressMethod::None => return Ok(data.to_vec()),
+        CompressMethod::Deflate => {
+            ZlibDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)
+                .map_err(|_| ParseError::Decompress)?;
+        }
+        #[cfg(feature = "zstd")]
+        CompressMethod::Zstd => {
+            zstd::stream::read::Decoder::new(data)
+                .map_err(|_| ParseError::Decompress)?
+                .take(limit)
+                .read_to_end(&mut out)
+                .map_err(|_| ParseError::Decompress)?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        CompressMethod::Zstd => return Err(ParseError::Decompress),
+    }
+
+    if out.len() > MAX_DECOMPRESSED_SIZE {
+        return Err(ParseError::Decompress);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{codec::wire::ParseError, protocol::compress::*};
+
+    #[test]
+    fn compress_round_trip() {
+        let data = b"hyper function ".repeat(100);
+        for method in CompressMethod::supported() {
+            let compressed = compress(*method, &data);
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(*method, &compressed).unwrap(), data);
+        }
+
+        assert_eq!(
+            decompress(CompressMethod::Deflate, &[1, 2, 3]),
+            Err(ParseError::Decompress)
+        );
+    }
+
+    #[test]
+    fn negotiate_compress_method() {
+        assert_eq!(CompressMethod::negotiate(None), CompressMethod::None);
+        assert_eq!(
+            CompressMethod::negotiate(Some("1")),
+            CompressMethod::Deflate
+        );
+        assert_eq!(CompressMethod::negotiate(Some("9,x")), CompressMethod::None);
+        assert_eq!(
+            CompressMethod::negotiate(Some("1,2")),
+            CompressMethod::supported()[0]
+        );
+    }
+
+    #[test]
+    fn compress_above_min_size() {
+        let compression = Compression {
+            method: CompressMethod::Deflate,
+            min_size: 1,
+        };
+        assert!(!compression.applies_to(&[0; 1024]));
+        assert!(compression.applies_to(&[0; 1025]));
+        assert!(!Compression::default().applies_to(&[0; 4096]));
+    }
+}
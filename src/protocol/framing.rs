@@ -0,0 +1,1014 @@
+use std::{fmt, io::Cursor};
+
+use crate::codec::wire::{
+    read_bin, read_headers, read_str_bytes, read_string, remaining, write_headers, write_str_bytes,
+    ParseError, Reader,
+};
+
+use super::{
+    compress::{compress, Compression},
+    packet::*,
+};
+
+// Which link a connection is. Both speak the same packets under their own numbers, the
+// upstream link adds a socket id to MESSAGE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    // clients connected to /hfn
+    Client,
+    // the core's connection to devtools or a gateway cluster
+    Upstream,
+}
+
+const CLIENT_PACKET_TYPES: [(PacketKind, u8); 10] = [
+    (PacketKind::Open, 1),
+    (PacketKind::Retry, 2),
+    (PacketKind::Reset, 3),
+    (PacketKind::Redirect, 4),
+    (PacketKind::Close, 5),
+    (PacketKind::Ping, 6),
+    (PacketKind::Pong, 7),
+    (PacketKind::Message, 8),
+    (PacketKind::Ack, 9),
+    (PacketKind::Reject, 10),
+];
+
+// no RESET or REJECT upstream
+const UPSTREAM_PACKET_TYPES: [(PacketKind, u8); 8] = [
+    (PacketKind::Open, 6),
+    (PacketKind::Close, 7),
+    (PacketKind::Ping, 8),
+    (PacketKind::Pong, 9),
+    (PacketKind::Retry, 10),
+    (PacketKind::Redirect, 11),
+    (PacketKind::Message, 12),
+    (PacketKind::Ack, 13),
+];
+
+impl Profile {
+    fn packet_types(self) -> &'static [(PacketKind, u8)] {
+        match self {
+            Profile::Client => &CLIENT_PACKET_TYPES,
+            Profile::Upstream => &UPSTREAM_PACKET_TYPES,
+        }
+    }
+
+    pub fn packet_type(self, kind: PacketKind) -> Option<u8> {
+        self.packet_types()
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, t)| *t)
+    }
+
+    pub fn packet_kind(self, packet_type: u8) -> Option<PacketKind> {
+        self.packet_types()
+            .iter()
+            .find(|(_, t)| *t == packet_type)
+            .map(|(k, _)| *k)
+    }
+}
+
+// How packets are laid out on one connection: the link profile and the negotiated
+// protocol version.
+//
+// From version 1 on OPEN starts with the version, so a peer that asked for a newer one
+// can read what it got before the rest of the packet. Version 2 brings the upstream
+// compression fields of OPEN and MESSAGE to the client link, version 3 adds REJECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub profile: Profile,
+    pub version: u8,
+    // what outgoing messages get compressed with, negotiated per connection
+    pub compression: Compression,
+}
+
+impl Framing {
+    pub const fn client(version: u8) -> Self {
+        Framing {
+            profile: Profile::Client,
+            version,
+            compression: Compression::NONE,
+        }
+    }
+
+    pub const fn upstream(version: u8) -> Self {
+        Framing {
+            profile: Profile::Upstream,
+            version,
+            compression: Compression::NONE,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn is_upstream(&self) -> bool {
+        self.profile == Profile::Upstream
+    }
+
+    // OPEN has compress_size and compress_method, MESSAGE a compress flag
+    pub fn carries_compression(&self) -> bool {
+        self.is_upstream() || self.version >= 2
+    }
+
+    // REJECT is known to the peer
+    pub fn carries_reject(&self) -> bool {
+        !self.is_upstream() && self.version >= 3
+    }
+
+    // Message whose pkg_id, headers and payload the host already encoded. The payload is
+    // compressed when it is over the negotiated size.
+    pub fn encode_host_message(&self, id: i32, data: &[u8], socket_id: &str) -> Vec<u8> {
+        let packet_type = self.profile.packet_type(PacketKind::Message).unwrap();
+
+        let mut buf = Vec::with_capacity(9 + data.len() + socket_id.len());
+        rmp::encode::write_pfix(&mut buf, packet_type).unwrap();
+        rmp::encode::write_sint(&mut buf, id as i64).unwrap();
+
+        let mut method = 0;
+        match self.compressed_host_payload(data) {
+            Some((head, payload)) => {
+                buf.extend_from_slice(head);
+                rmp::encode::write_bin(&mut buf, &payload).unwrap();
+                method = self.compression.method as u8;
+            }
+            None => buf.extend_from_slice(data),
+        }
+
+        if self.is_upstream() {
+            rmp::encode::write_str(&mut buf, socket_id).unwrap();
+        }
+        if self.carries_compression() {
+            rmp::encode::write_pfix(&mut buf, method).unwrap();
+        }
+
+        buf
+    }
+
+    // the encoded pkg_id and headers, and the compressed payload
+    fn compressed_host_payload<'a>(&self, data: &'a [u8]) -> Option<(&'a [u8], Vec<u8>)> {
+        if !self.carries_compression() || !self.compression.applies_to(data) {
+            return None;
+        }
+
+        let mut cur = Cursor::new(data);
+        rmp::decode::read_int::<i32, _>(&mut cur).ok()?;
+        read_headers(&mut cur).ok()?;
+        let head = cur.position() as usize;
+        let payload = read_bin(&mut cur).ok()?;
+        if !self.compression.applies_to(&payload) {
+            return None;
+        }
+
+        Some((&data[..head], compress(self.compression.method, &payload)))
+    }
+
+    // one websocket frame can carry several packets
+    pub fn parse_frame(&self, data: &[u8]) -> Result<Vec<Packet>, ParseError> {
+        let mut packets = Vec::new();
+        let mut cur = Cursor::new(data);
+        while remaining(&cur) > 0 {
+            packets.push(self.parse_packet(&mut cur)?);
+        }
+
+        Ok(packets)
+    }
+
+    // Frames before OPEN. A peer from before versioning ignores the version we asked for
+    // and sends OPEN without one, its frames only read at version 0, which the link then
+    // keeps. A versioned OPEN answers at most the version asked for.
+    pub fn parse_open_frame(&mut self, data: &[u8]) -> Result<Vec<Packet>, ParseError> {
+        let versioned = self.parse_frame(data);
+        if self.version == 0 {
+            return versioned;
+        }
+
+        let answered = |packets: &Vec<Packet>| {
+            packets.iter().all(|packet| match packet {
+                Packet::OPEN(open) => (1..=self.version).contains(&open.version),
+                _ => true,
+            })
+        };
+        if matches!(&versioned, Ok(packets) if answered(packets)) {
+            return versioned;
+        }
+
+        let unversioned = Framing {
+            version: 0,
+            ..*self
+        };
+        match unversioned.parse_frame(data) {
+            Ok(packets) => {
+                *self = unversioned;
+                Ok(packets)
+            }
+            Err(_) => versioned,
+        }
+    }
+
+    pub fn parse_packet(&self, cur: &mut Reader) -> Result<Packet, ParseError> {
+        let packet_type = rmp::decode::read_pfix(cur)?;
+        let kind = self
+            .profile
+            .packet_kind(packet_type)
+            .filter(|kind| *kind != PacketKind::Reject || self.carries_reject())
+            .ok_or(ParseError::UnknownPacket(packet_type))?;
+
+        let packet = match kind {
+            PacketKind::Open => {
+                let mut open = PacketOpen::default();
+                if self.version > 0 {
+                    open.version = rmp::decode::read_pfix(cur)?;
+                }
+                open.ping_interval = rmp::decode::read_pfix(cur)?;
+                open.ping_timeout = rmp::decode::read_pfix(cur)?;
+                if self.carries_compression() {
+                    open.compress_size = rmp::decode::read_pfix(cur)?;
+                    open.compress_method = rmp::decode::read_pfix(cur)?;
+                }
+                Packet::OPEN(open)
+            }
+            PacketKind::Retry => Packet::RETRY(PacketRetry {
+                delay: rmp::decode::read_pfix(cur)?,
+            }),
+            PacketKind::Reset => Packet::RESET(PacketReset {
+                delay: rmp::decode::read_pfix(cur)?,
+            }),
+            PacketKind::Redirect => Packet::REDIRECT(PacketRedirect {
+                delay: rmp::decode::read_pfix(cur)?,
+                target: read_string(cur)?,
+            }),
+            PacketKind::Close => Packet::CLOSE(PacketClose {
+                reason: read_string(cur)?,
+            }),
+            PacketKind::Ping => Packet::PING(PacketPing {}),
+            PacketKind::Pong => Packet::PONG(PacketPong {}),
+            PacketKind::Message => {
+                let mut msg = PacketMessage {
+                    id: rmp::decode::read_int(cur)?,
+                    pkg_id: rmp::decode::read_int(cur)?,
+                    headers: read_headers(cur)?,
+                    payload: read_bin(cur)?,
+                    ..Default::default()
+                };
+                if self.is_upstream() {
+                    msg.socket_id = read_str_bytes(cur)?;
+                }
+                if self.carries_compression() {
+                    msg.compress = rmp::decode::read_pfix(cur)?;
+                }
+                Packet::MESSAGE(msg)
+            }
+            PacketKind::Ack => Packet::ACK(PacketAck {
+                id: rmp::decode::read_int(cur)?,
+                pkg_id: rmp::decode::read_int(cur)?,
+            }),
+            PacketKind::Reject => Packet::REJECT(PacketReject {
+                id: rmp::decode::read_int(cur)?,
+                pkg_id: rmp::decode::read_int(cur)?,
+                code: rmp::decode::read_pfix(cur)?,
+                path: read_string(cur)?,
+                reason: read_string(cur)?,
+            }),
+        };
+
+        Ok(packet)
+    }
+}
+
+// A packet the link can't carry: the profile has no number for it, or the peer's
+// version doesn't know it yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedPacket(pub PacketKind);
+
+impl fmt::Display for UnsupportedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} packet not supported by the link", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedPacket {}
+
+impl Packet {
+    pub fn encode(&self, framing: Framing) -> Result<Vec<u8>, UnsupportedPacket> {
+        let kind = self.kind();
+        let packet_type = framing
+            .profile
+            .packet_type(kind)
+            .filter(|_| kind != PacketKind::Reject || framing.carries_reject())
+            .ok_or(UnsupportedPacket(kind))?;
+
+        let mut buf = Vec::new();
+        let w = &mut buf;
+        rmp::encode::write_pfix(w, packet_type).unwrap();
+
+        // u8 fields go out as positive fixints, which is what the parser accepts
+        match self {
+            Packet::OPEN(p) => {
+                if framing.version > 0 {
+                    rmp::encode::write_uint(w, p.version as u64).unwrap();
+                }
+                rmp::encode::write_uint(w, p.ping_interval as u64).unwrap();
+                rmp::encode::write_uint(w, p.ping_timeout as u64).unwrap();
+                if framing.carries_compression() {
+                    rmp::encode::write_uint(w, p.compress_size as u64).unwrap();
+                    rmp::encode::write_uint(w, p.compress_method as u64).unwrap();
+                }
+            }
+            Packet::RETRY(PacketRetry { delay }) | Packet::RESET(PacketReset { delay }) => {
+                rmp::encode::write_uint(w, *delay as u64).unwrap();
+            }
+            Packet::REDIRECT(p) => {
+                rmp::encode::write_uint(w, p.delay as u64).unwrap();
+                rmp::encode::write_str(w, &p.target).unwrap();
+            }
+            Packet::CLOSE(p) => {
+                rmp::encode::write_str(w, &p.reason).unwrap();
+            }
+            Packet::PING(_) | Packet::PONG(_) => {}
+            Packet::MESSAGE(p) => {
+                rmp::encode::write_sint(w, p.id as i64).unwrap();
+                rmp::encode::write_sint(w, p.pkg_id as i64).unwrap();
+                write_headers(w, &p.headers);
+                rmp::encode::write_bin(w, &p.payload).unwrap();
+                if framing.is_upstream() {
+                    write_str_bytes(w, &p.socket_id);
+                }
+                if framing.carries_compression() {
+                    rmp::encode::write_uint(w, p.compress as u64).unwrap();
+                }
+            }
+            Packet::ACK(p) => {
+                rmp::encode::write_sint(w, p.id as i64).unwrap();
+                rmp::encode::write_sint(w, p.pkg_id as i64).unwrap();
+            }
+            Packet::REJECT(p) => {
+                rmp::encode::write_sint(w, p.id as i64).unwrap();
+                rmp::encode::write_sint(w, p.pkg_id as i64).unwrap();
+                rmp::encode::write_uint(w, p.code as u64).unwrap();
+                rmp::encode::write_str(w, &p.path).unwrap();
+                rmp::encode::write_str(w, &p.reason).unwrap();
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    use crate::{
+        codec::wire::{remaining, write_headers, ParseError},
+        protocol::{
+            compress::{CompressMethod, Compression},
+            framing::{Profile, UnsupportedPacket},
+            *,
+        },
+    };
+
+    const CLIENT: Framing = Framing::client(PROTOCOL_VERSION);
+    const UPSTREAM: Framing = Framing::upstream(PROTOCOL_VERSION);
+
+    fn framing() -> impl Strategy<Value = Framing> {
+        prop_oneof![
+            (0..=PROTOCOL_VERSION).prop_map(Framing::client),
+            (0..=PROTOCOL_VERSION).prop_map(Framing::upstream),
+        ]
+    }
+
+    // headers are a flattened str map, keys and values alternate
+    fn headers() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        prop::collection::vec((any::<Vec<u8>>(), any::<Vec<u8>>()), 0..4)
+            .prop_map(|pairs| pairs.into_iter().flat_map(|(k, v)| [k, v]).collect())
+    }
+
+    // packets of the framing, with the fields its layout doesn't carry left at default
+    fn packet(framing: Framing) -> impl Strategy<Value = Packet> {
+        let upstream = framing.profile == Profile::Upstream;
+        let version = if framing.version > 0 { 0..128u8 } else { 0..1 };
+        let compress_u8 = if framing.carries_compression() {
+            0..128u8
+        } else {
+            0..1
+        };
+        let socket_id = if upstream { 0..16usize } else { 0..1 };
+
+        let open = (
+            version,
+            0..128u8,
+            0..128u8,
+            compress_u8.clone(),
+            compress_u8.clone(),
+        )
+            .prop_map(
+                |(version, ping_interval, ping_timeout, compress_size, compress_method)| {
+                    Packet::OPEN(PacketOpen {
+                        version,
+                        ping_interval,
+                        ping_timeout,
+                        compress_size,
+                        compress_method,
+                    })
+                },
+            );
+        let message = (
+            any::<i32>(),
+            any::<i32>(),
+            headers(),
+            any::<Vec<u8>>(),
+            prop::collection::vec(any::<u8>(), socket_id),
+            compress_u8,
+        )
+            .prop_map(|(id, pkg_id, headers, payload, socket_id, compress)| {
+                Packet::MESSAGE(PacketMessage {
+                    id,
+                    pkg_id,
+                    headers,
+                    payload,
+                    socket_id,
+                    compress,
+                })
+            });
+        // there is no RESET upstream
+        let reset = (0..128u8).prop_map(move |delay| match upstream {
+            true => Packet::RETRY(PacketRetry { delay }),
+            false => Packet::RESET(PacketReset { delay }),
+        });
+        // nor REJECT, which clients know from version 3
+        let carries_reject = framing.carries_reject();
+        let reject = (
+            any::<i32>(),
+            any::<i32>(),
+            0..128u8,
+            any::<String>(),
+            any::<String>(),
+        )
+            .prop_map(
+                move |(id, pkg_id, code, path, reason)| match carries_reject {
+                    true => Packet::REJECT(PacketReject {
+                        id,
+                        pkg_id,
+                        code,
+                        path,
+                        reason,
+                    }),
+                    false => Packet::ACK(PacketAck { id, pkg_id }),
+                },
+            );
+
+        prop_oneof![
+            open,
+            (0..128u8).prop_map(|delay| Packet::RETRY(PacketRetry { delay })),
+            reset,
+            (0..128u8, any::<String>())
+                .prop_map(|(delay, target)| Packet::REDIRECT(PacketRedirect { delay, target })),
+            any::<String>().prop_map(|reason| Packet::CLOSE(PacketClose { reason })),
+            Just(Packet::PING(PacketPing {})),
+            Just(Packet::PONG(PacketPong {})),
+            message,
+            (any::<i32>(), any::<i32>())
+                .prop_map(|(id, pkg_id)| Packet::ACK(PacketAck { id, pkg_id })),
+            reject,
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn encode_parse_round_trip(
+            (framing, packet) in framing().prop_flat_map(|f| (Just(f), packet(f)))
+        ) {
+            let data = packet.encode(framing).unwrap();
+            let mut cur = Cursor::new(&data[..]);
+            prop_assert_eq!(framing.parse_packet(&mut cur), Ok(packet));
+            prop_assert_eq!(remaining(&cur), 0);
+        }
+
+        #[test]
+        fn encode_parse_frame_round_trip(
+            (framing, packets) in framing()
+                .prop_flat_map(|f| (Just(f), prop::collection::vec(packet(f), 1..8)))
+        ) {
+            let data: Vec<u8> = packets.iter().flat_map(|p| p.encode(framing).unwrap()).collect();
+            prop_assert_eq!(framing.parse_frame(&data), Ok(packets));
+        }
+    }
+
+    #[test]
+    fn decode_client_packet_message() {
+        let mut data = Vec::new();
+        rmp::encode::write_pfix(&mut data, 8).unwrap();
+        rmp::encode::write_sint(&mut data, 1).unwrap();
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        rmp::encode::write_map_len(&mut data, 1).unwrap();
+        rmp::encode::write_str(&mut data, "k").unwrap();
+        rmp::encode::write_str(&mut data, "v").unwrap();
+        rmp::encode::write_bin(&mut data, &[1, 2, 3]).unwrap();
+        // compress
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        match CLIENT.parse_packet(&mut cur).unwrap() {
+            Packet::MESSAGE(msg) => {
+                assert_eq!((msg.id, msg.pkg_id), (1, 2));
+                assert_eq!(msg.headers, vec![b"k".to_vec(), b"v".to_vec()]);
+                assert_eq!(msg.payload, vec![1, 2, 3]);
+            }
+            _ => panic!("parse packet message failed"),
+        }
+    }
+
+    #[test]
+    fn decode_client_multi_packet_frame() {
+        let mut data = Vec::new();
+        rmp::encode::write_pfix(&mut data, 6).unwrap();
+        rmp::encode::write_pfix(&mut data, 9).unwrap();
+        rmp::encode::write_sint(&mut data, 3).unwrap();
+        rmp::encode::write_sint(&mut data, 4).unwrap();
+
+        let packets = CLIENT.parse_frame(&data).unwrap();
+        assert!(matches!(packets[0], Packet::PING(_)));
+        assert!(matches!(packets[1], Packet::ACK(_)));
+
+        // a truncated trailing packet fails the whole frame
+        data.push(9);
+        assert_eq!(
+            CLIENT.parse_frame(&data).err(),
+            Some(ParseError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn reject_malformed_client_packets() {
+        // payload length past the end of the frame
+        let mut data = Vec::new();
+        rmp::encode::write_pfix(&mut data, 8).unwrap();
+        rmp::encode::write_sint(&mut data, 1).unwrap();
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        rmp::encode::write_map_len(&mut data, 0).unwrap();
+        rmp::encode::write_bin_len(&mut data, 1024).unwrap();
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            CLIENT.parse_packet(&mut cur).err(),
+            Some(ParseError::UnexpectedEof)
+        );
+
+        let data = [0x7f];
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            CLIENT.parse_packet(&mut cur).err(),
+            Some(ParseError::UnknownPacket(0x7f))
+        );
+
+        // close reason is not a str
+        let data = [5, 1];
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            CLIENT.parse_packet(&mut cur).err(),
+            Some(ParseError::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn decode_packet_open() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 6).unwrap();
+        rmp::encode::write_pfix(&mut data, PROTOCOL_VERSION).unwrap();
+        rmp::encode::write_pfix(&mut data, 25).unwrap();
+        rmp::encode::write_pfix(&mut data, 20).unwrap();
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet open failed");
+        match packet {
+            Packet::OPEN(packet) => {
+                assert_eq!(packet.version, PROTOCOL_VERSION);
+                assert_eq!(packet.ping_interval, 25);
+                assert_eq!(packet.ping_timeout, 20);
+                assert_eq!(packet.compress_size, 0);
+                assert_eq!(packet.compress_method, 0);
+            }
+            _ => panic!("parse packet open failed"),
+        }
+    }
+
+    #[test]
+    fn decode_unversioned_upstream_packet_open() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 6).unwrap();
+        rmp::encode::write_pfix(&mut data, 25).unwrap();
+        rmp::encode::write_pfix(&mut data, 20).unwrap();
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = Framing::upstream(0)
+            .parse_packet(&mut cur)
+            .expect("parse packet open failed");
+        match packet {
+            Packet::OPEN(packet) => {
+                assert_eq!(packet.version, 0);
+                assert_eq!(packet.ping_interval, 25);
+                assert_eq!(packet.ping_timeout, 20);
+                assert_eq!(packet.compress_size, 0);
+                assert_eq!(packet.compress_method, 0);
+            }
+            _ => panic!("parse packet open failed"),
+        }
+    }
+
+    #[test]
+    fn parse_open_frame_of_any_version() {
+        let open = PacketOpen {
+            ping_interval: 25,
+            ping_timeout: 20,
+            ..Default::default()
+        };
+        let ping = Packet::PING(PacketPing {});
+
+        // a baseline peer, alone and with packets behind OPEN
+        let unversioned = Framing::upstream(0);
+        for frame in [
+            Packet::OPEN(open.clone()).encode(unversioned).unwrap(),
+            [
+                Packet::OPEN(open.clone()).encode(unversioned).unwrap(),
+                ping.encode(unversioned).unwrap(),
+            ]
+            .concat(),
+        ] {
+            let mut framing = UPSTREAM;
+            let packets = framing.parse_open_frame(&frame).unwrap();
+            assert_eq!(packets[0], Packet::OPEN(open.clone()));
+            assert_eq!(framing.version, 0);
+        }
+
+        let versioned = PacketOpen {
+            version: 2,
+            ..open.clone()
+        };
+        let mut framing = UPSTREAM;
+        let frame = Packet::OPEN(versioned.clone()).encode(UPSTREAM).unwrap();
+        let packets = framing.parse_open_frame(&frame).unwrap();
+        assert_eq!(packets, vec![Packet::OPEN(versioned)]);
+        assert_eq!(framing.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn decode_packet_close() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 7).unwrap();
+        rmp::encode::write_str(&mut data, "no reason").unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet close failed");
+        match packet {
+            Packet::CLOSE(packet) => {
+                assert_eq!(packet.reason, "no reason");
+            }
+            _ => panic!("parse packet open failed"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_ping() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 8).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet ping failed");
+        match packet {
+            Packet::PING(_) => {}
+            _ => panic!("parse packet ping failed"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_pong() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 9).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet pong failed");
+        match packet {
+            Packet::PONG(_) => {}
+            _ => panic!("parse packet pong failed"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_retry() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 10).unwrap();
+        rmp::encode::write_pfix(&mut data, 3).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet retry failed");
+        match packet {
+            Packet::RETRY(packet) => {
+                assert_eq!(packet.delay, 3);
+            }
+            _ => panic!("parse packet retry failed"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_redirect() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 11).unwrap();
+        rmp::encode::write_pfix(&mut data, 6).unwrap();
+        rmp::encode::write_str(&mut data, "123").unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet redirect failed");
+        match packet {
+            Packet::REDIRECT(packet) => {
+                assert_eq!(packet.delay, 6);
+                assert_eq!(packet.target, "123");
+            }
+            _ => panic!("parse packet redirect failed"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_message() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 12).unwrap();
+        // message id
+        rmp::encode::write_sint(&mut data, 1).unwrap();
+        // package id
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        // headers
+        rmp::encode::write_map_len(&mut data, 3).unwrap();
+        rmp::encode::write_str(&mut data, "a").unwrap();
+        rmp::encode::write_str(&mut data, "1").unwrap();
+        rmp::encode::write_str(&mut data, "b").unwrap();
+        rmp::encode::write_str(&mut data, "2").unwrap();
+        rmp::encode::write_str(&mut data, "c").unwrap();
+        rmp::encode::write_str(&mut data, "3").unwrap();
+        // payload
+        rmp::encode::write_bin(&mut data, &[0x01, 0x02, 0x03]).unwrap();
+        // socket id
+        rmp::encode::write_str(&mut data, "socketid:1").unwrap();
+        // compress
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let msg = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet message failed");
+        match msg {
+            Packet::MESSAGE(msg) => {
+                assert_eq!(msg.id, 1);
+                assert_eq!(msg.pkg_id, 2);
+                assert_eq!(msg.headers.len(), 3 * 2);
+                assert_eq!(msg.headers[0], "a".as_bytes());
+                assert_eq!(msg.headers[1], "1".as_bytes());
+                assert_eq!(msg.headers[2], "b".as_bytes());
+                assert_eq!(msg.headers[3], "2".as_bytes());
+                assert_eq!(msg.headers[4], "c".as_bytes());
+                assert_eq!(msg.headers[5], "3".as_bytes());
+                assert_eq!(msg.payload, vec![0x01, 0x02, 0x03]);
+            }
+            _ => panic!("should be message"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_message_with_no_headers() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 12).unwrap();
+        // message id
+        rmp::encode::write_sint(&mut data, 1).unwrap();
+        // package id
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        // headers
+        rmp::encode::write_map_len(&mut data, 0).unwrap();
+        // payload
+        rmp::encode::write_bin(&mut data, &[0x01, 0x02, 0x03]).unwrap();
+        // socket id
+        rmp::encode::write_str(&mut data, "socketid:1").unwrap();
+        // compress
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let msg = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet message failed");
+        match msg {
+            Packet::MESSAGE(msg) => {
+                assert_eq!(msg.id, 1);
+                assert_eq!(msg.pkg_id, 2);
+                assert_eq!(msg.headers.len(), 0);
+                assert_eq!(msg.payload, vec![0x01, 0x02, 0x03]);
+            }
+            _ => panic!("should be message"),
+        }
+    }
+
+    #[test]
+    fn decode_packet_message_with_three_message_mixin() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 12).unwrap();
+        // message id
+        rmp::encode::write_sint(&mut data, 1).unwrap();
+        // package id
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        // headers
+        rmp::encode::write_map_len(&mut data, 0).unwrap();
+        // payload
+        rmp::encode::write_bin(&mut data, &[0x01, 0x02, 0x03]).unwrap();
+        // socket id
+        rmp::encode::write_str(&mut data, "socketid:1").unwrap();
+        // compress
+        rmp::encode::write_pfix(&mut data, 0).unwrap();
+
+        let data = [data.as_slice(), data.as_slice(), data.as_slice()].concat();
+
+        let mut msgs: Vec<PacketMessage> = Vec::new();
+        let mut cur = Cursor::new(&data[..]);
+
+        while cur.position() < data.len() as u64 {
+            let msg = UPSTREAM.parse_packet(&mut cur).unwrap();
+            match msg {
+                Packet::MESSAGE(msg) => {
+                    msgs.push(msg);
+                }
+                _ => panic!("should be message"),
+            }
+        }
+
+        assert_eq!(msgs.len(), 3);
+
+        for msg in msgs {
+            assert_eq!(msg.id, 1);
+            assert_eq!(msg.pkg_id, 2);
+            assert_eq!(msg.headers.len(), 0);
+            assert_eq!(msg.payload, vec![0x01, 0x02, 0x03]);
+        }
+    }
+
+    #[test]
+    fn decode_packet_ack() {
+        let mut data = Vec::new();
+        // packet message
+        rmp::encode::write_pfix(&mut data, 13).unwrap();
+        rmp::encode::write_pfix(&mut data, 3).unwrap();
+        rmp::encode::write_pfix(&mut data, 8).unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM
+            .parse_packet(&mut cur)
+            .expect("parse packet ack failed");
+        match packet {
+            Packet::ACK(packet) => {
+                assert_eq!(packet.id, 3);
+                assert_eq!(packet.pkg_id, 8);
+            }
+            _ => panic!("parse packet ack failed"),
+        }
+    }
+
+    #[test]
+    fn wrong_data_should_return_error() {
+        let mut data = Vec::new();
+        // packet ack
+        rmp::encode::write_pfix(&mut data, 13).unwrap();
+        rmp::encode::write_pfix(&mut data, 3).unwrap();
+        // wrong type
+        rmp::encode::write_str(&mut data, "bla").unwrap();
+
+        let mut cur = Cursor::new(&data[..]);
+        let packet = UPSTREAM.parse_packet(&mut cur);
+        assert!(packet.is_err());
+    }
+
+    #[test]
+    fn decode_unversioned_packet_open() {
+        // clients that don't negotiate a version get OPEN without one
+        let data = [1, 25, 20];
+        let mut cur = Cursor::new(&data[..]);
+        let packet = Framing::client(0).parse_packet(&mut cur).unwrap();
+        assert_eq!(
+            packet,
+            Packet::OPEN(PacketOpen {
+                ping_interval: 25,
+                ping_timeout: 20,
+                ..Default::default()
+            })
+        );
+        assert_eq!(remaining(&cur), 0);
+
+        // the same bytes are a truncated versioned OPEN
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            CLIENT.parse_packet(&mut cur).err(),
+            Some(ParseError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn packet_numbering_per_profile() {
+        let ping = Packet::PING(PacketPing {});
+        assert_eq!(ping.encode(CLIENT).unwrap(), vec![6]);
+        assert_eq!(ping.encode(UPSTREAM).unwrap(), vec![8]);
+
+        // RESET only exists on the client link
+        let data = [3, 1];
+        let mut cur = Cursor::new(&data[..]);
+        assert!(matches!(
+            CLIENT.parse_packet(&mut cur),
+            Ok(Packet::RESET(_))
+        ));
+        let mut cur = Cursor::new(&data[..]);
+        assert_eq!(
+            UPSTREAM.parse_packet(&mut cur).err(),
+            Some(ParseError::UnknownPacket(3))
+        );
+        let reset = Packet::RESET(PacketReset { delay: 1 });
+        assert_eq!(
+            reset.encode(UPSTREAM),
+            Err(UnsupportedPacket(PacketKind::Reset))
+        );
+
+        // REJECT needs a client of version 3
+        let reject = Packet::REJECT(PacketReject {
+            id: 1,
+            pkg_id: 1,
+            code: 3,
+            path: String::new(),
+            reason: String::new(),
+        });
+        assert!(reject.encode(CLIENT).is_ok());
+        assert_eq!(
+            reject.encode(Framing::client(2)),
+            Err(UnsupportedPacket(PacketKind::Reject))
+        );
+    }
+
+    #[test]
+    fn compress_host_message_over_min_size() {
+        let framing = CLIENT.with_compression(Compression {
+            method: CompressMethod::Deflate,
+            min_size: 1,
+        });
+
+        for (payload, compress) in [(vec![7; 1024], 0), (vec![7; 4096], 1)] {
+            let mut data = Vec::new();
+            rmp::encode::write_sint(&mut data, 2).unwrap();
+            write_headers(&mut data, &[b"k".to_vec(), b"v".to_vec()]);
+            rmp::encode::write_bin(&mut data, &payload).unwrap();
+
+            let buf = framing.encode_host_message(1, &data, "");
+            match framing.parse_frame(&buf).unwrap().pop() {
+                Some(Packet::MESSAGE(mut msg)) => {
+                    assert_eq!(msg.compress, compress);
+                    assert_eq!(msg.headers, vec![b"k".to_vec(), b"v".to_vec()]);
+                    msg.decompress().unwrap();
+                    assert_eq!(msg.payload, payload);
+                }
+                _ => panic!("parse packet message failed"),
+            }
+        }
+
+        // version 1 clients can't inflate
+        let framing = Framing::client(1).with_compression(framing.compression);
+        let mut data = Vec::new();
+        rmp::encode::write_sint(&mut data, 2).unwrap();
+        write_headers(&mut data, &[]);
+        rmp::encode::write_bin(&mut data, &[7; 4096]).unwrap();
+        assert!(matches!(
+            framing
+                .parse_frame(&framing.encode_host_message(1, &data, ""))
+                .unwrap()[..],
+            [Packet::MESSAGE(PacketMessage { compress: 0, .. })]
+        ));
+    }
+}
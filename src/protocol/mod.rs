@@ -0,0 +1,39 @@
+pub mod compress;
+pub mod framing;
+pub mod packet;
+
+pub use framing::Framing;
+pub use packet::*;
+
+// Version of the wire protocol spoken by this build. A client asks for one with `pv` in
+// the /hfn query and the server answers in OPEN. Clients that don't ask speak version 0,
+// the unversioned protocol.
+//
+// 1: OPEN carries the version
+// 2: payload compression on the client link
+// 3: REJECT for client messages that fail validation
+pub const PROTOCOL_VERSION: u8 = 3;
+
+// The version to speak with a client asking for `requested`, None if it can't be served.
+// A newer client is answered with our version and has to fall back to it.
+pub fn negotiate_version(requested: Option<&str>) -> Option<u8> {
+    match requested {
+        Some(v) => Some(v.parse::<u8>().ok()?.min(PROTOCOL_VERSION)),
+        None => Some(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::*;
+
+    #[test]
+    fn negotiate_protocol_version() {
+        assert_eq!(negotiate_version(None), Some(0));
+        assert_eq!(negotiate_version(Some("1")), Some(1));
+        // newer clients fall back to ours
+        assert_eq!(negotiate_version(Some("9")), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(Some("v1")), None);
+        assert_eq!(negotiate_version(Some("256")), None);
+    }
+}
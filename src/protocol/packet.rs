@@ -0,0 +1,145 @@
+// packets mirror the wire protocol, not every field is consumed yet
+#![allow(dead_code)]
+
+use crate::codec::wire::ParseError;
+
+use super::compress::{decompress, CompressMethod};
+
+// One packet set for both links. Fields a profile doesn't carry on the wire are left
+// at their default, see Framing for the layouts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    OPEN(PacketOpen),
+    RETRY(PacketRetry),
+    RESET(PacketReset),
+    REDIRECT(PacketRedirect),
+    CLOSE(PacketClose),
+    PING(PacketPing),
+    PONG(PacketPong),
+    MESSAGE(PacketMessage),
+    ACK(PacketAck),
+    REJECT(PacketReject),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Open,
+    Retry,
+    Reset,
+    Redirect,
+    Close,
+    Ping,
+    Pong,
+    Message,
+    Ack,
+    Reject,
+}
+
+impl Packet {
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            Packet::OPEN(_) => PacketKind::Open,
+            Packet::RETRY(_) => PacketKind::Retry,
+            Packet::RESET(_) => PacketKind::Reset,
+            Packet::REDIRECT(_) => PacketKind::Redirect,
+            Packet::CLOSE(_) => PacketKind::Close,
+            Packet::PING(_) => PacketKind::Ping,
+            Packet::PONG(_) => PacketKind::Pong,
+            Packet::MESSAGE(_) => PacketKind::Message,
+            Packet::ACK(_) => PacketKind::Ack,
+            Packet::REJECT(_) => PacketKind::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketOpen {
+    // negotiated protocol version, not on the wire for version 0
+    pub version: u8,
+    pub ping_interval: u8, // ping interval second
+    pub ping_timeout: u8,  // ping timeout second
+    // upstream and client from version 2
+    pub compress_size: u8, // min compress size kb, message payload great than this should be compress
+    pub compress_method: u8, // compression method 0: no, 1: deflate, 2: zstd
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketClose {
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketPing {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketPong {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketRetry {
+    pub delay: u8,
+}
+
+// client only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketReset {
+    pub delay: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketRedirect {
+    pub delay: u8,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketMessage {
+    pub id: i32,
+    pub pkg_id: i32,
+    pub headers: Vec<Vec<u8>>,
+    pub payload: Vec<u8>,
+    // upstream only
+    pub socket_id: Vec<u8>,
+    // upstream and client from version 2, the CompressMethod of the payload
+    pub compress: u8,
+}
+
+// inflate the payloads of the MESSAGE packets in a frame
+pub fn decompress_messages(mut packets: Vec<Packet>) -> Result<Vec<Packet>, ParseError> {
+    for packet in packets.iter_mut() {
+        if let Packet::MESSAGE(msg) = packet {
+            msg.decompress()?;
+        }
+    }
+    Ok(packets)
+}
+
+impl PacketMessage {
+    // inflate the payload in place
+    pub fn decompress(&mut self) -> Result<(), ParseError> {
+        if self.compress == 0 {
+            return Ok(());
+        }
+
+        let method = CompressMethod::from_u8(self.compress).ok_or(ParseError::Decompress)?;
+        self.payload = decompress(method, &self.payload)?;
+        self.compress = 0;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketAck {
+    pub id: i32,
+    pub pkg_id: i32,
+}
+
+// client only, from version 3. Answers a MESSAGE the server turned away instead of the
+// ACK, see RejectCode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketReject {
+    pub id: i32,
+    pub pkg_id: i32,
+    pub code: u8,
+    // where in the payload it failed, empty when the payload wasn't looked at
+    pub path: String,
+    pub reason: String,
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::codec::{sign::verify_hmac_sha256_hex, ClientAuth};
+
+// what an authenticated client is, attached to its socket
+pub type Claims = BTreeMap<String, String>;
+
+// decides on a handshake in a blocking task, Err carries the reason for the log
+pub type HostAuthenticator = Arc<dyn Fn(&AuthRequest) -> Result<Claims, String> + Send + Sync>;
+
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+// The parts of a /hfn handshake an authenticator gets to see
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub client_id: String,
+    pub session_id: String,
+    // milliseconds, the `ts` query
+    pub client_ts: u64,
+    // `token` query or an `Authorization: Bearer` header
+    pub token: Option<String>,
+    pub query: HashMap<String, String>,
+}
+
+// Runs before the websocket upgrade, a rejected client gets a 401
+#[derive(Clone, Default)]
+pub enum Authenticator {
+    // any client knowing the app id
+    #[default]
+    None,
+    // token -> subject
+    Token(HashMap<String, String>),
+    // `sig` is the hex HMAC-SHA256 of "{aid}\n{cid}\n{sid}\n{ts}", ts within max_skew
+    Hmac {
+        app_id: String,
+        secret: Vec<u8>,
+        max_skew: Duration,
+    },
+    Host(HostAuthenticator),
+}
+
+impl Authenticator {
+    pub fn from_args(app_id: &str, args: &ClientAuth) -> Self {
+        match args {
+            ClientAuth::Token { tokens } => Authenticator::Token(tokens.clone()),
+            ClientAuth::Hmac { secret, max_skew } => Authenticator::Hmac {
+                app_id: app_id.to_string(),
+                secret: secret.as_bytes().to_vec(),
+                max_skew: max_skew
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_MAX_CLOCK_SKEW),
+            },
+        }
+    }
+
+    pub async fn authenticate(&self, req: AuthRequest) -> Result<Claims, String> {
+        match self {
+            Authenticator::None => Ok(Claims::new()),
+            Authenticator::Token(tokens) => {
+                let token = req.token.as_ref().ok_or("missing token")?;
+                let subject = tokens.get(token).ok_or("unknown token")?;
+                Ok(Claims::from([("sub".to_string(), subject.clone())]))
+            }
+            Authenticator::Hmac {
+                app_id,
+                secret,
+                max_skew,
+            } => {
+                let now = chrono::Utc::now().timestamp_millis().max(0) as u64;
+                if now.abs_diff(req.client_ts) > max_skew.as_millis() as u64 {
+                    return Err("ts out of the allowed clock skew".to_string());
+                }
+
+                let sig = req.query.get("sig").ok_or("missing sig")?;
+                let data = format!(
+                    "{}\n{}\n{}\n{}",
+                    app_id, req.client_id, req.session_id, req.client_ts
+                );
+                if !verify_hmac_sha256_hex(secret, data.as_bytes(), sig) {
+                    return Err("invalid sig".to_string());
+                }
+
+                Ok(Claims::from([("sub".to_string(), req.client_id)]))
+            }
+            Authenticator::Host(authenticate) => {
+                // the host may block, keep it off the runtime workers
+                let authenticate = authenticate.clone();
+                tokio::task::spawn_blocking(move || authenticate(&req))
+                    .await
+                    .map_err(|e| format!("authenticator panicked: {}", e))?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::{
+        codec::sign::hmac_sha256_hex,
+        server::auth::{AuthRequest, Authenticator, Claims},
+    };
+
+    fn request(client_ts: u64, query: &[(&str, &str)]) -> AuthRequest {
+        let query: HashMap<String, String> = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        AuthRequest {
+            client_id: "c1".to_string(),
+            session_id: "s1".to_string(),
+            client_ts,
+            token: query.get("token").cloned(),
+            query,
+        }
+    }
+
+    fn authenticate(auth: &Authenticator, req: AuthRequest) -> Result<Claims, String> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(auth.authenticate(req))
+    }
+
+    #[test]
+    fn authenticate_token() {
+        let auth = Authenticator::Token(HashMap::from([("t1".to_string(), "u1".to_string())]));
+
+        let claims = authenticate(&auth, request(0, &[("token", "t1")]));
+        assert_eq!(
+            claims,
+            Ok(Claims::from([("sub".to_string(), "u1".to_string())]))
+        );
+        assert!(authenticate(&auth, request(0, &[("token", "t2")])).is_err());
+        assert!(authenticate(&auth, request(0, &[])).is_err());
+    }
+
+    #[test]
+    fn authenticate_hmac_within_clock_skew() {
+        let auth = Authenticator::Hmac {
+            app_id: "app".to_string(),
+            secret: b"secret".to_vec(),
+            max_skew: Duration::from_secs(60),
+        };
+        let sig = |ts: u64| hmac_sha256_hex(b"secret", format!("app\nc1\ns1\n{}", ts).as_bytes());
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let claims = authenticate(&auth, request(now, &[("sig", &sig(now))]));
+        assert_eq!(
+            claims,
+            Ok(Claims::from([("sub".to_string(), "c1".to_string())]))
+        );
+
+        // signed for another ts
+        let req = request(now, &[("sig", &sig(now + 1))]);
+        assert!(authenticate(&auth, req).is_err());
+
+        // too far from our clock either way
+        for ts in [now - 120_000, now + 120_000] {
+            let req = request(ts, &[("sig", &sig(ts))]);
+            assert!(authenticate(&auth, req).is_err());
+        }
+    }
+}
@@ -1,3 +1,10 @@
+pub mod auth;
+pub mod outbox;
+pub mod rpc;
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod session;
 pub mod socket;
+pub mod tls;
 pub mod transport;
+pub mod validate;
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+// max unacked messages in flight per session
+pub const OUTBOX_WINDOW: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i32,
+    pub payload: Vec<u8>,
+}
+
+// Outgoing messages of one client session. Messages are numbered here, at most `window`
+// of them wait for an ACK at the same time, the rest queue in `pending`. No more than
+// `capacity` messages are buffered in total.
+#[derive(Debug)]
+pub struct Outbox {
+    window: usize,
+    capacity: usize,
+    next_id: i32,
+    unacked: VecDeque<OutboxMessage>,
+    pending: VecDeque<OutboxMessage>,
+    attached: bool,
+    // bumped whenever a socket attaches, lets an expiry timer detect a resumed session
+    generation: u64,
+}
+
+impl Outbox {
+    pub fn new(window: usize, capacity: usize) -> Self {
+        Outbox {
+            window: window.min(capacity),
+            capacity,
+            next_id: 1,
+            unacked: VecDeque::new(),
+            pending: VecDeque::new(),
+            attached: false,
+            generation: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> i32 {
+        let id = self.next_id;
+        // 0 means "no ack required" on the wire
+        self.next_id = if id == i32::MAX { 1 } else { id + 1 };
+        id
+    }
+
+    // returns the message id and the message if it can be sent right away
+    pub fn push(&mut self, payload: Vec<u8>) -> (i32, Option<OutboxMessage>) {
+        let msg = OutboxMessage {
+            id: self.next_id(),
+            payload,
+        };
+        let id = msg.id;
+
+        if self.attached && self.unacked.len() < self.window {
+            self.unacked.push_back(msg.clone());
+            (id, Some(msg))
+        } else {
+            self.pending.push_back(msg);
+            (id, None)
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.unacked.len() + self.pending.len() >= self.capacity
+    }
+
+    // make room for a new message, queued messages go first since the client never saw them
+    pub fn drop_oldest(&mut self) -> Option<OutboxMessage> {
+        match self.pending.pop_front() {
+            Some(msg) => Some(msg),
+            None => self.unacked.pop_front(),
+        }
+    }
+
+    // returns the acked message and pending messages that now fit in the window
+    pub fn ack(&mut self, id: i32) -> Option<(OutboxMessage, Vec<OutboxMessage>)> {
+        let index = self.unacked.iter().position(|msg| msg.id == id)?;
+        let acked = self.unacked.remove(index)?;
+        Some((acked, self.fill_window()))
+    }
+
+    fn fill_window(&mut self) -> Vec<OutboxMessage> {
+        let mut msgs = vec![];
+        if !self.attached {
+            return msgs;
+        }
+
+        while self.unacked.len() < self.window {
+            match self.pending.pop_front() {
+                Some(msg) => {
+                    self.unacked.push_back(msg.clone());
+                    msgs.push(msg);
+                }
+                None => break,
+            }
+        }
+        msgs
+    }
+
+    // a socket took over the session, returns everything to (re)transmit
+    pub fn attach(&mut self) -> Vec<OutboxMessage> {
+        self.attached = true;
+        self.generation += 1;
+
+        let mut msgs: Vec<OutboxMessage> = self.unacked.iter().cloned().collect();
+        msgs.append(&mut self.fill_window());
+        msgs
+    }
+
+    // the socket closed, returns the generation to check against when expiring
+    pub fn detach(&mut self) -> u64 {
+        self.attached = false;
+        self.generation
+    }
+
+    pub fn is_expired(&self, generation: u64) -> bool {
+        !self.attached && self.generation == generation
+    }
+
+    // every message that was never acked
+    pub fn drain(&mut self) -> Vec<OutboxMessage> {
+        let mut msgs: Vec<OutboxMessage> = self.unacked.drain(..).collect();
+        msgs.extend(self.pending.drain(..));
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::outbox::Outbox;
+
+    #[test]
+    fn number_and_window_messages() {
+        let mut outbox = Outbox::new(2, 8);
+        assert!(outbox.attach().is_empty());
+
+        let (id1, msg1) = outbox.push(vec![1]);
+        let (id2, msg2) = outbox.push(vec![2]);
+        let (id3, msg3) = outbox.push(vec![3]);
+
+        assert_eq!((id1, id2, id3), (1, 2, 3));
+        assert_eq!(msg1.unwrap().payload, vec![1]);
+        assert_eq!(msg2.unwrap().payload, vec![2]);
+        assert!(msg3.is_none());
+
+        let (acked, next) = outbox.ack(1).unwrap();
+        assert_eq!(acked.id, 1);
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].id, 3);
+
+        // duplicated ack
+        assert!(outbox.ack(1).is_none());
+    }
+
+    #[test]
+    fn bounded_capacity() {
+        let mut outbox = Outbox::new(2, 3);
+        outbox.attach();
+
+        outbox.push(vec![1]);
+        outbox.push(vec![2]);
+        outbox.push(vec![3]);
+        assert!(outbox.is_full());
+
+        // queued message 3 goes before in-flight ones
+        assert_eq!(outbox.drop_oldest().unwrap().id, 3);
+        assert_eq!(outbox.drop_oldest().unwrap().id, 1);
+        assert!(!outbox.is_full());
+    }
+
+    #[test]
+    fn retransmit_on_attach() {
+        let mut outbox = Outbox::new(2, 8);
+        outbox.attach();
+
+        outbox.push(vec![1]);
+        outbox.push(vec![2]);
+        outbox.push(vec![3]);
+
+        let generation = outbox.detach();
+        assert!(outbox.is_expired(generation));
+
+        // queued while detached
+        let (_, msg) = outbox.push(vec![4]);
+        assert!(msg.is_none());
+
+        let msgs = outbox.attach();
+        assert!(!outbox.is_expired(generation));
+        assert_eq!(
+            msgs.iter().map(|msg| msg.id).collect::<Vec<i32>>(),
+            vec![1, 2]
+        );
+
+        outbox.ack(2);
+        outbox.ack(1);
+        assert_eq!(
+            outbox
+                .drain()
+                .iter()
+                .map(|msg| msg.id)
+                .collect::<Vec<i32>>(),
+            vec![3, 4]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+
+use super::validate::RPC_HEADER;
+use crate::{
+    codec::{event::encode_rpc_result, wire::write_str_bytes},
+    error::HfnError,
+    protocol::PacketMessage,
+};
+
+// Headers of a call, next to the `rpc` header naming the rpc. Values are decimal call ids.
+// A request carries `req`, its response `res` with the same id. A failed call has `err`
+// with the reason instead of a payload.
+pub const REQ_HEADER: &[u8] = b"req";
+pub const RES_HEADER: &[u8] = b"res";
+pub const ERR_HEADER: &[u8] = b"err";
+
+// why calls failed without a response
+pub const RPC_SOCKET_EXPIRED: &str = "socket expired";
+pub const RPC_GATEWAY_LOST: &str = "gateway connection lost";
+
+pub fn header<'a>(headers: &'a [Vec<u8>], name: &[u8]) -> Option<&'a [u8]> {
+    headers
+        .chunks_exact(2)
+        .find(|chunk| chunk[0] == name)
+        .map(|chunk| chunk[1].as_slice())
+}
+
+fn header_id(headers: &[Vec<u8>], name: &[u8]) -> Option<u32> {
+    std::str::from_utf8(header(headers, name)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+pub fn is_response(headers: &[Vec<u8>]) -> bool {
+    header(headers, RES_HEADER).is_some()
+}
+
+// host side message body of a request, see Framing::encode_host_message
+pub fn encode_request(package_id: u32, rpc_id: u32, call_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(24 + payload.len());
+    rmp::encode::write_uint(&mut data, package_id as u64).unwrap();
+    rmp::encode::write_map_len(&mut data, 2).unwrap();
+    write_str_bytes(&mut data, RPC_HEADER);
+    rmp::encode::write_str(&mut data, &rpc_id.to_string()).unwrap();
+    write_str_bytes(&mut data, REQ_HEADER);
+    rmp::encode::write_str(&mut data, &call_id.to_string()).unwrap();
+    rmp::encode::write_bin(&mut data, payload).unwrap();
+    data
+}
+
+// the answer to a request of the client, Err is the reason the call failed
+pub fn encode_response(
+    package_id: u32,
+    rpc_id: u32,
+    call_id: u32,
+    result: Result<&[u8], &str>,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(24 + result.map_or(0, |p| p.len()));
+    rmp::encode::write_uint(&mut data, package_id as u64).unwrap();
+    rmp::encode::write_map_len(&mut data, if result.is_ok() { 2 } else { 3 }).unwrap();
+    write_str_bytes(&mut data, RPC_HEADER);
+    rmp::encode::write_str(&mut data, &rpc_id.to_string()).unwrap();
+    write_str_bytes(&mut data, RES_HEADER);
+    rmp::encode::write_str(&mut data, &call_id.to_string()).unwrap();
+    if let Err(reason) = result {
+        write_str_bytes(&mut data, ERR_HEADER);
+        write_str_bytes(&mut data, reason.as_bytes());
+    }
+    rmp::encode::write_bin(&mut data, result.unwrap_or_default()).unwrap();
+    data
+}
+
+#[derive(Debug)]
+struct PendingCall {
+    socket_id: String,
+    // reports the timeout, aborted when the call ends otherwise
+    timer: Option<JoinHandle<()>>,
+}
+
+// Calls of the host waiting for the client's response. A call ends with its response,
+// its timeout or its cancellation, whichever comes first. It fails when its socket
+// expires, the gateway connection is lost or the instance shuts down.
+#[derive(Debug, Default)]
+pub struct RpcCalls {
+    next_id: AtomicU32,
+    pending: DashMap<u32, PendingCall>,
+}
+
+impl RpcCalls {
+    // registers a call to the socket and returns its id
+    pub fn start(&self, socket_id: &str) -> u32 {
+        loop {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            // 0 is never a call id, and an id wrapped around may still be pending
+            if id == 0 {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.pending.entry(id) {
+                entry.insert(PendingCall {
+                    socket_id: socket_id.to_string(),
+                    timer: None,
+                });
+                return id;
+            }
+        }
+    }
+
+    pub fn set_timer(&self, id: u32, timer: JoinHandle<()>) {
+        match self.pending.get_mut(&id) {
+            Some(mut call) => call.timer = Some(timer),
+            // answered already
+            None => timer.abort(),
+        }
+    }
+
+    // called by the timer, false if the call ended before
+    pub fn expire(&self, id: u32) -> bool {
+        self.pending.remove(&id).is_some()
+    }
+
+    // false if the call ended before
+    pub fn cancel(&self, id: u32) -> bool {
+        match self.pending.remove(&id) {
+            Some((_, call)) => {
+                if let Some(timer) = call.timer {
+                    timer.abort();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // A response of the socket, returns the event for the host when it ends a pending
+    // call of that socket. None for anything else, late responses and unknown call ids
+    // included, which the host then gets as a plain message.
+    pub fn complete(&self, socket_id: &str, msg: &PacketMessage) -> Option<Vec<u8>> {
+        let id = header_id(&msg.headers, RES_HEADER)?;
+        let (_, call) = self
+            .pending
+            .remove_if(&id, |_, call| call.socket_id == socket_id)?;
+        if let Some(timer) = call.timer {
+            timer.abort();
+        }
+
+        let event = match header(&msg.headers, ERR_HEADER) {
+            Some(reason) => {
+                let err = HfnError::RpcFailed(String::from_utf8_lossy(reason).into_owned());
+                encode_rpc_result(socket_id, id, Err(&err))
+            }
+            None => encode_rpc_result(socket_id, id, Ok(&msg.payload)),
+        };
+        Some(event)
+    }
+
+    // the socket expired, none of its calls can be answered any more
+    pub fn fail_socket(&self, socket_id: &str, reason: &str) -> Vec<Vec<u8>> {
+        let ids = self
+            .pending
+            .iter()
+            .filter(|call| call.socket_id == socket_id)
+            .map(|call| *call.key())
+            .collect();
+        self.fail(ids, reason)
+    }
+
+    pub fn fail_all(&self, reason: &str) -> Vec<Vec<u8>> {
+        let ids = self.pending.iter().map(|call| *call.key()).collect();
+        self.fail(ids, reason)
+    }
+
+    // the result events of the calls that were still pending
+    fn fail(&self, ids: Vec<u32>, reason: &str) -> Vec<Vec<u8>> {
+        let err = HfnError::RpcFailed(reason.to_string());
+        ids.into_iter()
+            .filter_map(|id| {
+                let (_, call) = self.pending.remove(&id)?;
+                if let Some(timer) = call.timer {
+                    timer.abort();
+                }
+                Some(encode_rpc_result(&call.socket_id, id, Err(&err)))
+            })
+            .collect()
+    }
+}
+
+pub async fn report_failed_calls(read_tx: &Sender<Vec<u8>>, events: Vec<Vec<u8>>) {
+    for event in events {
+        let _ = read_tx.send(event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        codec::{event::EVENT_RPC_RESULT, wire::read_headers},
+        protocol::PacketMessage,
+        server::rpc::*,
+    };
+
+    fn message(data: &[u8]) -> PacketMessage {
+        let mut cur = Cursor::new(data);
+        let pkg_id = rmp::decode::read_int(&mut cur).unwrap();
+        let headers = read_headers(&mut cur).unwrap();
+        let len = rmp::decode::read_bin_len(&mut cur).unwrap() as usize;
+        let start = cur.position() as usize;
+        PacketMessage {
+            pkg_id,
+            headers,
+            payload: data[start..start + len].to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn correlate_responses() {
+        let calls = RpcCalls::default();
+        let id = calls.start("s1");
+        assert_ne!(calls.start("s1"), id);
+
+        let request = message(&encode_request(1, 4, id, &[0x90]));
+        assert_eq!(header(&request.headers, RPC_HEADER), Some(&b"4"[..]));
+        assert_eq!(header_id(&request.headers, REQ_HEADER), Some(id));
+        assert!(!is_response(&request.headers));
+
+        // only the socket called can answer
+        let response = message(&encode_response(1, 4, id, Ok(&[0xc3])));
+        assert!(is_response(&response.headers));
+        assert_eq!(calls.complete("s2", &response), None);
+
+        let event = calls.complete("s1", &response).unwrap();
+        assert_eq!(event[0], EVENT_RPC_RESULT);
+        assert_eq!(event.last(), Some(&0xc3));
+        // late
+        assert_eq!(calls.complete("s1", &response), None);
+
+        let id = calls.start("s1");
+        let response = message(&encode_response(1, 4, id, Err("no such user")));
+        assert_eq!(response.payload, Vec::<u8>::new());
+        let event = calls.complete("s1", &response).unwrap();
+        let (socket_id, mut rest) = rmp::decode::read_str_from_slice(&event[1..]).unwrap();
+        assert_eq!(socket_id, "s1");
+        assert_eq!(rmp::decode::read_int::<u32, _>(&mut rest).unwrap(), id);
+        assert_eq!(rmp::decode::read_int::<u16, _>(&mut rest).unwrap(), 20);
+
+        let id = calls.start("s1");
+        assert!(calls.cancel(id));
+        assert!(!calls.cancel(id));
+        assert!(!calls.expire(id));
+
+        // calls of an expired socket fail, the others stay
+        let calls = RpcCalls::default();
+        let (id, other) = (calls.start("s1"), calls.start("s2"));
+        let events = calls.fail_socket("s1", "socket closed");
+        assert_eq!(events.len(), 1);
+        let (_, mut rest) = rmp::decode::read_str_from_slice(&events[0][1..]).unwrap();
+        assert_eq!(rmp::decode::read_int::<u32, _>(&mut rest).unwrap(), id);
+        assert!(!calls.cancel(id));
+
+        assert_eq!(calls.fail_all("shutting down").len(), 1);
+        assert!(!calls.cancel(other));
+    }
+}
@@ -1,29 +1,95 @@
-use std::{collections::HashMap, convert::Infallible};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use futures_util::{SinkExt, StreamExt};
 use hyper::{
+    header::AUTHORIZATION,
+    server::{accept::Accept, conn::AddrIncoming},
     service::{make_service_fn, service_fn},
+    upgrade::Upgraded,
     Body, Request, Response, Server as HyperServer, StatusCode,
 };
-use rusty_ulid::generate_ulid_string;
-use tokio::sync::mpsc;
+use hyper_tungstenite::WebSocketStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch},
+    time::{interval, sleep, MissedTickBehavior},
+};
 
-use crate::{APP_ID, READ_CHAN_TX, SOCKET_CHANS};
+use crate::{
+    codec::{
+        event::{encode_disconnect, encode_error, ConnectEvent},
+        payload::PayloadCodec,
+    },
+    protocol::{
+        compress::{CompressMethod, Compression},
+        negotiate_version, Framing, Packet, PacketRetry,
+    },
+};
 
-use super::socket::{Action, Socket};
+use super::{
+    auth::{AuthRequest, Authenticator},
+    rpc::{report_failed_calls, RpcCalls, RPC_SOCKET_EXPIRED},
+    session::{attach_session, detach_session, expire_session, report_undelivered, Sessions},
+    socket::{Socket, SocketChans, SocketConfig, SocketTx},
+    tls::{accept_tls, TlsReloader, TLS_RELOAD_INTERVAL},
+    transport::Transport,
+};
+
+// how long a closed socket waits for its session to reconnect
+pub const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 pub struct Server {
-    pub addr: String,
+    pub addr: SocketAddr,
+    pub app_id: String,
+    pub read_tx: mpsc::Sender<Vec<u8>>,
+    pub socket_chans: Arc<SocketChans>,
+    pub sessions: Arc<Sessions>,
+    pub session_grace_period: Duration,
+    pub socket_config: SocketConfig,
+    // live connections allowed, clients over it are told to retry
+    pub max_connections: Option<usize>,
+    // seconds in the RETRY sent over the cap
+    pub connection_retry_delay: u8,
+    pub connections: AtomicUsize,
+    pub authenticator: Authenticator,
+    // strict mode, see validate_message
+    pub payload_validator: Option<Arc<PayloadCodec>>,
+    pub rpc_calls: Arc<RpcCalls>,
+    // serve https and wss only
+    pub tls: Option<Arc<TlsReloader>>,
+    pub shutdown_rx: watch::Receiver<bool>,
+}
+
+// A connection counted against max_connections, released on drop
+struct ConnectionSlot(Arc<Server>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Server {
-    pub async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    pub async fn handle_request(
+        self: Arc<Self>,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
         if request.uri().path().eq("/hfn") {
             let bad_request = || {
                 println!("bad request");
-                return Ok(Response::builder()
+                Ok(Response::builder()
                     .status(400)
                     .body(Body::from("Bad Request"))
-                    .unwrap());
+                    .unwrap())
             };
 
             if !hyper_tungstenite::is_upgrade_request(&request) {
@@ -35,7 +101,7 @@ impl Server {
                 None => return bad_request(),
             };
 
-            let query: HashMap<String, String> = url::form_urlencoded::parse(&qs.as_bytes())
+            let query: HashMap<String, String> = url::form_urlencoded::parse(qs.as_bytes())
                 .into_owned()
                 .collect();
 
@@ -67,6 +133,25 @@ impl Server {
                 None => return bad_request(),
             };
 
+            // optional, clients without it speak the unversioned protocol
+            let protocol_version = match negotiate_version(query.get("pv").map(|v| v.as_str())) {
+                Some(v) => v,
+                None => return bad_request(),
+            };
+
+            // the client lists what it can inflate in `compress`, from version 2
+            let mut framing = Framing::client(protocol_version);
+            if let Some(min_size) = self.socket_config.compress_size {
+                if framing.carries_compression() {
+                    framing = framing.with_compression(Compression {
+                        method: CompressMethod::negotiate(
+                            query.get("compress").map(|v| v.as_str()),
+                        ),
+                        min_size,
+                    });
+                }
+            }
+
             if app_id.len() > 64
                 || client_id.len() > 64
                 || session_id.len() > 64
@@ -75,10 +160,34 @@ impl Server {
                 return bad_request();
             }
 
-            if app_id.ne(unsafe { &APP_ID }) {
+            if app_id.ne(&self.app_id) {
                 return bad_request();
             }
 
+            let token = query.get("token").cloned().or_else(|| {
+                let authorization = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+                Some(authorization.strip_prefix("Bearer ")?.to_string())
+            });
+            let auth_request = AuthRequest {
+                client_id: client_id.clone(),
+                session_id: session_id.clone(),
+                client_ts,
+                token,
+                query,
+            };
+            let claims = match self.authenticator.authenticate(auth_request).await {
+                Ok(v) => Arc::new(v),
+                Err(e) => {
+                    println!("unauthorized: {}", e);
+                    return Ok(Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .body(Body::from("Unauthorized"))
+                        .unwrap());
+                }
+            };
+
+            let slot = self.clone().acquire_connection();
+
             let (response, websocket) = match hyper_tungstenite::upgrade(request, None) {
                 Ok(v) => v,
                 Err(_) => return bad_request(),
@@ -87,38 +196,101 @@ impl Server {
             tokio::spawn(async move {
                 let stream = match websocket.await {
                     Ok(v) => v,
-                    Err(_) => {
-                        return ();
+                    Err(_) => return,
+                };
+
+                // shutting down, don't register new sockets
+                if *self.shutdown_rx.borrow() {
+                    return;
+                }
+
+                // over the connection cap, the client comes back later
+                let slot = match slot {
+                    Some(v) => v,
+                    None => {
+                        let delay = self.connection_retry_delay;
+                        Server::send_retry(stream, framing, delay).await;
+                        return;
                     }
                 };
 
+                let read_chan_tx = self.read_tx.clone();
+                let (socket_tx, actions_rx, close_rx) =
+                    SocketTx::channel(self.socket_config.chan_capacity);
+
+                let session = attach_session(
+                    &self.socket_chans,
+                    &self.sessions,
+                    &client_id,
+                    &session_id,
+                    socket_tx.clone(),
+                    claims,
+                    &self.socket_config,
+                );
+
                 let socket = Socket {
-                    id: generate_ulid_string(),
+                    id: session.socket_id,
                     client_id,
                     session_id,
                     client_ts,
                     client_version,
+                    outbox: session.outbox,
+                    stats: session.stats,
+                    config: self.socket_config,
+                    framing,
+                    validator: self.payload_validator.clone(),
+                    rpc_calls: self.rpc_calls.clone(),
                 };
 
-                let read_chan_tx = READ_CHAN_TX.get().unwrap().clone();
-                let (socket_write_chan_tx, socket_write_chan_rx) =
-                    mpsc::unbounded_channel::<Action>();
+                let connect_event = ConnectEvent {
+                    socket_id: &socket.id,
+                    client_id: &socket.client_id,
+                    session_id: &socket.session_id,
+                    client_version: &socket.client_version,
+                    client_ts: socket.client_ts,
+                    resumed: session.resumed,
+                };
+                let _ = read_chan_tx.send(connect_event.to_buf()).await;
+                let disconnect_tx = read_chan_tx.clone();
+                let own_tx = socket_tx.clone();
 
-                let socket_id = socket.id.clone();
-                let socket_chans = SOCKET_CHANS.get().unwrap();
-                socket_chans.insert(socket_id.clone(), socket_write_chan_tx.clone());
+                let reason = socket
+                    .accept_ws(stream, read_chan_tx, socket_tx, actions_rx, close_rx)
+                    .await;
+                drop(slot);
 
-                socket
-                    .accept_ws(
-                        stream,
-                        read_chan_tx,
-                        socket_write_chan_tx,
-                        socket_write_chan_rx,
+                // another connection resumed the session, it owns the socket now
+                let generation = match detach_session(&self.socket_chans, &socket.id, &own_tx) {
+                    Some(v) => v,
+                    None => return,
+                };
+                let expire = || {
+                    expire_session(
+                        &self.socket_chans,
+                        &self.sessions,
+                        &socket.id,
+                        Some(generation),
                     )
-                    .await;
+                };
+                let disconnect = encode_disconnect(&socket.id, reason);
 
-                // clean up
-                socket_chans.remove(&socket_id);
+                let undelivered = if reason.keeps_session() {
+                    let _ = disconnect_tx.send(disconnect).await;
+                    sleep(self.session_grace_period).await;
+                    // the session did not come back, give up on its messages
+                    expire()
+                } else {
+                    // nobody resumes a session the server closed on purpose, it is gone
+                    // by the time the host hears of the disconnect
+                    let undelivered = expire();
+                    let _ = disconnect_tx.send(disconnect).await;
+                    undelivered
+                };
+                if let Some(msgs) = undelivered {
+                    report_undelivered(&disconnect_tx, &socket.id, msgs).await;
+                    let failed = self.rpc_calls.fail_socket(&socket.id, RPC_SOCKET_EXPIRED);
+                    report_failed_calls(&disconnect_tx, failed).await;
+                }
             });
 
             // Return the response so the spawned future can continue.
@@ -130,16 +302,92 @@ impl Server {
             Ok(response)
         }
     }
-    pub async fn listen(&self) {
-        let addr: std::net::SocketAddr = self.addr.parse().expect("fail to parse addr");
 
-        let server = HyperServer::bind(&addr).serve(make_service_fn(|_| async {
-            Ok::<_, Infallible>(service_fn(Server::handle_request))
+    fn acquire_connection(self: Arc<Self>) -> Option<ConnectionSlot> {
+        let max_connections = self.max_connections.unwrap_or(usize::MAX);
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max_connections).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot(self))
+    }
+
+    async fn send_retry(stream: WebSocketStream<Upgraded>, framing: Framing, delay: u8) {
+        let (mut sink, _) = stream.split();
+        let retry = Packet::RETRY(PacketRetry { delay });
+        let _ = Transport::send_packet(&mut sink, framing, &retry).await;
+        let _ = sink.close().await;
+    }
+
+    pub async fn listen(self: Arc<Self>, incoming: AddrIncoming) {
+        match self.tls.clone() {
+            Some(tls) => {
+                tokio::spawn(self.clone().reload_tls(tls.clone()));
+                self.serve(accept_tls(incoming, tls)).await
+            }
+            None => self.serve(incoming).await,
+        }
+    }
+
+    async fn serve<I>(self: Arc<Self>, incoming: I)
+    where
+        I: Accept,
+        I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let server = self.clone();
+        let server = HyperServer::builder(incoming).serve(make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    server.clone().handle_request(request)
+                }))
+            }
         }));
 
+        // stop accepting connections once shutdown is signaled
+        let server = server.with_graceful_shutdown(async move {
+            while !*shutdown_rx.borrow() {
+                if shutdown_rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+
         println!("Listening on {}", self.addr);
         if let Err(e) = server.await {
             eprintln!("server error: {}", e);
         }
     }
+
+    // Pick up renewed certificates. One that fails to load is reported to the host
+    // and the listener keeps the previous one.
+    async fn reload_tls(self: Arc<Self>, tls: Arc<TlsReloader>) {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let mut ticker = interval(TLS_RELOAD_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+
+        while !*shutdown_rx.borrow() {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                changed = shutdown_rx.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+
+            match tls.reload_if_changed() {
+                Ok(true) => println!("tls certificate reloaded"),
+                Ok(false) => {}
+                Err(e) => {
+                    let _ = self.read_tx.send(encode_error("", &e)).await;
+                }
+            }
+        }
+    }
 }
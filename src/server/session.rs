@@ -0,0 +1,318 @@
+use std::sync::{Arc, Mutex};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use rusty_ulid::generate_ulid_string;
+use tokio::sync::mpsc::Sender;
+
+use crate::codec::event::{encode_delivery, DisconnectReason};
+
+use super::{
+    auth::Claims,
+    outbox::{Outbox, OutboxMessage},
+    socket::{SocketChan, SocketChans, SocketConfig, SocketStats, SocketTx},
+};
+
+// (client id, session id) -> logical socket id
+pub type Sessions = DashMap<(String, String), String>;
+
+pub struct AttachedSession {
+    pub socket_id: String,
+    pub outbox: Arc<Mutex<Outbox>>,
+    pub stats: Arc<SocketStats>,
+    // an offline socket of the same session was taken over
+    pub resumed: bool,
+}
+
+// Bind a new connection to its session. A client reconnecting with the same
+// client id and session id gets its previous socket id and outbox back, as long as it
+// authenticated as the same identity. Anyone else knowing the ids gets a fresh session
+// that can't be resumed, the ids stay with the identity that created them.
+pub fn attach_session(
+    socket_chans: &SocketChans,
+    sessions: &Sessions,
+    client_id: &str,
+    session_id: &str,
+    tx: SocketTx,
+    claims: Arc<Claims>,
+    config: &SocketConfig,
+) -> AttachedSession {
+    // holding the entry serializes concurrent connects of the same session
+    let entry = sessions.entry((client_id.to_string(), session_id.to_string()));
+
+    // another identity holds the ids
+    let mut claimed = false;
+    if let Entry::Occupied(entry) = &entry {
+        let socket_id = entry.get().clone();
+        if let Some(mut socket_chan) = socket_chans.get_mut(&socket_id) {
+            claimed = socket_chan.claims != claims;
+            if !claimed {
+                // the old connection has not noticed it is gone yet, take over
+                if let Some(old_tx) = socket_chan.tx.replace(tx.clone()) {
+                    old_tx.close(DisconnectReason::ConnectionLost, "session resumed");
+                }
+
+                return AttachedSession {
+                    socket_id,
+                    outbox: socket_chan.outbox.clone(),
+                    stats: socket_chan.stats.clone(),
+                    resumed: true,
+                };
+            }
+        }
+    }
+
+    let socket_id = generate_ulid_string();
+    let outbox = Arc::new(Mutex::new(Outbox::new(
+        config.window(),
+        config.chan_capacity,
+    )));
+    let stats = Arc::new(SocketStats::default());
+    socket_chans.insert(
+        socket_id.clone(),
+        SocketChan {
+            client_id: client_id.to_string(),
+            session_id: session_id.to_string(),
+            slow_consumer_policy: config.slow_consumer_policy,
+            tx: Some(tx),
+            outbox: outbox.clone(),
+            stats: stats.clone(),
+            claims,
+        },
+    );
+    if !claimed {
+        entry.insert(socket_id.clone());
+    }
+
+    AttachedSession {
+        socket_id,
+        outbox,
+        stats,
+        resumed: false,
+    }
+}
+
+// The connection closed. Returns the outbox generation if it still owned the socket,
+// None when another connection took the session over.
+pub fn detach_session(socket_chans: &SocketChans, socket_id: &str, tx: &SocketTx) -> Option<u64> {
+    let mut socket_chan = socket_chans.get_mut(socket_id)?;
+    if !socket_chan.tx.as_ref()?.same_channel(tx) {
+        return None;
+    }
+
+    socket_chan.tx = None;
+    let generation = socket_chan.outbox.lock().unwrap().detach();
+    Some(generation)
+}
+
+// Drop an offline socket, returns its undelivered messages. With a generation nothing
+// happens if the session was resumed after that generation.
+pub fn expire_session(
+    socket_chans: &SocketChans,
+    sessions: &Sessions,
+    socket_id: &str,
+    generation: Option<u64>,
+) -> Option<Vec<OutboxMessage>> {
+    let removed = socket_chans.remove_if(socket_id, |_, socket_chan| {
+        socket_chan.tx.is_none()
+            && generation
+                .is_none_or(|generation| socket_chan.outbox.lock().unwrap().is_expired(generation))
+    });
+
+    let (_, socket_chan) = removed?;
+
+    sessions.remove_if(
+        &(
+            socket_chan.client_id.clone(),
+            socket_chan.session_id.clone(),
+        ),
+        |_, id| id == socket_id,
+    );
+
+    let msgs = socket_chan.outbox.lock().unwrap().drain();
+    Some(msgs)
+}
+
+pub async fn report_undelivered(
+    read_tx: &Sender<Vec<u8>>,
+    socket_id: &str,
+    msgs: Vec<OutboxMessage>,
+) {
+    for msg in msgs {
+        let _ = read_tx
+            .send(encode_delivery(socket_id, msg.id, false))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codec::SlowConsumerPolicy,
+        server::{
+            session::*,
+            socket::{SocketChans, SocketConfig, SocketTx},
+        },
+    };
+
+    const CONFIG: SocketConfig = SocketConfig {
+        chan_capacity: 16,
+        slow_consumer_policy: SlowConsumerPolicy::DropNewest,
+        ping_interval: 25,
+        ping_timeout: 20,
+        compress_size: None,
+    };
+
+    #[test]
+    fn resume_offline_session() {
+        let socket_chans = SocketChans::new();
+        let sessions = Sessions::new();
+
+        let (tx1, _, _rx1) = SocketTx::channel(8);
+        let first = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx1.clone(),
+            Arc::default(),
+            &CONFIG,
+        );
+        assert!(!first.resumed);
+
+        let generation = detach_session(&socket_chans, &first.socket_id, &tx1).unwrap();
+
+        let (tx2, _, _rx2) = SocketTx::channel(8);
+        let second = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx2.clone(),
+            Arc::default(),
+            &CONFIG,
+        );
+        assert!(second.resumed);
+        assert_eq!(first.socket_id, second.socket_id);
+
+        // the expiry timer of the first connection must not drop the resumed session
+        assert!(
+            expire_session(&socket_chans, &sessions, &first.socket_id, Some(generation)).is_none()
+        );
+
+        // same session id from another client is a different session
+        let (tx3, _, _rx3) = SocketTx::channel(8);
+        let other = attach_session(
+            &socket_chans,
+            &sessions,
+            "c2",
+            "s1",
+            tx3,
+            Arc::default(),
+            &CONFIG,
+        );
+        assert!(!other.resumed);
+        assert_ne!(other.socket_id, first.socket_id);
+
+        second.outbox.lock().unwrap().push(vec![1]);
+        let generation = detach_session(&socket_chans, &second.socket_id, &tx2).unwrap();
+        let undelivered = expire_session(
+            &socket_chans,
+            &sessions,
+            &second.socket_id,
+            Some(generation),
+        )
+        .unwrap();
+        assert_eq!(undelivered.len(), 1);
+        assert!(socket_chans.get(&second.socket_id).is_none());
+    }
+
+    #[test]
+    fn take_over_online_session() {
+        let socket_chans = SocketChans::new();
+        let sessions = Sessions::new();
+
+        let (tx1, _, mut close_rx1) = SocketTx::channel(8);
+        let first = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx1.clone(),
+            Arc::default(),
+            &CONFIG,
+        );
+
+        let (tx2, _, _rx2) = SocketTx::channel(8);
+        let second = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx2,
+            Arc::default(),
+            &CONFIG,
+        );
+        assert!(second.resumed);
+        assert_eq!(first.socket_id, second.socket_id);
+
+        // the old connection is told to close and no longer owns the socket
+        assert!(close_rx1.try_recv().is_ok());
+        assert!(detach_session(&socket_chans, &first.socket_id, &tx1).is_none());
+    }
+
+    #[test]
+    fn resume_only_as_the_same_identity() {
+        let socket_chans = SocketChans::new();
+        let sessions = Sessions::new();
+        let claims = |sub: &str| Arc::new(Claims::from([("sub".to_string(), sub.to_string())]));
+
+        let (tx1, _, mut close_rx1) = SocketTx::channel(8);
+        let first = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx1.clone(),
+            claims("alice"),
+            &CONFIG,
+        );
+        first.outbox.lock().unwrap().push(vec![1]);
+
+        let (tx2, _, _rx2) = SocketTx::channel(8);
+        let other = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx2,
+            claims("mallory"),
+            &CONFIG,
+        );
+        assert!(!other.resumed);
+        assert_ne!(other.socket_id, first.socket_id);
+        assert!(other.outbox.lock().unwrap().drain().is_empty());
+
+        // the first connection keeps its socket, outbox and claims
+        assert!(close_rx1.try_recv().is_err());
+        assert_eq!(
+            socket_chans.get(&first.socket_id).unwrap().claims,
+            claims("alice")
+        );
+
+        // and still resumes with its buffered message
+        detach_session(&socket_chans, &first.socket_id, &tx1).unwrap();
+        let (tx3, _, _rx3) = SocketTx::channel(8);
+        let resumed = attach_session(
+            &socket_chans,
+            &sessions,
+            "c1",
+            "s1",
+            tx3,
+            claims("alice"),
+            &CONFIG,
+        );
+        assert!(resumed.resumed);
+        assert_eq!(resumed.socket_id, first.socket_id);
+        assert_eq!(resumed.outbox.lock().unwrap().attach().len(), 1);
+    }
+}
@@ -1,17 +1,179 @@
 use std::{
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
-use futures_util::StreamExt;
+use dashmap::DashMap;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use hyper::upgrade::Upgraded;
-use hyper_tungstenite::WebSocketStream;
+use hyper_tungstenite::{
+    tungstenite::{Error, Message},
+    WebSocketStream,
+};
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    time::sleep,
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    time::{sleep, Instant},
+};
+
+use crate::{
+    codec::{
+        event::{encode_delivery, encode_error, encode_message, DisconnectReason},
+        payload::PayloadCodec,
+        SlowConsumerPolicy,
+    },
+    error::HfnError,
+    protocol::{Framing, Packet, PacketClose},
 };
 
-use super::transport::{Packet, PacketMessage, Transport};
+use super::{
+    auth::Claims,
+    outbox::{Outbox, OutboxMessage, OUTBOX_WINDOW},
+    rpc::RpcCalls,
+    transport::Transport,
+    validate::validate_message,
+};
+
+const SLOW_CONSUMER_REASON: &str = "slow consumer";
+
+// Settings shared by every socket of a server
+#[derive(Debug, Clone, Copy)]
+pub struct SocketConfig {
+    // outgoing messages buffered per socket, also the action chan capacity
+    pub chan_capacity: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // seconds, announced to the client in the OPEN packet
+    pub ping_interval: u8,
+    pub ping_timeout: u8,
+    // kb, payloads over it are compressed for clients that support it, None disables
+    pub compress_size: Option<u8>,
+}
+
+impl SocketConfig {
+    // unacked messages in flight, half the action chan is left for acks and pings
+    pub fn window(&self) -> usize {
+        OUTBOX_WINDOW.min(self.chan_capacity / 2).max(1)
+    }
+}
+
+// Write side of one live connection
+#[derive(Debug, Clone)]
+pub struct SocketTx {
+    pub actions: Sender<Action>,
+    // kept apart so a close gets through even when actions are backed up
+    pub close: Sender<ActionClose>,
+}
+
+impl SocketTx {
+    pub fn channel(capacity: usize) -> (Self, Receiver<Action>, Receiver<ActionClose>) {
+        let (actions, actions_rx) = mpsc::channel::<Action>(capacity);
+        let (close, close_rx) = mpsc::channel::<ActionClose>(1);
+        (SocketTx { actions, close }, actions_rx, close_rx)
+    }
+
+    pub fn close(&self, cause: DisconnectReason, reason: &str) -> bool {
+        let packet = Packet::CLOSE(PacketClose {
+            reason: reason.to_string(),
+        });
+        self.close_with(cause, packet)
+    }
+
+    // close with RETRY, RESET or REDIRECT instead of a CLOSE packet
+    pub fn close_with(&self, cause: DisconnectReason, packet: Packet) -> bool {
+        match self.close.try_send(ActionClose { cause, packet }) {
+            Ok(_) => true,
+            // a close is already on its way
+            Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    pub fn same_channel(&self, other: &SocketTx) -> bool {
+        self.actions.same_channel(&other.actions)
+    }
+
+    // queue a message that fits the window, a connection that can't take it is too slow
+    fn send_message(&self, msg: OutboxMessage) {
+        if let Err(TrySendError::Full(_)) = self.actions.try_send(Action::SendMessage(msg.into())) {
+            // the message stays unacked in the outbox for a resumed session
+            self.close(DisconnectReason::SlowConsumer, SLOW_CONSUMER_REASON);
+        }
+    }
+}
+
+pub struct SocketChan {
+    pub client_id: String,
+    pub session_id: String,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // None while the client is offline and the session waits for it to resume
+    pub tx: Option<SocketTx>,
+    pub outbox: Arc<Mutex<Outbox>>,
+    pub stats: Arc<SocketStats>,
+    // the identity that created the session, only it can resume
+    pub claims: Arc<Claims>,
+}
+
+impl SocketChan {
+    // Queue a message for delivery, returns the message id and, with the drop oldest
+    // policy, the message dropped to make room for it.
+    pub fn send_message(
+        &self,
+        socket_id: &str,
+        payload: Vec<u8>,
+    ) -> Result<(i32, Option<OutboxMessage>), HfnError> {
+        // keep the lock while sending so messages hit the wire in id order
+        let mut outbox = self.outbox.lock().unwrap();
+
+        let mut dropped = None;
+        if outbox.is_full() {
+            match self.slow_consumer_policy {
+                SlowConsumerPolicy::DropOldest => dropped = outbox.drop_oldest(),
+                SlowConsumerPolicy::DropNewest => {
+                    return Err(HfnError::Full(socket_id.to_string()))
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    self.close(DisconnectReason::SlowConsumer, SLOW_CONSUMER_REASON);
+                    return Err(HfnError::Full(socket_id.to_string()));
+                }
+            }
+        }
+
+        let (id, msg) = outbox.push(payload);
+        if let (Some(msg), Some(tx)) = (msg, &self.tx) {
+            tx.send_message(msg);
+        }
+        Ok((id, dropped))
+    }
+
+    // returns false if there is no live connection to close
+    pub fn close(&self, cause: DisconnectReason, reason: &str) -> bool {
+        match &self.tx {
+            Some(tx) => tx.close(cause, reason),
+            None => false,
+        }
+    }
+
+    pub fn close_with(&self, cause: DisconnectReason, packet: Packet) -> bool {
+        match &self.tx {
+            Some(tx) => tx.close_with(cause, packet),
+            None => false,
+        }
+    }
+}
+
+// Per logical socket, kept across resumed connections
+#[derive(Debug, Default)]
+pub struct SocketStats {
+    // round trip time of the last answered PING
+    pub rtt: Mutex<Option<Duration>>,
+    // malformed frames received, each one closes the connection
+    pub protocol_violations: AtomicU64,
+}
+
+// logical socket id -> socket write chan, outlives a connection while its session can resume
+pub type SocketChans = DashMap<String, SocketChan>;
 
 #[derive(Debug)]
 pub struct Socket {
@@ -20,172 +182,341 @@ pub struct Socket {
     pub session_id: String,
     pub client_ts: u64,
     pub client_version: String,
+    pub outbox: Arc<Mutex<Outbox>>,
+    pub stats: Arc<SocketStats>,
+    pub config: SocketConfig,
+    // client profile at the version negotiated in the handshake
+    pub framing: Framing,
+    // strict mode, messages not matching their schema are rejected
+    pub validator: Option<Arc<PayloadCodec>>,
+    // responses to the host's rpc calls end them instead of reaching the host as messages
+    pub rpc_calls: Arc<RpcCalls>,
+}
+
+// Where the current PING is. The heartbeat queues it, the writer stamps it when it goes
+// out and the PONG answers it, so neither the RTT nor the timeout count time spent in
+// the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ping {
+    Idle,
+    Queued,
+    Sent(Instant),
 }
 
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Action {
     SendOpen(ActionSendOpen),
     SendPing(ActionSendPing),
+    SendPong(ActionSendPong),
     SendMessage(ActionSendMessage),
-    Close(ActionClose),
+    SendAck(ActionSendAck),
+    SendReject(ActionSendReject),
 }
 
 #[derive(Debug)]
 pub struct ActionSendOpen {
-    pub ping_interval: i64,
-    pub ping_timeout: i64,
+    pub ping_interval: u8,
+    pub ping_timeout: u8,
 }
 
 #[derive(Debug)]
 pub struct ActionSendPing {}
 
+#[derive(Debug)]
+pub struct ActionSendPong {}
+
 #[derive(Debug)]
 pub struct ActionSendMessage {
+    pub id: i32,
     pub payload: Vec<u8>,
 }
 
-pub struct ActionClose {}
+impl From<OutboxMessage> for ActionSendMessage {
+    fn from(msg: OutboxMessage) -> Self {
+        ActionSendMessage {
+            id: msg.id,
+            payload: msg.payload,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ActionSendAck {
+    pub id: i32,
+    pub pkg_id: i32,
+}
+
+#[derive(Debug)]
+pub struct ActionSendReject {
+    pub packet: Packet,
+}
+
+#[derive(Debug)]
+pub struct ActionClose {
+    pub cause: DisconnectReason,
+    // last packet the client gets, CLOSE with a reason or RETRY / RESET / REDIRECT
+    pub packet: Packet,
+}
 
 impl Socket {
     pub async fn accept_ws(
         &self,
         stream: WebSocketStream<Upgraded>,
-        read_chan_tx: UnboundedSender<Vec<u8>>,
-        socket_write_chan_tx: UnboundedSender<Action>,
-        mut socket_write_chan_rx: UnboundedReceiver<Action>,
-    ) {
+        read_chan_tx: Sender<Vec<u8>>,
+        socket_tx: SocketTx,
+        mut actions_rx: Receiver<Action>,
+        mut close_rx: Receiver<ActionClose>,
+    ) -> DisconnectReason {
         let (mut sink, mut stream) = stream.split();
 
-        socket_write_chan_tx.send(Action::SendOpen(ActionSendOpen {
-            ping_interval: 25,
-            ping_timeout: 20,
+        let _ = socket_tx.actions.try_send(Action::SendOpen(ActionSendOpen {
+            ping_interval: self.config.ping_interval,
+            ping_timeout: self.config.ping_timeout,
         }));
 
-        let (close_tx, mut close_rx) = mpsc::channel::<()>(1);
+        // retransmit what a previous socket of this session left unacked
+        {
+            let mut outbox = self.outbox.lock().unwrap();
+            for msg in outbox.attach() {
+                socket_tx.send_message(msg);
+            }
+        }
+
+        let (disconnect_tx, mut disconnect_rx) = mpsc::channel::<DisconnectReason>(1);
 
-        let close_tx_clone = close_tx.clone();
+        let framing = self.framing;
+        let ping = Arc::new(Mutex::new(Ping::Idle));
+        let ping_clone = ping.clone();
+        let disconnect_tx_clone = disconnect_tx.clone();
         let sink_task = tokio::spawn(async move {
-            while let Some(action) = socket_write_chan_rx.recv().await {
-                match action {
-                    Action::SendOpen(action) => {
-                        Transport::send_open_packet(
-                            &mut sink,
-                            action.ping_interval,
-                            action.ping_timeout,
-                        )
-                        .await;
-                    }
-                    Action::SendPing(action) => {
-                        Transport::send_ping_packet(&mut sink).await;
-                    }
-                    Action::SendMessage(action) => {
-                        Transport::send_message_packet(&mut sink, action.payload).await;
+            loop {
+                tokio::select! {
+                    Some(action) = actions_rx.recv() => {
+                        if Socket::write_action(&mut sink, framing, &ping_clone, action).await.is_err() {
+                            // connection is broken
+                            let _ = disconnect_tx_clone.send(DisconnectReason::ConnectionLost).await;
+                            return;
+                        }
                     }
-                    Action::Close(action) => {
-                        Transport::send_close_packet(&mut sink, "").await;
-                        close_tx_clone.send(()).await;
+                    Some(action) = close_rx.recv() => {
+                        // flush what was queued before the close
+                        while let Ok(action) = actions_rx.try_recv() {
+                            if Socket::write_action(&mut sink, framing, &ping_clone, action).await.is_err() {
+                                break;
+                            }
+                        }
+
+                        let _ = Transport::send_packet(&mut sink, framing, &action.packet).await;
+                        let _ = sink.close().await;
+                        let _ = disconnect_tx_clone.send(action.cause).await;
+                        return;
                     }
+                    else => return,
                 }
             }
         });
 
         let socket_id = self.id.clone();
-        let last_heartbeat = Arc::new(Mutex::new(chrono::Utc::now().timestamp()));
+        let outbox = self.outbox.clone();
+        let ack_tx = socket_tx.clone();
+        let stats = self.stats.clone();
+        let validator = self.validator.clone();
+        let rpc_calls = self.rpc_calls.clone();
 
-        let last_heartbeat_clone = last_heartbeat.clone();
-        let close_tx_clone = close_tx.clone();
+        let ping_clone = ping.clone();
+        let disconnect_tx_clone = disconnect_tx.clone();
 
         let stream_task = tokio::spawn(async move {
-            while let Some(packets) = Transport::next(&mut stream).await {
+            while let Some(packets) = Transport::next(&mut stream, framing).await {
+                let packets = match packets {
+                    Ok(v) => v,
+                    Err(e) => {
+                        stats.protocol_violations.fetch_add(1, Ordering::Relaxed);
+                        let err = HfnError::ProtocolViolation(e.to_string());
+                        let _ = read_chan_tx.send(encode_error(&socket_id, &err)).await;
+                        ack_tx.close(DisconnectReason::ProtocolViolation, &err.to_string());
+                        return;
+                    }
+                };
+
                 for packet in packets {
                     match packet {
-                        Packet::OPEN(open) => {
-                            // println!("socket open: {:?}", open);
-                        }
-                        Packet::CLOSE(close) => {
-                            close_tx_clone.send(()).await;
+                        Packet::CLOSE(_) => {
+                            let _ = disconnect_tx_clone
+                                .send(DisconnectReason::ClientClose)
+                                .await;
                             return;
                         }
-                        Packet::PING(ping) => {
-                            // println!("ping: {:?}", ping);
+                        Packet::MESSAGE(msg) => {
+                            let (id, pkg_id) = (msg.id, msg.pkg_id);
+                            let rejection = validator
+                                .as_ref()
+                                .and_then(|codec| validate_message(codec, &msg).err());
+                            if let Some(rejection) = rejection {
+                                let err = HfnError::InvalidPayload(rejection.to_string());
+                                let _ = read_chan_tx.send(encode_error(&socket_id, &err)).await;
+
+                                // older clients get the ack so the message isn't sent again
+                                let action = if framing.carries_reject() {
+                                    Action::SendReject(ActionSendReject {
+                                        packet: rejection.to_packet(&msg),
+                                    })
+                                } else if id != 0 {
+                                    Action::SendAck(ActionSendAck { id, pkg_id })
+                                } else {
+                                    continue;
+                                };
+                                let _ = ack_tx.actions.send(action).await;
+                                continue;
+                            }
+
+                            // anything but the response to a pending call goes to the host
+                            let data = rpc_calls.complete(&socket_id, &msg).unwrap_or_else(|| {
+                                encode_message(
+                                    socket_id.as_bytes(),
+                                    msg.pkg_id,
+                                    &msg.headers,
+                                    &msg.payload,
+                                )
+                            });
+                            // a full read chan holds back reading from this client
+                            if read_chan_tx.send(data).await.is_err() {
+                                // host stopped reading
+                                let _ = disconnect_tx_clone
+                                    .send(DisconnectReason::ServerShutdown)
+                                    .await;
+                                return;
+                            }
+
+                            // id 0 means the client doesn't need an ack
+                            if id != 0 {
+                                let _ = ack_tx
+                                    .actions
+                                    .send(Action::SendAck(ActionSendAck { id, pkg_id }))
+                                    .await;
+                            }
                         }
-                        Packet::PONG(pong) => {
-                            // println!("pong: {:?}", pong);
+                        Packet::PING(_) => {
+                            let _ = ack_tx
+                                .actions
+                                .send(Action::SendPong(ActionSendPong {}))
+                                .await;
                         }
-                        Packet::MESSAGE(msg) => {
-                            let data = Socket::encode_message(&socket_id, msg);
-                            read_chan_tx
-                                .send(data)
-                                .expect("failed to send message to read_tx");
+                        Packet::PONG(_) => {
+                            let mut ping = ping_clone.lock().unwrap();
+                            if let Ping::Sent(sent_at) = *ping {
+                                *stats.rtt.lock().unwrap() = Some(sent_at.elapsed());
+                                *ping = Ping::Idle;
+                            }
                         }
                         Packet::ACK(ack) => {
-                            // println!("ack: {:?}", ack);
+                            let acked = {
+                                let mut outbox = outbox.lock().unwrap();
+                                outbox.ack(ack.id).map(|(acked, msgs)| {
+                                    for msg in msgs {
+                                        ack_tx.send_message(msg);
+                                    }
+                                    acked
+                                })
+                            };
+
+                            if let Some(acked) = acked {
+                                let _ = read_chan_tx
+                                    .send(encode_delivery(&socket_id, acked.id, true))
+                                    .await;
+                            }
                         }
                         // nothing todo
                         _ => {}
                     }
                 }
-
-                *last_heartbeat_clone.lock().unwrap() = chrono::Utc::now().timestamp();
             }
 
-            close_tx_clone.send(()).await;
+            let _ = disconnect_tx_clone
+                .send(DisconnectReason::ConnectionLost)
+                .await;
         });
 
-        let socket_write_chan_tx = socket_write_chan_tx.clone();
+        let ping_tx = socket_tx.actions.clone();
 
-        let close_tx_clone = close_tx.clone();
+        let disconnect_tx_clone = disconnect_tx.clone();
+        let ping_interval = Duration::from_secs(self.config.ping_interval as u64);
+        let ping_timeout = Duration::from_secs(self.config.ping_timeout as u64);
         let heartbeat_task = tokio::spawn(async move {
             loop {
-                let now = chrono::Utc::now().timestamp();
-                let heartbeat_at = *last_heartbeat.lock().unwrap();
+                let round = Instant::now();
+                *ping.lock().unwrap() = Ping::Queued;
+                // skipped while the socket is backed up, the next round tries again
+                if ping_tx
+                    .try_send(Action::SendPing(ActionSendPing {}))
+                    .is_err()
+                {
+                    *ping.lock().unwrap() = Ping::Idle;
+                }
 
-                if now - heartbeat_at > 25 + 20 {
-                    close_tx_clone.send(()).await;
-                    return;
+                // only the PONG answers a PING, the timeout runs from when it went out
+                loop {
+                    let wait = match *ping.lock().unwrap() {
+                        Ping::Idle => break,
+                        Ping::Queued => ping_timeout,
+                        Ping::Sent(sent_at) => ping_timeout.saturating_sub(sent_at.elapsed()),
+                    };
+                    if wait.is_zero() {
+                        let _ = disconnect_tx_clone
+                            .send(DisconnectReason::HeartbeatTimeout)
+                            .await;
+                        return;
+                    }
+                    sleep(wait).await;
                 }
 
-                // send ping
-                socket_write_chan_tx.send(Action::SendPing(ActionSendPing {}));
-                sleep(Duration::from_secs(25)).await
+                sleep(ping_interval.saturating_sub(round.elapsed())).await;
             }
         });
 
-        close_rx.recv().await;
+        let reason = disconnect_rx
+            .recv()
+            .await
+            .unwrap_or(DisconnectReason::ConnectionLost);
 
         stream_task.abort();
         sink_task.abort();
         heartbeat_task.abort();
-    }
 
-    fn encode_message(socket_id: &str, mut msg: PacketMessage) -> Vec<u8> {
-        let mut cap = 4 + 2 + msg.payload.len() + 2 + socket_id.len();
+        reason
+    }
 
-        cap += 2;
-        if !msg.headers.is_empty() {
-            for header in &msg.headers {
-                cap += 5;
-                cap += header.len();
+    async fn write_action(
+        sink: &mut SplitSink<WebSocketStream<Upgraded>, Message>,
+        framing: Framing,
+        ping: &Mutex<Ping>,
+        action: Action,
+    ) -> Result<(), Error> {
+        match action {
+            Action::SendOpen(action) => {
+                Transport::send_open_packet(
+                    sink,
+                    framing,
+                    action.ping_interval,
+                    action.ping_timeout,
+                )
+                .await
+            }
+            Action::SendPing(_) => {
+                *ping.lock().unwrap() = Ping::Sent(Instant::now());
+                Transport::send_ping_packet(sink, framing).await
+            }
+            Action::SendPong(_) => Transport::send_pong_packet(sink, framing).await,
+            Action::SendMessage(action) => {
+                Transport::send_message_packet(sink, framing, action.id, action.payload).await
+            }
+            Action::SendAck(action) => {
+                Transport::send_ack_packet(sink, framing, action.id, action.pkg_id).await
+            }
+            Action::SendReject(action) => {
+                Transport::send_packet(sink, framing, &action.packet).await
             }
         }
-        let mut data: Vec<u8> = Vec::with_capacity(cap);
-
-        rmp::encode::write_sint(&mut data, msg.pkg_id as i64).unwrap();
-
-        if msg.headers.is_empty() {
-            rmp::encode::write_map_len(&mut data, 0).unwrap();
-        } else {
-            rmp::encode::write_map_len(&mut data, (msg.headers.len() / 2) as u32).unwrap();
-            msg.headers.chunks_mut(2).for_each(|chunk| {
-                rmp::encode::write_str_len(&mut data, chunk[0].len() as u32).unwrap();
-                data.append(&mut chunk[0]);
-                rmp::encode::write_str_len(&mut data, chunk[1].len() as u32).unwrap();
-                data.append(&mut chunk[1]);
-            });
-        }
-
-        rmp::encode::write_bin(&mut data, &msg.payload).unwrap();
-        rmp::encode::write_str(&mut data, socket_id).unwrap();
-
-        data
     }
 }
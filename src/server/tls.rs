@@ -0,0 +1,306 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use futures_util::future::poll_fn;
+use hyper::server::{
+    accept::{self, Accept},
+    conn::{AddrIncoming, AddrStream},
+};
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, NoClientAuth},
+    sign, Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme,
+};
+use rustls_pemfile::Item;
+use tokio::{sync::mpsc, time::timeout};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::error::HfnError;
+
+// how often the files are checked for changes
+pub const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+// a client that doesn't finish the handshake in time is dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsPaths {
+    // PEM certificate chain, leaf first
+    pub cert: PathBuf,
+    // PEM private key, PKCS#8, RSA or EC
+    pub key: PathBuf,
+    // PEM roots client certificates must chain to, enables mutual TLS
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsPaths {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.cert, &self.key].into_iter().chain(&self.client_ca)
+    }
+}
+
+// The listener's TLS config. Renewed certificates are picked up by reload_if_changed,
+// connections already up keep the config they were accepted with.
+pub struct TlsReloader {
+    paths: TlsPaths,
+    acceptor: RwLock<TlsAcceptor>,
+    // (modified, len) of every file when last loaded
+    stamps: Mutex<Vec<Option<(SystemTime, u64)>>>,
+}
+
+impl TlsReloader {
+    pub fn new(paths: TlsPaths) -> Result<Self, HfnError> {
+        let stamps = file_stamps(&paths);
+        let config = load_config(&paths)?;
+
+        Ok(TlsReloader {
+            paths,
+            acceptor: RwLock::new(TlsAcceptor::from(Arc::new(config))),
+            stamps: Mutex::new(stamps),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    // Ok(true) when the files changed and the new config is in use. A config that
+    // fails to load leaves the previous one in place and is tried again on the next
+    // check, a renewal may still be half written.
+    pub fn reload_if_changed(&self) -> Result<bool, HfnError> {
+        let stamps = file_stamps(&self.paths);
+        if *self.stamps.lock().unwrap() == stamps {
+            return Ok(false);
+        }
+
+        let config = load_config(&self.paths)?;
+        *self.acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(config));
+        *self.stamps.lock().unwrap() = stamps;
+        Ok(true)
+    }
+}
+
+// Handshakes run in their own tasks so a slow client can't hold up the others.
+// Connections failing the handshake never reach hyper.
+pub fn accept_tls(
+    mut incoming: AddrIncoming,
+    tls: Arc<TlsReloader>,
+) -> impl Accept<Conn = TlsStream<AddrStream>, Error = io::Error> {
+    let (tx, mut rx) = mpsc::channel::<io::Result<TlsStream<AddrStream>>>(64);
+
+    tokio::spawn(async move {
+        loop {
+            let accept = poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx));
+            let stream = tokio::select! {
+                stream = accept => stream,
+                // hyper stopped accepting
+                _ = tx.closed() => return,
+            };
+
+            let stream = match stream {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    eprintln!("accept error: {}", e);
+                    continue;
+                }
+                None => return,
+            };
+
+            let acceptor = tls.acceptor();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Ok(Ok(stream)) =
+                    timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                {
+                    let _ = tx.send(Ok(stream)).await;
+                }
+            });
+        }
+    });
+
+    accept::from_stream(futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+fn load_config(paths: &TlsPaths) -> Result<ServerConfig, HfnError> {
+    let certs: Vec<Certificate> = read_pem(&paths.cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(v) => Some(Certificate(v)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(tls_error(&paths.cert, "no certificate found"));
+    }
+
+    let key = read_pem(&paths.key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(v) | Item::RSAKey(v) | Item::ECKey(v) => Some(PrivateKey(v)),
+            _ => None,
+        })
+        .ok_or_else(|| tls_error(&paths.key, "no private key found"))?;
+    check_key_pair(&certs[0], &key).map_err(|e| tls_error(&paths.key, e))?;
+
+    let verifier = match &paths.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for item in read_pem(client_ca)? {
+                if let Item::X509Certificate(v) = item {
+                    roots
+                        .add(&Certificate(v))
+                        .map_err(|e| tls_error(client_ca, e))?;
+                }
+            }
+            if roots.is_empty() {
+                return Err(tls_error(client_ca, "no certificate found"));
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| HfnError::Tls(e.to_string()))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+// the verifiers of the schemes a key can sign with
+const KEY_CHECK_SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+// rustls only checks that the key parses, the key of another certificate would fail
+// every handshake. Whatever the key signs has to verify with the leaf's public key.
+fn check_key_pair(leaf: &Certificate, key: &PrivateKey) -> Result<(), String> {
+    let key = sign::any_supported_type(key).map_err(|_| "unsupported private key")?;
+    let schemes: Vec<SignatureScheme> = KEY_CHECK_SCHEMES.iter().map(|(s, _)| *s).collect();
+    let signer = key
+        .choose_scheme(&schemes)
+        .ok_or("unsupported private key")?;
+    let (_, alg) = KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .ok_or("unsupported private key")?;
+
+    let msg = b"hfn key check";
+    let sig = signer.sign(msg).map_err(|e| e.to_string())?;
+    webpki::EndEntityCert::try_from(leaf.0.as_slice())
+        .map_err(|e| format!("invalid certificate: {:?}", e))?
+        .verify_signature(alg, msg, &sig)
+        .map_err(|_| "private key does not match the certificate".to_string())
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, HfnError> {
+    let file = File::open(path).map_err(|e| tls_error(path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| tls_error(path, e))
+}
+
+fn tls_error(path: &Path, e: impl ToString) -> HfnError {
+    HfnError::Tls(format!("{}: {}", path.display(), e.to_string()))
+}
+
+fn file_stamps(paths: &TlsPaths) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .files()
+        .map(|path| {
+            let meta = fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        error::HfnError,
+        server::tls::{TlsPaths, TlsReloader},
+    };
+
+    // self signed for localhost, (cert, key) PEM
+    fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "hfn-{}-{}",
+            rusty_ulid::generate_ulid_string(),
+            name
+        ));
+        path
+    }
+
+    #[test]
+    fn reload_changed_certificate() {
+        let paths = TlsPaths {
+            cert: temp_file("cert.pem"),
+            key: temp_file("key.pem"),
+            client_ca: None,
+        };
+        let (cert, key) = self_signed();
+        fs::write(&paths.cert, &cert).unwrap();
+        fs::write(&paths.key, &key).unwrap();
+
+        let tls = TlsReloader::new(paths.clone()).unwrap();
+        assert!(matches!(tls.reload_if_changed(), Ok(false)));
+
+        // a half written renewal keeps the old config and is tried again
+        fs::write(&paths.cert, "").unwrap();
+        assert!(matches!(tls.reload_if_changed(), Err(HfnError::Tls(_))));
+        assert!(matches!(tls.reload_if_changed(), Err(HfnError::Tls(_))));
+
+        // the new certificate is written before its key
+        let (cert, key) = self_signed();
+        fs::write(&paths.cert, &cert).unwrap();
+        match tls.reload_if_changed() {
+            Err(HfnError::Tls(e)) => {
+                assert!(e.ends_with("private key does not match the certificate"))
+            }
+            _ => panic!("a key of another certificate should not load"),
+        }
+        fs::write(&paths.key, &key).unwrap();
+        assert!(matches!(tls.reload_if_changed(), Ok(true)));
+        assert!(matches!(tls.reload_if_changed(), Ok(false)));
+
+        // the key file holds no key
+        fs::write(&paths.key, &cert).unwrap();
+        assert!(matches!(tls.reload_if_changed(), Err(HfnError::Tls(_))));
+
+        fs::remove_file(&paths.cert).unwrap();
+        fs::remove_file(&paths.key).unwrap();
+        assert!(TlsReloader::new(paths).is_err());
+    }
+}
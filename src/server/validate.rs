@@ -0,0 +1,202 @@
+use std::fmt;
+
+use super::rpc::{header, is_response, ERR_HEADER};
+use crate::{
+    codec::payload::{MessageTarget, PayloadCodec},
+    protocol::{Packet, PacketMessage, PacketReject},
+};
+
+// headers naming what a message calls, the value is the decimal hfn or rpc id
+pub const HFN_HEADER: &[u8] = b"hfn";
+pub const RPC_HEADER: &[u8] = b"rpc";
+
+// why a message was turned away, the `code` of REJECT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RejectCode {
+    UnknownPackage = 1,
+    // no hfn or rpc header, or one the package doesn't have
+    UnknownTarget = 2,
+    InvalidPayload = 3,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: RejectCode,
+    pub path: String,
+    pub reason: String,
+}
+
+impl Rejection {
+    fn new(code: RejectCode, reason: &str) -> Self {
+        Rejection {
+            code,
+            path: String::new(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn to_packet(&self, msg: &PacketMessage) -> Packet {
+        Packet::REJECT(PacketReject {
+            id: msg.id,
+            pkg_id: msg.pkg_id,
+            code: self.code as u8,
+            path: self.path.clone(),
+            reason: self.reason.clone(),
+        })
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.reason),
+            false => write!(f, "{}: {}", self.path, self.reason),
+        }
+    }
+}
+
+// Strict mode: a client message has to call an hfn or rpc of a known package with a
+// payload matching its schema. Responses to rpc calls are checked against the response
+// schema, unless the call failed.
+pub fn validate_message(codec: &PayloadCodec, msg: &PacketMessage) -> Result<(), Rejection> {
+    let package_id = u32::try_from(msg.pkg_id)
+        .ok()
+        .filter(|id| codec.has_package(*id))
+        .ok_or_else(|| Rejection::new(RejectCode::UnknownPackage, "unknown package"))?;
+
+    let unknown_target = || Rejection::new(RejectCode::UnknownTarget, "unknown hfn or rpc");
+    let target = message_target(&msg.headers).ok_or_else(unknown_target)?;
+    let schema_id = codec
+        .target_schema(package_id, target)
+        .ok_or_else(unknown_target)?;
+    if header(&msg.headers, ERR_HEADER).is_some() && is_response(&msg.headers) {
+        return Ok(());
+    }
+
+    match codec.decode(package_id, schema_id, &msg.payload) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Rejection {
+            code: RejectCode::InvalidPayload,
+            path: e.path,
+            reason: e.error.to_string(),
+        }),
+    }
+}
+
+// exactly one of the hfn and rpc headers
+fn message_target(headers: &[Vec<u8>]) -> Option<MessageTarget> {
+    let mut target = None;
+    for chunk in headers.chunks_exact(2) {
+        let id = || std::str::from_utf8(&chunk[1]).ok()?.parse().ok();
+        let found = match chunk[0].as_slice() {
+            HFN_HEADER => MessageTarget::Hfn(id()?),
+            RPC_HEADER => MessageTarget::Rpc(id()?),
+            _ => continue,
+        };
+        if target.replace(found).is_some() {
+            return None;
+        }
+    }
+    match target {
+        Some(MessageTarget::Rpc(id)) if is_response(headers) => Some(MessageTarget::RpcResult(id)),
+        target => target,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        codec::{payload::PayloadCodec, JsonConfig},
+        protocol::PacketMessage,
+        server::validate::*,
+    };
+
+    fn message(pkg_id: i32, headers: &[(&str, &str)], payload: &[u8]) -> PacketMessage {
+        PacketMessage {
+            id: 1,
+            pkg_id,
+            headers: headers
+                .iter()
+                .flat_map(|(k, v)| [k.as_bytes().to_vec(), v.as_bytes().to_vec()])
+                .collect(),
+            payload: payload.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_client_message() {
+        let config = JsonConfig::from_str(
+            r#"{
+                "name": "test",
+                "appid": "app",
+                "dev": { "devtools": "ws://127.0.0.1:1" },
+                "createdAt": "2022-01-01T00:00:00Z",
+                "packages": [{
+                    "id": 1,
+                    "name": "pkg",
+                    "modules": [{ "id": 1, "name": "m", "models": [], "hfns": [
+                        { "id": 3, "name": "show", "schemaId": 1 }
+                    ] }],
+                    "rpcs": [{ "id": 4, "name": "get", "reqSchemaId": 2, "resSchemaId": 1 }],
+                    "schemas": [
+                        { "id": 1, "fields": [
+                            { "id": 1, "name": "name", "type": "string", "isArray": false }
+                        ] },
+                        { "id": 2, "fields": [] }
+                    ]
+                }]
+            }"#,
+        )
+        .unwrap();
+        let codec = PayloadCodec::new(&config).unwrap();
+        let code = |msg: PacketMessage| validate_message(&codec, &msg).map_err(|e| e.code);
+
+        // [nil, "a"]
+        let payload = [0x92, 0xc0, 0xa1, b'a'];
+        assert_eq!(code(message(1, &[("hfn", "3")], &payload)), Ok(()));
+        assert_eq!(code(message(1, &[("rpc", "4")], &[0x90])), Ok(()));
+        // responses go by the response schema, failed calls carry no payload
+        assert_eq!(
+            code(message(1, &[("rpc", "4"), ("res", "1")], &payload)),
+            Ok(())
+        );
+        assert_eq!(
+            code(message(1, &[("rpc", "4"), ("res", "1"), ("err", "x")], &[])),
+            Ok(())
+        );
+        assert_eq!(
+            code(message(1, &[("rpc", "4"), ("res", "1")], &[0x90])),
+            Err(RejectCode::InvalidPayload)
+        );
+
+        assert_eq!(
+            code(message(2, &[("hfn", "3")], &payload)),
+            Err(RejectCode::UnknownPackage)
+        );
+        for headers in [
+            vec![],
+            vec![("hfn", "4")],
+            vec![("hfn", "x")],
+            vec![("hfn", "3"), ("rpc", "4")],
+        ] {
+            assert_eq!(
+                code(message(1, &headers, &payload)),
+                Err(RejectCode::UnknownTarget)
+            );
+        }
+
+        let rejection = validate_message(&codec, &message(1, &[("hfn", "3")], &[0x90]));
+        assert_eq!(
+            rejection,
+            Err(Rejection {
+                code: RejectCode::InvalidPayload,
+                path: "$.1".to_string(),
+                reason: "missing required field".to_string(),
+            })
+        );
+    }
+}
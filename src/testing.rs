@@ -0,0 +1,17 @@
+// The wire level pieces the integration tests in tests/ play clients, devtools and
+// gateways with. Not part of the api, hidden from the docs.
+
+pub use crate::{
+    codec::{
+        event::{
+            DisconnectReason, EVENT_CONNECT, EVENT_DELIVERY, EVENT_DISCONNECT, EVENT_ERROR,
+            EVENT_MESSAGE, EVENT_RPC_RESULT,
+        },
+        sign::hmac_sha256_hex,
+    },
+    gateway::signature::UpstreamSigner,
+    protocol::{
+        compress::{compress, CompressMethod},
+        Framing, Packet, PROTOCOL_VERSION,
+    },
+};
@@ -0,0 +1,194 @@
+// Fixtures shared by the integration tests: hfn.json and init args of a throwaway
+// instance, and a websocket client to talk to it.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use futures_util::StreamExt;
+use serde::Serialize;
+use tokio::{net::TcpStream, runtime::Runtime};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use hyper_function_core::{
+    testing::{EVENT_CONNECT, EVENT_DISCONNECT},
+    HfnInstance,
+};
+
+#[derive(Serialize)]
+pub struct TestInitArgs {
+    pub dev: bool,
+    pub sdk: String,
+    pub addr: Option<String>,
+    pub upstream_id: Option<String>,
+    pub pkg_names: Vec<String>,
+    pub hfn_config_path: Option<String>,
+    pub tokio_work_threads: Option<usize>,
+    pub session_grace_period: Option<u64>,
+    pub read_chan_capacity: Option<usize>,
+    pub socket_chan_capacity: Option<usize>,
+    pub gateway_chan_capacity: Option<usize>,
+    pub slow_consumer_policy: Option<String>,
+    pub ping_interval: Option<u64>,
+    pub ping_timeout: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub connection_retry_delay: Option<u64>,
+    pub compress_size: Option<u64>,
+    pub gateway_url: Option<String>,
+    pub client_auth: Option<serde_json::Value>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub strict_payloads: bool,
+}
+
+pub fn write_config(appid: &str) -> PathBuf {
+    write_packages_config(appid, "[]")
+}
+
+pub fn write_packages_config(appid: &str, packages: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("hfn-{}.json", rusty_ulid::generate_ulid_string()));
+    fs::write(
+        &path,
+        format!(
+            r#"{{
+                "name": "test",
+                "appid": "{}",
+                "dev": {{ "devtools": "ws://127.0.0.1:1" }},
+                "createdAt": "2022-01-01T00:00:00Z",
+                "packages": {}
+            }}"#,
+            appid, packages
+        ),
+    )
+    .unwrap();
+    path
+}
+
+pub fn init_args(config_path: &Path) -> Vec<u8> {
+    rmp_serde::to_vec(&test_init_args(config_path)).unwrap()
+}
+
+pub fn test_init_args(config_path: &Path) -> TestInitArgs {
+    TestInitArgs {
+        dev: false,
+        sdk: "test".to_string(),
+        addr: Some("127.0.0.1:0".to_string()),
+        upstream_id: None,
+        pkg_names: vec![],
+        hfn_config_path: Some(config_path.display().to_string()),
+        tokio_work_threads: Some(1),
+        session_grace_period: None,
+        read_chan_capacity: None,
+        socket_chan_capacity: None,
+        gateway_chan_capacity: None,
+        slow_consumer_policy: None,
+        ping_interval: None,
+        ping_timeout: None,
+        max_connections: None,
+        connection_retry_delay: None,
+        compress_size: None,
+        gateway_url: None,
+        client_auth: None,
+        tls_cert_path: None,
+        tls_key_path: None,
+        tls_client_ca_path: None,
+        strict_payloads: false,
+    }
+}
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub fn client_runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+pub fn connect_client(
+    rt: &Runtime,
+    instance: &HfnInstance,
+    aid: &str,
+    cid: &str,
+    sid: &str,
+) -> Client {
+    let url = format!(
+        "ws://{}/hfn?aid={}&cid={}&sid={}&ver=1&ts=0",
+        instance.local_addr().unwrap(),
+        aid,
+        cid,
+        sid
+    );
+    let (mut ws, _) = rt.block_on(tokio_tungstenite::connect_async(url)).unwrap();
+
+    // the socket is registered once OPEN arrives
+    let open = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+    assert_eq!(open[0], 1);
+
+    ws
+}
+
+// host side message body: pkg_id, headers, payload
+pub fn host_message(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    rmp::encode::write_sint(&mut data, 2).unwrap();
+    rmp::encode::write_map_len(&mut data, 0).unwrap();
+    rmp::encode::write_bin(&mut data, payload).unwrap();
+    data
+}
+
+// next MESSAGE packet received by the client, returns its id
+pub fn read_message_id(rt: &Runtime, ws: &mut Client) -> i32 {
+    rt.block_on(async {
+        while let Some(Ok(msg)) = ws.next().await {
+            let data = msg.into_data();
+            if data.first() == Some(&8) {
+                return rmp::decode::read_int(&mut &data[1..]).unwrap();
+            }
+        }
+        panic!("no message received");
+    })
+}
+
+// next packet of the type received by the client
+pub fn read_packet(rt: &Runtime, ws: &mut Client, packet_type: u8) -> Option<Vec<u8>> {
+    rt.block_on(async {
+        while let Some(Ok(msg)) = ws.next().await {
+            let data = msg.into_data();
+            if data.first() == Some(&packet_type) {
+                return Some(data);
+            }
+        }
+        None
+    })
+}
+
+pub fn read_socket_id(instance: &HfnInstance) -> String {
+    let connect = instance.read().unwrap();
+    assert_eq!(connect[0], EVENT_CONNECT);
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+    socket_id.to_string()
+}
+
+pub fn read_disconnect_reason(instance: &HfnInstance) -> u8 {
+    let disconnect = instance.read().unwrap();
+    assert_eq!(disconnect[0], EVENT_DISCONNECT);
+    *disconnect.last().unwrap()
+}
+
+pub fn read_close_reason(rt: &Runtime, ws: &mut Client) -> Option<String> {
+    rt.block_on(async {
+        while let Some(Ok(msg)) = ws.next().await {
+            let data = msg.into_data();
+            if data.first() == Some(&5) {
+                let (reason, _) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+                return Some(reason.to_string());
+            }
+        }
+        None
+    })
+}
@@ -0,0 +1,324 @@
+mod common;
+
+use std::{collections::HashMap, fs, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpListener, time::timeout};
+use tokio_tungstenite::tungstenite::{handshake::server::Request, Message};
+
+use hyper_function_core::{
+    testing::{compress, CompressMethod, Framing, Packet, UpstreamSigner, EVENT_MESSAGE},
+    HfnError, HfnInstance,
+};
+
+use common::*;
+
+#[test]
+// the handshake callback returns tungstenite's error response
+#[allow(clippy::result_large_err)]
+fn gateway_reconnects_and_follows_redirect() {
+    let rt = client_runtime();
+    let node1 = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let node2 = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let node2_url = format!("ws://{}/us", node2.local_addr().unwrap());
+
+    let path = write_config("app-gateway");
+    let config = fs::read_to_string(&path).unwrap().replace(
+        "ws://127.0.0.1:1",
+        &format!("ws://{}", node1.local_addr().unwrap()),
+    );
+    fs::write(&path, config).unwrap();
+
+    let mut args = test_init_args(&path);
+    args.dev = true;
+    args.addr = None;
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    // buffered until a connection gets its OPEN
+    let id = instance
+        .send_message("s1".to_string(), host_message(&[1]))
+        .unwrap();
+
+    let accept = |listener: &TcpListener| {
+        rt.block_on(async {
+            let (stream, _) = timeout(Duration::from_secs(5), listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            let mut query = String::new();
+            let ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res| {
+                query = req.uri().query().unwrap_or_default().to_string();
+                Ok(res)
+            })
+            .await
+            .unwrap();
+            (ws, query)
+        })
+    };
+
+    let (mut ws, query) = accept(&node1);
+    assert!(query.contains("usid="));
+    rt.block_on(ws.send(Message::Binary(vec![10, 0]))).unwrap();
+
+    let (mut ws, _) = accept(&node1);
+    let mut redirect = vec![11, 0];
+    rmp::encode::write_str(&mut redirect, &node2_url).unwrap();
+    rt.block_on(ws.send(Message::Binary(redirect))).unwrap();
+
+    // the upstream query comes along to the new node
+    let (mut ws, redirected_query) = accept(&node2);
+    assert!(redirected_query.contains("usid="));
+    rt.block_on(async {
+        ws.send(Message::Binary(vec![6, 1, 25, 20, 0, 0]))
+            .await
+            .unwrap();
+        ws.send(Message::Binary(vec![8])).await.unwrap();
+
+        let (mut message, mut pong) = (None, false);
+        while message.is_none() || !pong {
+            let data = ws.next().await.unwrap().unwrap().into_data();
+            match data[0] {
+                12 => message = Some(data),
+                9 => pong = true,
+                t => panic!("unexpected packet {}", t),
+            }
+        }
+
+        let message = message.unwrap();
+        assert_eq!(
+            rmp::decode::read_int::<i32, _>(&mut &message[1..]).unwrap(),
+            id
+        );
+        ws.close(None).await.unwrap();
+    });
+
+    // a dropped connection is retried at the node it was redirected to
+    accept(&node2);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn gateway_opens_to_unversioned_devtools() {
+    let rt = client_runtime();
+    let devtools = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+
+    let path = write_config("app-gateway-unversioned");
+    let config = fs::read_to_string(&path).unwrap().replace(
+        "ws://127.0.0.1:1",
+        &format!("ws://{}", devtools.local_addr().unwrap()),
+    );
+    fs::write(&path, config).unwrap();
+
+    let mut args = test_init_args(&path);
+    args.dev = true;
+    args.addr = None;
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let id = instance
+        .send_message("s1".to_string(), host_message(&[1]))
+        .unwrap();
+
+    rt.block_on(async {
+        let (stream, _) = timeout(Duration::from_secs(5), devtools.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        // devtools from before versioning has no version in OPEN
+        ws.send(Message::Binary(vec![6, 25, 20, 0, 0]))
+            .await
+            .unwrap();
+
+        let data = ws.next().await.unwrap().unwrap().into_data();
+        match Framing::upstream(0).parse_frame(&data).unwrap().pop() {
+            Some(Packet::MESSAGE(msg)) => {
+                assert_eq!(msg.id, id);
+                assert_eq!(msg.socket_id, b"s1");
+            }
+            _ => panic!("devtools should get a message"),
+        }
+    });
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[allow(clippy::result_large_err)]
+fn gateway_compression() {
+    let rt = client_runtime();
+    let devtools = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+
+    let path = write_config("app-gateway-compress");
+    let config = fs::read_to_string(&path).unwrap().replace(
+        "ws://127.0.0.1:1",
+        &format!("ws://{}", devtools.local_addr().unwrap()),
+    );
+    fs::write(&path, config).unwrap();
+
+    let mut args = test_init_args(&path);
+    args.dev = true;
+    args.addr = None;
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let payload = vec![7; 2048];
+    instance
+        .send_message("s1".to_string(), host_message(&payload))
+        .unwrap();
+
+    rt.block_on(async {
+        let (stream, _) = timeout(Duration::from_secs(5), devtools.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut query = String::new();
+        let mut ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res| {
+            query = req.uri().query().unwrap_or_default().to_string();
+            Ok(res)
+        })
+        .await
+        .unwrap();
+        let methods = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == "compress")
+            .map(|(_, v)| v.into_owned());
+        assert_eq!(methods, Some(CompressMethod::supported_list()));
+
+        // deflate everything over 1kb
+        ws.send(Message::Binary(vec![6, 2, 25, 20, 1, 1]))
+            .await
+            .unwrap();
+
+        let data = ws.next().await.unwrap().unwrap().into_data();
+        match Framing::upstream(2).parse_frame(&data).unwrap().pop() {
+            Some(Packet::MESSAGE(mut msg)) => {
+                assert_eq!(msg.compress, CompressMethod::Deflate as u8);
+                assert_eq!(msg.socket_id, b"s1");
+                msg.decompress().unwrap();
+                assert_eq!(msg.payload, payload);
+            }
+            _ => panic!("devtools should get a message"),
+        }
+
+        let mut msg = vec![12];
+        rmp::encode::write_sint(&mut msg, 0).unwrap();
+        rmp::encode::write_sint(&mut msg, 2).unwrap();
+        rmp::encode::write_map_len(&mut msg, 0).unwrap();
+        rmp::encode::write_bin(&mut msg, &compress(CompressMethod::Deflate, &payload)).unwrap();
+        rmp::encode::write_str(&mut msg, "s1").unwrap();
+        rmp::encode::write_pfix(&mut msg, CompressMethod::Deflate as u8).unwrap();
+        ws.send(Message::Binary(msg)).await.unwrap();
+    });
+
+    // the host reads devtools payloads inflated
+    let message = instance.read().unwrap();
+    assert_eq!(message[0], EVENT_MESSAGE);
+    let mut rest = &message[1..];
+    assert_eq!(rmp::decode::read_int::<i32, _>(&mut rest).unwrap(), 2);
+    assert_eq!(rmp::decode::read_map_len(&mut rest).unwrap(), 0);
+    let len = rmp::decode::read_bin_len(&mut rest).unwrap() as usize;
+    assert_eq!(rest[..len], payload[..]);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[allow(clippy::result_large_err)]
+fn serve_clients_through_gateway_cluster() {
+    let rt = client_runtime();
+    let cluster = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let cluster_addr = cluster.local_addr().unwrap();
+
+    let accept = || {
+        rt.block_on(async {
+            let (stream, _) = timeout(Duration::from_secs(5), cluster.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            let mut path = String::new();
+            let ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res| {
+                path = req.uri().to_string();
+                Ok(res)
+            })
+            .await
+            .unwrap();
+            (ws, path)
+        })
+    };
+
+    // no listener without addr, the core dials out to the gateway_url
+    let path = write_config("app-cluster");
+    let mut args = test_init_args(&path);
+    args.addr = None;
+    args.gateway_url = Some(format!("ws://{}/cluster", cluster_addr));
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+    assert_eq!(instance.local_addr(), None);
+
+    let (mut ws, path_and_query) = accept();
+    assert!(path_and_query.starts_with("/cluster?"));
+    assert!(path_and_query.contains("appid=app-cluster"));
+    // unsigned without a secret
+    assert!(!path_and_query.contains("sig="));
+    rt.block_on(ws.send(Message::Binary(vec![6, 2, 25, 20, 0, 0])))
+        .unwrap();
+
+    let id = instance
+        .send_message("s1".to_string(), host_message(&[1]))
+        .unwrap();
+    let message = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+    assert_eq!(message[0], 12);
+    assert_eq!(
+        rmp::decode::read_int::<i32, _>(&mut &message[1..]).unwrap(),
+        id
+    );
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    // the gateway in hfn.json is used when there is no addr, a bare host gets /us
+    let config = fs::read_to_string(&path).unwrap().replace(
+        r#""packages""#,
+        &format!(
+            r#""gateway": {{ "url": "ws://{}", "secret": "s3cret" }}, "packages""#,
+            cluster_addr
+        ),
+    );
+    fs::write(&path, config).unwrap();
+    let mut args = test_init_args(&path);
+    args.addr = None;
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+    let (_, path_and_query) = accept();
+    assert!(path_and_query.starts_with("/us?"));
+
+    // signed with the secret from hfn.json
+    let query: HashMap<String, String> =
+        url::form_urlencoded::parse(path_and_query.split_once('?').unwrap().1.as_bytes())
+            .into_owned()
+            .collect();
+    assert_eq!(query["usid"], instance.upstream_id);
+    let signer = UpstreamSigner::new("s3cret", "app-cluster", &query["usid"], &query["pkgs"]);
+    assert_eq!(query["sig"], signer.sign(query["ts"].parse().unwrap()));
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    // an addr takes precedence over hfn.json
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+    assert!(instance.local_addr().is_some());
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    let mut args = test_init_args(&path);
+    args.gateway_url = Some("http://127.0.0.1:1".to_string());
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    match instance.run() {
+        Err(HfnError::InvalidUrl(_)) => {}
+        _ => panic!("should be invalid url"),
+    }
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    fs::remove_file(path).unwrap();
+}
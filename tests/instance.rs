@@ -0,0 +1,262 @@
+mod common;
+
+use std::{fs, time::Duration};
+
+use futures_util::StreamExt;
+
+use hyper_function_core::{
+    testing::{DisconnectReason, EVENT_CONNECT, EVENT_DISCONNECT},
+    HfnError, HfnInstance, HfnValue,
+};
+
+use common::*;
+
+#[test]
+fn run_isolated_instances() {
+    let path_a = write_config("app-a");
+    let path_b = write_config("app-b");
+
+    let (a, _) = HfnInstance::init(init_args(&path_a)).unwrap();
+    let (b, _) = HfnInstance::init(init_args(&path_b)).unwrap();
+
+    assert_eq!(a.app_id, "app-a");
+    assert_eq!(b.app_id, "app-b");
+    assert_ne!(a.upstream_id, b.upstream_id);
+
+    a.run().unwrap();
+    b.run().unwrap();
+
+    match a.send_message("unknown".to_string(), vec![]) {
+        Err(HfnError::SocketNotFound(id)) => assert_eq!(id, "unknown"),
+        _ => panic!("should be socket not found"),
+    }
+
+    fs::remove_file(path_a).unwrap();
+    fs::remove_file(path_b).unwrap();
+}
+
+#[test]
+fn init_with_missing_config() {
+    let mut path = std::env::temp_dir();
+    path.push("hfn-missing.json");
+
+    match HfnInstance::init(init_args(&path)) {
+        Err(HfnError::ConfigNotFound(_)) => {}
+        _ => panic!("should be config not found"),
+    }
+}
+
+#[test]
+fn init_with_bad_args() {
+    match HfnInstance::init(vec![0xc1]) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let path = write_config("app-zero-capacity");
+    let mut args = test_init_args(&path);
+    args.read_chan_capacity = Some(0);
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let mut args = test_init_args(&path);
+    args.ping_interval = Some(128);
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let mut args = test_init_args(&path);
+    args.max_connections = Some(0);
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let mut args = test_init_args(&path);
+    args.connection_retry_delay = Some(128);
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let mut args = test_init_args(&path);
+    args.compress_size = Some(128);
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+
+    let mut args = test_init_args(&path);
+    args.tls_cert_path = Some("cert.pem".to_string());
+    match HfnInstance::init(rmp_serde::to_vec(&args).unwrap()) {
+        Err(HfnError::InvalidInitArgs(_)) => {}
+        _ => panic!("should be invalid init args"),
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn payload_of_unknown_schema() {
+    let path = write_config("app-payload");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+
+    let value = HfnValue::Struct(Default::default());
+    match instance.encode_payload(1, 1, &value) {
+        Err(HfnError::InvalidArgument(_)) => {}
+        _ => panic!("should be invalid argument"),
+    }
+    match instance.decode_payload(1, 1, vec![0x90]) {
+        Err(HfnError::InvalidArgument(_)) => {}
+        _ => panic!("should be invalid argument"),
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn shutdown_closes_sockets() {
+    let path = write_config("app-shutdown");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-shutdown", "c", "s");
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    let reason = read_close_reason(&rt, &mut ws);
+    assert_eq!(reason.as_deref(), Some("server shutdown"));
+
+    let connect = instance.read().unwrap();
+    assert_eq!(connect[0], EVENT_CONNECT);
+    let disconnect = instance.read().unwrap();
+    assert_eq!(disconnect[0], EVENT_DISCONNECT);
+    assert_eq!(
+        *disconnect.last().unwrap(),
+        DisconnectReason::ServerShutdown as u8
+    );
+    assert!(instance.read().is_none());
+    match instance.send_message("unknown".to_string(), vec![]) {
+        Err(HfnError::Shutdown) => {}
+        _ => panic!("should be shutdown"),
+    }
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn restart_default_instance() {
+    let path = write_config("app-restart");
+
+    hyper_function_core::init(init_args(&path)).unwrap();
+    hyper_function_core::run().unwrap();
+    match hyper_function_core::init(init_args(&path)) {
+        Err(HfnError::AlreadyInitialized) => {}
+        _ => panic!("should be already initialized"),
+    }
+    hyper_function_core::shutdown(Duration::from_secs(1)).unwrap();
+
+    hyper_function_core::init(init_args(&path)).unwrap();
+    hyper_function_core::run().unwrap();
+    hyper_function_core::shutdown(Duration::from_secs(1)).unwrap();
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn heartbeat_from_config_and_args() {
+    let path = write_config("app-heartbeat");
+    let config = fs::read_to_string(&path).unwrap().replace(
+        r#""packages""#,
+        r#""heartbeat": { "pingInterval": 10, "pingTimeout": 5 }, "packages""#,
+    );
+    fs::write(&path, config).unwrap();
+
+    // init args win over hfn.json
+    let mut args = test_init_args(&path);
+    args.ping_timeout = Some(3);
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let url = format!(
+        "ws://{}/hfn?aid=app-heartbeat&cid=c1&sid=s1&ver=1&ts=0",
+        instance.local_addr().unwrap()
+    );
+    let (mut ws, _) = rt.block_on(tokio_tungstenite::connect_async(url)).unwrap();
+    let open = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+    assert_eq!(open, vec![1, 10, 3]);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn init_result_field_types() {
+    let packages = |t: &str| {
+        format!(
+            r#"[{{
+                "id": 1,
+                "name": "pkg",
+                "modules": [],
+                "rpcs": [],
+                "schemas": [
+                    {{ "id": 1, "fields": [
+                        {{ "id": 1, "name": "f", "type": "{}", "isArray": false }}
+                    ] }},
+                    {{ "id": 2, "fields": [] }}
+                ]
+            }}]"#,
+            t
+        )
+    };
+
+    let path = write_packages_config("app-types", &packages("map<string,ref<2>>?"));
+    let (instance, result) = HfnInstance::init(init_args(&path)).unwrap();
+    let result: serde_json::Value = rmp_serde::from_slice(&result).unwrap();
+    let field = &result["fields"][0];
+    assert_eq!(field["t"], "map<string,ref<2>>?");
+    // nothing refers to schema 1
+    assert_eq!(
+        result["warnings"],
+        serde_json::json!(["$.packages[0].schemas[0]: schema 1 is not referenced"])
+    );
+    assert_eq!(
+        field["field_type"],
+        serde_json::json!({
+            "kind": "optional",
+            "inner": {
+                "kind": "map",
+                "key": { "kind": "string" },
+                "value": { "kind": "ref", "schema_id": 2 }
+            }
+        })
+    );
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+
+    // an alias keeps its name in `t`
+    let path = write_packages_config("app-types", &packages("int"));
+    let (instance, result) = HfnInstance::init(init_args(&path)).unwrap();
+    let result: serde_json::Value = rmp_serde::from_slice(&result).unwrap();
+    let field = &result["fields"][0];
+    assert_eq!(field["t"], "int");
+    assert_eq!(
+        field["field_type"],
+        serde_json::json!({ "kind": "int", "bits": 64 })
+    );
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+
+    // unknown types and refs to a schema the package doesn't have
+    for t in ["json", "ref<3>", "map<string,ref<3>>"] {
+        let path = write_packages_config("app-types", &packages(t));
+        match HfnInstance::init(init_args(&path)) {
+            Err(HfnError::InvalidConfig(_)) => {}
+            _ => panic!("{} should be an invalid config", t),
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
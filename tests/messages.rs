@@ -0,0 +1,473 @@
+mod common;
+
+use std::{collections::HashMap, fs, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use hyper_function_core::{
+    testing::{
+        compress, CompressMethod, DisconnectReason, Framing, Packet, EVENT_CONNECT, EVENT_DELIVERY,
+        EVENT_DISCONNECT, EVENT_ERROR, EVENT_MESSAGE, EVENT_RPC_RESULT, PROTOCOL_VERSION,
+    },
+    HfnError, HfnInstance, TryReadRes,
+};
+
+use common::*;
+
+#[test]
+fn ack_messages() {
+    let path = write_config("app-ack");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-ack", "c1", "s1");
+
+    let connect = instance.read().unwrap();
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+
+    let id = instance
+        .send_message(socket_id.to_string(), host_message(&[1, 2, 3]))
+        .unwrap();
+    assert_eq!(read_message_id(&rt, &mut ws), id);
+
+    // client acks the message and sends one of its own
+    let mut ack = Vec::new();
+    rmp::encode::write_pfix(&mut ack, 9).unwrap();
+    rmp::encode::write_sint(&mut ack, id as i64).unwrap();
+    rmp::encode::write_sint(&mut ack, 2).unwrap();
+    let mut msg = vec![8];
+    rmp::encode::write_sint(&mut msg, 7).unwrap();
+    msg.append(&mut host_message(&[4]));
+    rt.block_on(ws.send(Message::Binary([ack, msg].concat())))
+        .unwrap();
+
+    let delivery = instance.read().unwrap();
+    assert_eq!(delivery[0], EVENT_DELIVERY);
+    let (_, mut rest) = rmp::decode::read_str_from_slice(&delivery[1..]).unwrap();
+    assert_eq!(rmp::decode::read_int::<i32, _>(&mut rest).unwrap(), id);
+    assert!(rmp::decode::read_bool(&mut rest).unwrap());
+
+    let message = instance.read().unwrap();
+    assert_eq!(message[0], EVENT_MESSAGE);
+
+    let ack = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+    assert_eq!(ack[0], 9);
+    assert_eq!(rmp::decode::read_int::<i32, _>(&mut &ack[1..]).unwrap(), 7);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn retransmit_unacked_on_reconnect() {
+    let path = write_config("app-retransmit");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-retransmit", "c1", "s1");
+
+    let connect = instance.read().unwrap();
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+
+    let id = instance
+        .send_message(socket_id.to_string(), host_message(&[1]))
+        .unwrap();
+    assert_eq!(read_message_id(&rt, &mut ws), id);
+
+    // drop the connection without acking
+    rt.block_on(ws.close(None)).unwrap();
+    drop(ws);
+    let disconnect = instance.read().unwrap();
+    assert_eq!(disconnect[0], EVENT_DISCONNECT);
+
+    let mut ws = connect_client(&rt, &instance, "app-retransmit", "c1", "s1");
+    assert_eq!(read_message_id(&rt, &mut ws), id);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn resume_session_after_reconnect() {
+    let path = write_config("app-resume");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-resume", "c1", "s1");
+
+    let connect = instance.read().unwrap();
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+    let socket_id = socket_id.to_string();
+    // resumed flag is the last field
+    assert_eq!(*connect.last().unwrap(), 0xc2);
+
+    rt.block_on(ws.close(None)).unwrap();
+    drop(ws);
+    let disconnect = instance.read().unwrap();
+    assert_eq!(disconnect[0], EVENT_DISCONNECT);
+
+    // buffered while the client is offline
+    let id = instance
+        .send_message(socket_id.clone(), host_message(&[1]))
+        .unwrap();
+
+    let mut ws = connect_client(&rt, &instance, "app-resume", "c1", "s1");
+    let connect = instance.read().unwrap();
+    assert_eq!(connect[0], EVENT_CONNECT);
+    let (resumed_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+    assert_eq!(resumed_id, socket_id);
+    assert_eq!(*connect.last().unwrap(), 0xc3);
+    assert_eq!(read_message_id(&rt, &mut ws), id);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn slow_consumer_policies() {
+    for policy in ["drop_newest", "drop_oldest", "disconnect"] {
+        let path = write_config("app-slow");
+        let mut args = test_init_args(&path);
+        args.socket_chan_capacity = Some(2);
+        args.slow_consumer_policy = Some(policy.to_string());
+        let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+        instance.run().unwrap();
+
+        let rt = client_runtime();
+        let mut ws = connect_client(&rt, &instance, "app-slow", "c1", "s1");
+        let connect = instance.read().unwrap();
+        let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+        let socket_id = socket_id.to_string();
+
+        // the client never acks, so the buffer fills up
+        instance
+            .send_message(socket_id.clone(), host_message(&[1]))
+            .unwrap();
+        // queued behind the one message window
+        let id2 = instance
+            .send_message(socket_id.clone(), host_message(&[2]))
+            .unwrap();
+        let res = instance.send_message(socket_id.clone(), host_message(&[3]));
+
+        match policy {
+            "drop_newest" => assert!(matches!(res, Err(HfnError::Full(_)))),
+            "drop_oldest" => {
+                assert!(res.is_ok());
+                let delivery = instance.read().unwrap();
+                assert_eq!(delivery[0], EVENT_DELIVERY);
+                let (_, mut rest) = rmp::decode::read_str_from_slice(&delivery[1..]).unwrap();
+                assert_eq!(rmp::decode::read_int::<i32, _>(&mut rest).unwrap(), id2);
+                assert!(!rmp::decode::read_bool(&mut rest).unwrap());
+            }
+            _ => {
+                assert!(matches!(res, Err(HfnError::Full(_))));
+                assert_eq!(
+                    read_close_reason(&rt, &mut ws).as_deref(),
+                    Some("slow consumer")
+                );
+                let disconnect = instance.read().unwrap();
+                assert_eq!(
+                    *disconnect.last().unwrap(),
+                    DisconnectReason::SlowConsumer as u8
+                );
+            }
+        }
+
+        instance.shutdown(Duration::from_secs(1)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn compress_client_messages() {
+    let path = write_config("app-compress");
+    let mut args = test_init_args(&path);
+    args.compress_size = Some(1);
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let url = format!(
+        "ws://{}/hfn?aid=app-compress&cid=c1&sid=s1&ver=1&ts=0&pv=2&compress=1,9",
+        instance.local_addr().unwrap()
+    );
+    let (mut ws, _) = rt.block_on(tokio_tungstenite::connect_async(url)).unwrap();
+
+    // deflate over 1kb is announced in OPEN
+    let open = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+    assert_eq!(open, vec![1, 2, 25, 20, 1, CompressMethod::Deflate as u8]);
+    let socket_id = read_socket_id(&instance);
+
+    let payload = vec![7; 4096];
+    instance
+        .send_message(socket_id.clone(), host_message(&payload))
+        .unwrap();
+    let data = read_packet(&rt, &mut ws, 8).unwrap();
+    match Framing::client(2).parse_frame(&data).unwrap().pop() {
+        Some(Packet::MESSAGE(mut msg)) => {
+            assert_eq!(msg.compress, CompressMethod::Deflate as u8);
+            assert!(msg.payload.len() < payload.len());
+            msg.decompress().unwrap();
+            assert_eq!(msg.payload, payload);
+        }
+        _ => panic!("client should get a message"),
+    }
+
+    // the host reads client payloads inflated
+    let mut msg = vec![8];
+    rmp::encode::write_sint(&mut msg, 0).unwrap();
+    rmp::encode::write_sint(&mut msg, 2).unwrap();
+    rmp::encode::write_map_len(&mut msg, 0).unwrap();
+    rmp::encode::write_bin(&mut msg, &compress(CompressMethod::Deflate, &payload)).unwrap();
+    rmp::encode::write_pfix(&mut msg, CompressMethod::Deflate as u8).unwrap();
+    rt.block_on(ws.send(Message::Binary(msg))).unwrap();
+
+    let message = instance.read().unwrap();
+    assert_eq!(message[0], EVENT_MESSAGE);
+    let mut rest = &message[1..];
+    assert_eq!(rmp::decode::read_int::<i32, _>(&mut rest).unwrap(), 2);
+    assert_eq!(rmp::decode::read_map_len(&mut rest).unwrap(), 0);
+    let len = rmp::decode::read_bin_len(&mut rest).unwrap() as usize;
+    assert_eq!(rest[..len], payload[..]);
+
+    // a payload that doesn't inflate is a protocol violation
+    rt.block_on(ws.send(Message::Binary(vec![8, 0, 2, 0x80, 0xc4, 1, 1, 1])))
+        .unwrap();
+    assert_eq!(instance.read().unwrap()[0], EVENT_ERROR);
+    assert_eq!(
+        read_disconnect_reason(&instance),
+        DisconnectReason::ProtocolViolation as u8
+    );
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reject_invalid_client_payloads() {
+    let path = write_packages_config(
+        "app-strict",
+        r#"[{
+            "id": 1,
+            "name": "pkg",
+            "modules": [{ "id": 1, "name": "m", "models": [], "hfns": [
+                { "id": 3, "name": "show", "schemaId": 1 }
+            ] }],
+            "rpcs": [],
+            "schemas": [{ "id": 1, "fields": [
+                { "id": 1, "name": "name", "type": "string", "isArray": false }
+            ] }]
+        }]"#,
+    );
+    let mut args = test_init_args(&path);
+    args.strict_payloads = true;
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let connect = |pv: u8| {
+        let url = format!(
+            "ws://{}/hfn?aid=app-strict&cid=c1&sid=s{}&ver=1&ts=0&pv={}",
+            instance.local_addr().unwrap(),
+            pv,
+            pv
+        );
+        let (mut ws, _) = rt.block_on(tokio_tungstenite::connect_async(url)).unwrap();
+        rt.block_on(ws.next()).unwrap().unwrap();
+        read_socket_id(&instance);
+        ws
+    };
+    let message = |id: i64, payload: &[u8]| {
+        let mut msg = vec![8];
+        rmp::encode::write_sint(&mut msg, id).unwrap();
+        rmp::encode::write_sint(&mut msg, 1).unwrap();
+        rmp::encode::write_map_len(&mut msg, 1).unwrap();
+        rmp::encode::write_str(&mut msg, "hfn").unwrap();
+        rmp::encode::write_str(&mut msg, "3").unwrap();
+        rmp::encode::write_bin(&mut msg, payload).unwrap();
+        rmp::encode::write_pfix(&mut msg, 0).unwrap();
+        Message::Binary(msg)
+    };
+
+    let mut ws = connect(PROTOCOL_VERSION);
+    // name is a str
+    rt.block_on(ws.send(message(5, &[0x92, 0xc0, 0x01])))
+        .unwrap();
+    let data = read_packet(&rt, &mut ws, 10).unwrap();
+    match Framing::client(PROTOCOL_VERSION)
+        .parse_frame(&data)
+        .unwrap()
+        .pop()
+    {
+        Some(Packet::REJECT(reject)) => {
+            assert_eq!((reject.id, reject.pkg_id, reject.code), (5, 1, 3));
+            assert_eq!(reject.path, "$.1");
+        }
+        _ => panic!("client should get a reject"),
+    }
+    // the host hears of it instead of the message
+    let error = instance.read().unwrap();
+    assert_eq!(error[0], EVENT_ERROR);
+
+    rt.block_on(ws.send(message(6, &[0x92, 0xc0, 0xa1, b'a'])))
+        .unwrap();
+    assert_eq!(instance.read().unwrap()[0], EVENT_MESSAGE);
+    let data = read_packet(&rt, &mut ws, 9).unwrap();
+    assert_eq!(data[1], 6);
+
+    // clients before REJECT only get the ack
+    let mut ws = connect(2);
+    rt.block_on(ws.send(message(7, &[0x90]))).unwrap();
+    let data = read_packet(&rt, &mut ws, 9).unwrap();
+    assert_eq!(data[1], 7);
+    assert_eq!(instance.read().unwrap()[0], EVENT_ERROR);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn call_and_respond_rpc() {
+    let path = write_packages_config(
+        "app-rpc",
+        r#"[{
+            "id": 1,
+            "name": "pkg",
+            "modules": [],
+            "rpcs": [{ "id": 4, "name": "get", "reqSchemaId": 1, "resSchemaId": 1 }],
+            "schemas": [{ "id": 1, "fields": [] }]
+        }]"#,
+    );
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-rpc", "c1", "s1");
+    let socket_id = read_socket_id(&instance);
+
+    // the next request the client gets, its headers
+    let read_request = |ws: &mut Client| {
+        let data = read_packet(&rt, ws, 8).unwrap();
+        match Framing::client(0).parse_frame(&data).unwrap().pop() {
+            Some(Packet::MESSAGE(msg)) => {
+                assert_eq!(msg.pkg_id, 1);
+                msg.headers
+                    .chunks_exact(2)
+                    .map(|h| {
+                        let k = String::from_utf8(h[0].clone()).unwrap();
+                        (k, String::from_utf8(h[1].clone()).unwrap())
+                    })
+                    .collect::<HashMap<String, String>>()
+            }
+            _ => panic!("client should get a message"),
+        }
+    };
+    let response = |headers: &[(&str, &str)], payload: &[u8]| {
+        let mut msg = vec![8, 0, 1];
+        rmp::encode::write_map_len(&mut msg, headers.len() as u32).unwrap();
+        for (k, v) in headers {
+            rmp::encode::write_str(&mut msg, k).unwrap();
+            rmp::encode::write_str(&mut msg, v).unwrap();
+        }
+        rmp::encode::write_bin(&mut msg, payload).unwrap();
+        Message::Binary(msg)
+    };
+    // call id, code, message and payload
+    let read_result = || {
+        let data = instance.read().unwrap();
+        assert_eq!(data[0], EVENT_RPC_RESULT);
+        let (id, rest) = rmp::decode::read_str_from_slice(&data[1..]).unwrap();
+        assert_eq!(id, socket_id);
+        let mut rest = rest;
+        let call_id: u32 = rmp::decode::read_int(&mut rest).unwrap();
+        let code: u16 = rmp::decode::read_int(&mut rest).unwrap();
+        let (message, mut rest) = rmp::decode::read_str_from_slice(rest).unwrap();
+        let len = rmp::decode::read_bin_len(&mut rest).unwrap() as usize;
+        (call_id, code, message.to_string(), rest[..len].to_vec())
+    };
+
+    let call = |timeout: Duration| instance.call_rpc(socket_id.clone(), 1, 4, vec![0x90], timeout);
+    let id = call(Duration::from_secs(5)).unwrap();
+    let request = read_request(&mut ws);
+    assert_eq!(request["rpc"], "4");
+    assert_eq!(request["req"], id.to_string());
+    let res = id.to_string();
+    rt.block_on(ws.send(response(&[("rpc", "4"), ("res", &res)], &[0x90])))
+        .unwrap();
+    assert_eq!(read_result(), (id, 0, String::new(), vec![0x90]));
+
+    let id = call(Duration::from_secs(5)).unwrap();
+    read_request(&mut ws);
+    let res = id.to_string();
+    let failed = response(&[("rpc", "4"), ("res", &res), ("err", "busy")], &[]);
+    rt.block_on(ws.send(failed)).unwrap();
+    let (call_id, code, message, _) = read_result();
+    assert_eq!((call_id, code), (id, 20));
+    assert_eq!(message, "rpc call failed: busy");
+
+    // no response in time
+    let id = call(Duration::from_millis(50)).unwrap();
+    read_request(&mut ws);
+    let (call_id, code, _, _) = read_result();
+    assert_eq!((call_id, code), (id, 19));
+
+    // a cancelled call has no result, its late response reaches the host as a message
+    let id = call(Duration::from_millis(50)).unwrap();
+    read_request(&mut ws);
+    assert!(instance.cancel_rpc(id));
+    assert!(!instance.cancel_rpc(id));
+    let res = id.to_string();
+    rt.block_on(ws.send(response(&[("rpc", "4"), ("res", &res)], &[0x90])))
+        .unwrap();
+    assert_eq!(instance.read().unwrap()[0], EVENT_MESSAGE);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(matches!(instance.try_read(), TryReadRes::EMPTY));
+
+    // so does a response to a call that never was
+    rt.block_on(ws.send(response(&[("rpc", "4"), ("res", "12345")], &[0x90])))
+        .unwrap();
+    assert_eq!(instance.read().unwrap()[0], EVENT_MESSAGE);
+
+    // the client calls, the host answers
+    rt.block_on(ws.send(response(&[("rpc", "4"), ("req", "9")], &[0x90])))
+        .unwrap();
+    assert_eq!(instance.read().unwrap()[0], EVENT_MESSAGE);
+    instance
+        .respond_rpc(socket_id.clone(), 1, 4, 9, Err("nope".to_string()))
+        .unwrap();
+    let answer = read_request(&mut ws);
+    assert_eq!(answer["res"], "9");
+    assert_eq!(answer["err"], "nope");
+
+    match instance.call_rpc(socket_id.clone(), 1, 5, vec![], Duration::from_secs(1)) {
+        Err(HfnError::InvalidArgument(_)) => {}
+        _ => panic!("rpc 5 doesn't exist"),
+    }
+    match call(Duration::ZERO) {
+        Err(HfnError::InvalidArgument(_)) => {}
+        _ => panic!("timeout must be positive"),
+    }
+
+    // the socket is gone for good, its call fails before the timeout
+    let id = call(Duration::from_secs(5)).unwrap();
+    read_request(&mut ws);
+    instance.close_socket(&socket_id, "bye").unwrap();
+    let result = loop {
+        let data = instance.read().unwrap();
+        if data[0] == EVENT_RPC_RESULT {
+            break data;
+        }
+    };
+    let (_, mut rest) = rmp::decode::read_str_from_slice(&result[1..]).unwrap();
+    assert_eq!(rmp::decode::read_int::<u32, _>(&mut rest).unwrap(), id);
+    assert_eq!(rmp::decode::read_int::<u16, _>(&mut rest).unwrap(), 20);
+    let (message, _) = rmp::decode::read_str_from_slice(rest).unwrap();
+    assert_eq!(message, "rpc call failed: socket expired");
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
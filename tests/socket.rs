@@ -0,0 +1,471 @@
+mod common;
+
+use std::{fs, sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{client::IntoClientRequest, Error as WsError, Message},
+    Connector,
+};
+
+use hyper_function_core::{
+    testing::{
+        hmac_sha256_hex, DisconnectReason, EVENT_CONNECT, EVENT_DISCONNECT, EVENT_ERROR,
+        PROTOCOL_VERSION,
+    },
+    Claims, HfnError, HfnInstance, TryReadRes,
+};
+
+use common::*;
+
+#[test]
+fn negotiate_protocol_version() {
+    let path = write_config("app-version");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let connect = |pv: &str| {
+        let url = format!(
+            "ws://{}/hfn?aid=app-version&cid=c1&sid=s1&ver=1&ts=0&pv={}",
+            instance.local_addr().unwrap(),
+            pv
+        );
+        // the handshake fails on a bad request
+        let ws = rt.block_on(tokio_tungstenite::connect_async(url)).ok();
+        ws.map(|(ws, _)| ws)
+    };
+
+    // OPEN starts with the version, a newer client gets ours
+    for (pv, version) in [("1", 1), ("9", PROTOCOL_VERSION)] {
+        let mut ws = connect(pv).unwrap();
+        let open = rt.block_on(ws.next()).unwrap().unwrap().into_data();
+        assert_eq!(open[..2], [1, version]);
+    }
+
+    assert!(connect("x").is_none());
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn ping_pong_and_rtt() {
+    let path = write_config("app-rtt");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-rtt", "c1", "s1");
+    let connect = instance.read().unwrap();
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+    let socket_id = socket_id.to_string();
+    assert_eq!(instance.socket_rtt(&socket_id).unwrap(), None);
+
+    rt.block_on(async {
+        // the first PING goes out right after OPEN
+        let ping = ws.next().await.unwrap().unwrap().into_data();
+        assert_eq!(ping, vec![6]);
+        ws.send(Message::Binary(vec![7])).await.unwrap();
+
+        // server answers client PINGs
+        ws.send(Message::Binary(vec![6])).await.unwrap();
+        let pong = ws.next().await.unwrap().unwrap().into_data();
+        assert_eq!(pong, vec![7]);
+    });
+
+    // the PONG is processed before the PING that was sent after it
+    assert!(instance.socket_rtt(&socket_id).unwrap().is_some());
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn heartbeat_timeout_without_pong() {
+    let path = write_config("app-no-pong");
+    let mut args = test_init_args(&path);
+    args.ping_interval = Some(1);
+    args.ping_timeout = Some(1);
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-no-pong", "c1", "s1");
+    instance.read().unwrap();
+
+    // a client that keeps sending but never answers the PING
+    rt.block_on(async {
+        for _ in 0..10 {
+            if ws.send(Message::Binary(vec![6])).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+    assert_eq!(
+        read_disconnect_reason(&instance),
+        DisconnectReason::HeartbeatTimeout as u8
+    );
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn close_socket_on_malformed_frame() {
+    let path = write_config("app-malformed");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-malformed", "c1", "s1");
+    let connect = instance.read().unwrap();
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+    let socket_id = socket_id.to_string();
+
+    // MESSAGE whose payload length runs past the frame
+    let mut data = Vec::new();
+    rmp::encode::write_pfix(&mut data, 8).unwrap();
+    rmp::encode::write_sint(&mut data, 1).unwrap();
+    rmp::encode::write_sint(&mut data, 2).unwrap();
+    rmp::encode::write_map_len(&mut data, 0).unwrap();
+    rmp::encode::write_bin_len(&mut data, u32::MAX).unwrap();
+    rt.block_on(ws.send(Message::Binary(data))).unwrap();
+
+    assert_eq!(
+        read_close_reason(&rt, &mut ws).as_deref(),
+        Some("protocol violation: unexpected end of frame")
+    );
+
+    let error = instance.read().unwrap();
+    assert_eq!(error[0], EVENT_ERROR);
+    let disconnect = instance.read().unwrap();
+    assert_eq!(
+        *disconnect.last().unwrap(),
+        DisconnectReason::ProtocolViolation as u8
+    );
+    assert_eq!(instance.socket_protocol_violations(&socket_id).unwrap(), 1);
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn close_socket_by_host() {
+    let path = write_config("app-close");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-close", "c1", "s1");
+
+    let connect = instance.read().unwrap();
+    assert_eq!(connect[0], EVENT_CONNECT);
+    let (socket_id, _) = rmp::decode::read_str_from_slice(&connect[1..]).unwrap();
+
+    instance.close_socket(socket_id, "logout").unwrap();
+    assert_eq!(read_close_reason(&rt, &mut ws).as_deref(), Some("logout"));
+
+    let disconnect = instance.read().unwrap();
+    assert_eq!(disconnect[0], EVENT_DISCONNECT);
+    assert_eq!(
+        *disconnect.last().unwrap(),
+        DisconnectReason::ServerClose as u8
+    );
+
+    match instance.close_socket("unknown", "logout") {
+        Err(HfnError::SocketNotFound(_)) => {}
+        _ => panic!("should be socket not found"),
+    }
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn close_sockets_by_client_id() {
+    let path = write_config("app-kick");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws1 = connect_client(&rt, &instance, "app-kick", "banned", "s1");
+    let mut ws2 = connect_client(&rt, &instance, "app-kick", "banned", "s2");
+    let _ws3 = connect_client(&rt, &instance, "app-kick", "other", "s3");
+
+    assert_eq!(
+        instance.close_sockets_by_client("banned", "ban").unwrap(),
+        2
+    );
+    assert_eq!(read_close_reason(&rt, &mut ws1).as_deref(), Some("ban"));
+    assert_eq!(read_close_reason(&rt, &mut ws2).as_deref(), Some("ban"));
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn retry_reset_and_redirect_socket() {
+    let path = write_config("app-flow");
+    let (instance, _) = HfnInstance::init(init_args(&path)).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-flow", "c1", "s1");
+    let socket_id = read_socket_id(&instance);
+
+    match instance.retry_socket(&socket_id, 128) {
+        Err(HfnError::InvalidArgument(_)) => {}
+        _ => panic!("should be invalid argument"),
+    }
+    match instance.redirect_socket(&socket_id, 1, "not a url") {
+        Err(HfnError::InvalidUrl(_)) => {}
+        _ => panic!("should be invalid url"),
+    }
+
+    // RETRY keeps the session
+    instance.retry_socket(&socket_id, 3).unwrap();
+    assert_eq!(read_packet(&rt, &mut ws, 2), Some(vec![2, 3]));
+    assert_eq!(
+        read_disconnect_reason(&instance),
+        DisconnectReason::Retry as u8
+    );
+
+    let mut ws = connect_client(&rt, &instance, "app-flow", "c1", "s1");
+    assert_eq!(read_socket_id(&instance), socket_id);
+
+    // RESET drops it
+    instance.reset_socket(&socket_id, 0).unwrap();
+    assert_eq!(read_packet(&rt, &mut ws, 3), Some(vec![3, 0]));
+    assert_eq!(
+        read_disconnect_reason(&instance),
+        DisconnectReason::Reset as u8
+    );
+    assert!(matches!(
+        instance.retry_socket(&socket_id, 1),
+        Err(HfnError::SocketNotFound(_))
+    ));
+
+    let mut ws = connect_client(&rt, &instance, "app-flow", "c1", "s1");
+    let socket_id = read_socket_id(&instance);
+
+    instance
+        .redirect_socket(&socket_id, 1, "ws://node-2:3000/hfn")
+        .unwrap();
+    let redirect = read_packet(&rt, &mut ws, 4).unwrap();
+    assert_eq!(redirect[1], 1);
+    let (target, _) = rmp::decode::read_str_from_slice(&redirect[2..]).unwrap();
+    assert_eq!(target, "ws://node-2:3000/hfn");
+    assert_eq!(
+        read_disconnect_reason(&instance),
+        DisconnectReason::Redirect as u8
+    );
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn retry_over_connection_cap() {
+    let path = write_config("app-cap");
+    let mut args = test_init_args(&path);
+    args.max_connections = Some(1);
+    args.connection_retry_delay = Some(7);
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let rt = client_runtime();
+    let mut ws = connect_client(&rt, &instance, "app-cap", "c1", "s1");
+    read_socket_id(&instance);
+
+    // the second client is told to come back and never registered
+    let url = format!(
+        "ws://{}/hfn?aid=app-cap&cid=c2&sid=s1&ver=1&ts=0",
+        instance.local_addr().unwrap()
+    );
+    let (mut rejected, _) = rt.block_on(tokio_tungstenite::connect_async(url)).unwrap();
+    assert_eq!(read_packet(&rt, &mut rejected, 2), Some(vec![2, 7]));
+    assert!(matches!(instance.try_read(), TryReadRes::EMPTY));
+
+    // the slot frees up with the first connection
+    rt.block_on(ws.close(None)).unwrap();
+    drop(ws);
+    read_disconnect_reason(&instance);
+    connect_client(&rt, &instance, "app-cap", "c2", "s1");
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn authenticate_clients() {
+    let rt = client_runtime();
+    let connect = |instance: &HfnInstance, query: &str, token: Option<&str>| {
+        let url = format!(
+            "ws://{}/hfn?aid=app-auth&cid=c1&sid=s1&ver=1&{}",
+            instance.local_addr().unwrap(),
+            query
+        );
+        let mut request = url.into_client_request().unwrap();
+        if let Some(token) = token {
+            let authorization = format!("Bearer {}", token).parse().unwrap();
+            request.headers_mut().insert("authorization", authorization);
+        }
+        match rt.block_on(tokio_tungstenite::connect_async(request)) {
+            Ok(_) => 101,
+            Err(WsError::Http(response)) => response.status().as_u16(),
+            Err(e) => panic!("unexpected handshake error {}", e),
+        }
+    };
+
+    // clients sign aid, cid, sid and ts with the shared secret
+    let path = write_config("app-auth");
+    let mut args = test_init_args(&path);
+    args.client_auth = Some(serde_json::json!({ "type": "hmac", "secret": "s3cret" }));
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    let ts = chrono::Utc::now().timestamp_millis();
+    let sig = hmac_sha256_hex(b"s3cret", format!("app-auth\nc1\ns1\n{}", ts).as_bytes());
+    assert_eq!(connect(&instance, &format!("ts={}", ts), None), 401);
+    assert_eq!(connect(&instance, &format!("ts={}&sig=00", ts), None), 401);
+    assert_eq!(
+        connect(&instance, &format!("ts={}&sig={}", ts, sig), None),
+        101
+    );
+
+    let socket_id = read_socket_id(&instance);
+    let claims = instance.socket_claims(&socket_id).unwrap();
+    assert_eq!(claims.get("sub").map(|v| v.as_str()), Some("c1"));
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    // the host decides, ahead of client_auth
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance
+        .set_client_authenticator(|req| match req.token.as_deref() {
+            Some("good") => Ok(Claims::from([("role".to_string(), "admin".to_string())])),
+            _ => Err("bad token".to_string()),
+        })
+        .unwrap();
+    instance.run().unwrap();
+
+    assert_eq!(connect(&instance, "ts=0", Some("bad")), 401);
+    assert_eq!(connect(&instance, "ts=0", Some("good")), 101);
+    let socket_id = read_socket_id(&instance);
+    let claims = instance.socket_claims(&socket_id).unwrap();
+    assert_eq!(claims.get("role").map(|v| v.as_str()), Some("admin"));
+
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[allow(clippy::result_large_err)]
+fn serve_over_tls() {
+    let rt = client_runtime();
+    let dir = std::env::temp_dir();
+    let prefix = format!("hfn-{}", rusty_ulid::generate_ulid_string());
+    let write_pem = |name: &str, pem: &str| {
+        let path = dir.join(format!("{}-{}.pem", prefix, name));
+        fs::write(&path, pem).unwrap();
+        path
+    };
+
+    let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let server_pem = server_cert.serialize_pem().unwrap();
+    let cert_path = write_pem("cert", &server_pem);
+    let key_path = write_pem("key", &server_cert.serialize_private_key_pem());
+    let client_cert = rcgen::generate_simple_self_signed(vec!["c1".to_string()]).unwrap();
+    let client_pem = client_cert.serialize_pem().unwrap();
+    let ca_path = write_pem("ca", &client_pem);
+
+    let der = |pem: &str| {
+        let certs = rustls_pemfile::certs(&mut pem.as_bytes()).unwrap();
+        rustls::Certificate(certs[0].clone())
+    };
+    let connect = |instance: &HfnInstance, client: bool| {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&der(&server_pem)).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if client {
+            let key = rustls::PrivateKey(client_cert.serialize_private_key_der());
+            config
+                .with_single_cert(vec![der(&client_pem)], key)
+                .unwrap()
+        } else {
+            config.with_no_client_auth()
+        };
+
+        let url = format!(
+            "wss://localhost:{}/hfn?aid=app-tls&cid=c1&sid=s1&ver=1&ts=0",
+            instance.local_addr().unwrap().port()
+        );
+        let connector = Connector::Rustls(Arc::new(config));
+        rt.block_on(async {
+            let (mut ws, _) = connect_async_tls_with_config(url, None, Some(connector)).await?;
+            ws.next().await.ok_or(WsError::ConnectionClosed)??;
+            Ok::<_, WsError>(ws)
+        })
+    };
+
+    // hfn.json paths are relative to its directory
+    let config_path = dir.join(format!("{}.json", prefix));
+    fs::write(
+        &config_path,
+        format!(
+            r#"{{
+                "name": "test",
+                "appid": "app-tls",
+                "dev": {{ "devtools": "ws://127.0.0.1:1" }},
+                "tls": {{ "cert": "{}-cert.pem", "key": "{}-key.pem" }},
+                "createdAt": "2022-01-01T00:00:00Z",
+                "packages": []
+            }}"#,
+            prefix, prefix
+        ),
+    )
+    .unwrap();
+    let (instance, _) = HfnInstance::init(init_args(&config_path)).unwrap();
+    instance.run().unwrap();
+
+    let ws = connect(&instance, false).unwrap();
+    read_socket_id(&instance);
+    drop(ws);
+
+    // plain websocket clients don't get through
+    let url = format!(
+        "ws://{}/hfn?aid=app-tls&cid=c2&sid=s1&ver=1&ts=0",
+        instance.local_addr().unwrap()
+    );
+    assert!(rt.block_on(tokio_tungstenite::connect_async(url)).is_err());
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    // mutual TLS from init args, clients need a certificate from the ca
+    let mut args = test_init_args(&config_path);
+    args.tls_cert_path = Some(cert_path.display().to_string());
+    args.tls_key_path = Some(key_path.display().to_string());
+    args.tls_client_ca_path = Some(ca_path.display().to_string());
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    instance.run().unwrap();
+
+    assert!(connect(&instance, false).is_err());
+    let _ws = connect(&instance, true).unwrap();
+    read_socket_id(&instance);
+    instance.shutdown(Duration::from_secs(1)).unwrap();
+
+    // an unreadable certificate fails run
+    args.tls_cert_path = Some(dir.join("hfn-missing.pem").display().to_string());
+    let (instance, _) = HfnInstance::init(rmp_serde::to_vec(&args).unwrap()).unwrap();
+    match instance.run() {
+        Err(HfnError::Tls(_)) => {}
+        _ => panic!("should be tls error"),
+    }
+
+    for path in [config_path, cert_path, key_path, ca_path] {
+        fs::remove_file(path).unwrap();
+    }
+}